@@ -0,0 +1,935 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use imap::Session;
+use imap::types::{Flag, NameAttribute};
+use native_tls::TlsConnector;
+
+use crate::backend::{Backend, MailboxName, Month, Quota, Status, Summary, Thread};
+use crate::backend::imap_raw::{self, RawSession, Token};
+use crate::backend::structure::Structure;
+use crate::error::{Error, Result};
+use crate::net;
+use crate::search::{self, Criterion};
+use crate::thread;
+use crate::utf7;
+use crate::{IMAPMailbox, IMAPSession};
+
+pub fn flag_name(flag: &Flag) -> String {
+    match flag {
+        Flag::Seen => "\\Seen".to_string(),
+        Flag::Answered => "\\Answered".to_string(),
+        Flag::Flagged => "\\Flagged".to_string(),
+        Flag::Deleted => "\\Deleted".to_string(),
+        Flag::Draft => "\\Draft".to_string(),
+        Flag::Recent => "\\Recent".to_string(),
+        Flag::MayCreate => "\\*".to_string(),
+        Flag::Custom(f) => f.to_string(),
+    }
+}
+
+/// Reads a line, a byte at a time so nothing after it is taken from the
+/// stream.
+fn read_line<R: Read>(stream: &mut R) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    while line.last() != Some(&b'\n') {
+        if stream.read(&mut byte)? == 0 {
+            return Err(Error::Connection("connection closed".to_string()));
+        }
+        line.push(byte[0]);
+    }
+    Ok(line)
+}
+
+/// Flags a message in the selected mailbox \Deleted and, when `expunge` is
+/// set, expunges it with UID EXPUNGE (RFC 4315). A plain EXPUNGE would take
+/// every other message flagged \Deleted with it, so without UIDPLUS the
+/// message is only flagged and expunging is left to the user.
+fn remove<T: Read + Write>(session: &mut Session<T>, uid: &str, expunge: bool) -> Result<()> {
+    session.uid_store(uid, "+FLAGS (\\Deleted)")?;
+    if expunge {
+        if session.capabilities()?.has("UIDPLUS") {
+            session.uid_expunge(uid)?;
+        } else {
+            warn!("the server has no UIDPLUS, UID {} is only flagged \\Deleted", uid);
+        }
+    }
+    Ok(())
+}
+
+/// The directory path of a mailbox, without the personal namespace prefix.
+fn mailbox_path(name: &str, prefix: &str, delimiter: Option<&str>) -> Vec<String> {
+    let mut full = name;
+    if !prefix.is_empty() && full.len() > prefix.len() && full.starts_with(prefix) {
+        full = &full[prefix.len()..];
+    }
+
+    // a "/" inside a component would otherwise start a new directory
+    match delimiter {
+        Some(d) if !d.is_empty() => {
+            full.split(d)
+                .filter(|c| !c.is_empty())
+                .map(|c| utf7::decode(c).replace('/', "\u{2215}"))
+                .collect()
+        },
+        _ => vec![utf7::decode(full).replace('/', "\u{2215}")],
+    }
+}
+
+/// The server name for a directory path, the reverse of `mailbox_path`.
+/// INBOX is the only mailbox outside the prefix, a mailbox made inside it
+/// gets the prefix like any other.
+fn server_name(path: &[String], prefix: &str, delimiter: Option<&str>) -> String {
+    if path.len() == 1 && path[0].eq_ignore_ascii_case("INBOX") {
+        return "INBOX".to_string();
+    }
+
+    let parts: Vec<String> = path.iter()
+        .map(|c| utf7::encode(&c.replace('\u{2215}', "/")))
+        .collect();
+
+    let name = match delimiter {
+        Some(d) => parts.join(d),
+        None => parts.concat(),
+    };
+
+    format!("{}{}", prefix, name)
+}
+
+/// The (year, month) of an INTERNALDATE, "17-Jul-1996 02:44:25 -0700".
+fn internal_month(date: &str) -> Option<(i32, u32)> {
+    let mut parts = date.trim().split(['-', ' ']);
+    let _day = parts.next()?;
+    let month = parts.next()?;
+    let year = parts.next()?.parse::<i32>().ok()?;
+
+    let month = search::MONTHS.iter()
+        .position(|m| m.eq_ignore_ascii_case(month))? as u32 + 1;
+    Some((year, month))
+}
+
+/// Seconds since the epoch of an INTERNALDATE.
+fn internal_time(date: &str) -> Option<i64> {
+    mailparse::dateparse(&date.trim().replacen('-', " ", 2)).ok()
+}
+
+/// The (year, month) of a Date header in a header block.
+fn sent_month(header: &[u8]) -> Option<(i32, u32)> {
+    let (headers, _) = mailparse::parse_headers(header).ok()?;
+    let date = headers.iter()
+        .find(|h| h.get_key().map(|k| k.eq_ignore_ascii_case("Date")).unwrap_or(false))?
+        .get_value()
+        .ok()?;
+
+    let tm = time::at(time::Timespec::new(mailparse::dateparse(&date).ok()?, 0));
+    Some((tm.tm_year + 1900, tm.tm_mon as u32 + 1))
+}
+
+/// The uids in a THREAD response tree, depth first.
+fn thread_uids(tree: &Token, uids: &mut Vec<String>) {
+    match tree {
+        Token::Atom(a) => uids.push(a.clone()),
+        Token::List(l) => {
+            for t in l.iter() {
+                thread_uids(t, uids);
+            }
+        },
+        _ => (),
+    }
+}
+
+pub struct ImapBackend {
+    session: IMAPSession,
+    // second connection for commands imap can't parse, opened when needed
+    ext: Option<RawSession>,
+    // the mailbox examined on the second connection
+    ext_selected: Option<String>,
+    login: (String, u16, String, String),
+    // the currently selected mailbox and whether it was opened read-write
+    selected: Option<(String, bool)>,
+    // special-use attribute -> mailbox
+    special: BTreeMap<String, String>,
+    // personal namespace prefix and hierarchy delimiter, for building the
+    // server names of new mailboxes
+    prefix: String,
+    delimiter: Option<String>,
+    // the server has Gmail's X-GM-EXT-1 extension
+    gmail: bool,
+    expunge: bool,
+}
+
+impl ImapBackend {
+    pub fn connect(domain: &str, port: u16, uname: &str, pword: &str) -> Result<ImapBackend> {
+        let tls = TlsConnector::builder().build()?;
+        debug!("created tls");
+
+        // connected here rather than by imap::connect to have timeouts
+        let mut stream = tls.connect(domain, net::tcp(domain, port)?)?;
+        let greeting = read_line(&mut stream)?;
+        if !greeting.starts_with(b"* OK") && !greeting.starts_with(b"* PREAUTH") {
+            return Err(Error::Connection(format!("refused by {}: {}", domain, String::from_utf8_lossy(&greeting).trim_end())));
+        }
+        let client = imap::Client::new(stream);
+        debug!("created client");
+
+        let mut session = match client.login(uname, pword) {
+            Ok(s) => s,
+            Err((imap::error::Error::No(e), _)) => return Err(Error::Auth(e)),
+            Err((e, _)) => return Err(Error::from(e)),
+        };
+        debug!("created session");
+
+        let gmail = session.capabilities()
+            .map(|c| c.has("X-GM-EXT-1"))
+            .unwrap_or(false);
+
+        Ok(ImapBackend {
+            session,
+            ext: None,
+            ext_selected: None,
+            login: (domain.to_string(), port, uname.to_string(), pword.to_string()),
+            selected: None,
+            special: BTreeMap::new(),
+            prefix: String::new(),
+            delimiter: None,
+            gmail,
+            expunge: true,
+        })
+    }
+
+    fn open(&mut self, mailbox: &str, read_write: bool) -> Result<()> {
+        if let Some((name, rw)) = &self.selected {
+            if name == mailbox && (*rw || !read_write) {
+                return Ok(());
+            }
+        }
+
+        self.selected = None;
+
+        if read_write {
+            self.session.select(mailbox)?;
+        } else {
+            self.session.examine(mailbox)?;
+        }
+
+        self.selected = Some((mailbox.to_string(), read_write));
+        Ok(())
+    }
+
+    fn ext(&mut self) -> Result<&mut RawSession> {
+        if self.ext.is_none() {
+            let (domain, port, uname, pword) = &self.login;
+            self.ext = Some(RawSession::connect(domain, *port, uname, pword)?);
+        }
+
+        match self.ext.as_mut() {
+            Some(ext) => Ok(ext),
+            None => Err(Error::Unsupported),
+        }
+    }
+
+    /// Examines a mailbox on the second connection. EXAMINE makes sure
+    /// fetching there never sets \Seen.
+    fn ext_open(&mut self, mailbox: &str) -> Result<&mut RawSession> {
+        if self.ext_selected.as_ref().map(|s| s != mailbox).unwrap_or(true) {
+            self.ext_selected = None;
+            self.ext()?.command(&format!("EXAMINE {}", imap_raw::quote(mailbox)))?;
+            self.ext_selected = Some(mailbox.to_string());
+        }
+
+        self.ext()
+    }
+
+    /// The prefix and delimiter of the personal namespace, if the server
+    /// supports NAMESPACE.
+    fn namespace(&mut self) -> Option<(String, Option<String>)> {
+        if !self.session.capabilities().ok()?.has("NAMESPACE") {
+            return None;
+        }
+
+        let responses = self.ext().ok()?.command("NAMESPACE").ok()?;
+        let response = responses.iter().find(|r| r.first().map(|t| t.is_atom("NAMESPACE")).unwrap_or(false))?;
+
+        // NAMESPACE (("prefix" "delim") ...) other shared
+        let personal = response.get(1)?.as_list()?.first()?.as_list()?;
+        let prefix = personal.first()?.as_str()?;
+        let delimiter = personal.get(1).and_then(|d| d.as_str());
+
+        Some((prefix, delimiter))
+    }
+
+    /// Fetches the flags, size and arrival time of messages along with some
+    /// of their header fields, "SUBJECT DATE" etc.
+    fn fetch_summaries(&mut self, mailbox: &str, set: &str, fields: &str) -> Result<Vec<Summary>> {
+        let gmail = if self.gmail { " X-GM-MSGID" } else { "" };
+        let responses = self.ext_open(mailbox)?
+            .command(&format!("UID FETCH {} (UID FLAGS RFC822.SIZE INTERNALDATE{} BODY.PEEK[HEADER.FIELDS ({})])", set, gmail, fields))?;
+
+        let mut summaries = Vec::new();
+        for attributes in responses.iter().filter_map(|r| imap_raw::fetch_attributes(r)) {
+            let uid = match imap_raw::attribute(attributes, "UID").and_then(|u| u.as_u64()) {
+                Some(u) => u,
+                None => continue,
+            };
+
+            let mut summary = Summary::new(uid.to_string());
+            summary.size = imap_raw::attribute(attributes, "RFC822.SIZE")
+                .and_then(|s| s.as_u64())
+                .map(|s| s as u32);
+            summary.flags = imap_raw::attribute(attributes, "FLAGS")
+                .and_then(|f| f.as_list())
+                .map(|f| f.iter().filter_map(|f| f.as_str()).collect());
+            summary.received = imap_raw::attribute(attributes, "INTERNALDATE")
+                .and_then(|d| d.as_str())
+                .and_then(|d| internal_time(&d));
+            summary.id = imap_raw::attribute(attributes, "X-GM-MSGID")
+                .and_then(|i| i.as_str());
+            summary.header = attributes.chunks(2)
+                .find(|a| a[0].as_str().map(|k| k.to_uppercase().starts_with("BODY[")).unwrap_or(false))
+                .and_then(|a| a.get(1))
+                .and_then(|h| h.as_bytes())
+                .map(|h| h.to_vec());
+
+            summaries.push(summary);
+        }
+
+        summaries.sort_unstable_by_key(|s| s.uid.parse::<u32>().unwrap_or(0));
+        Ok(summaries)
+    }
+
+    fn server_name(&self, path: &[String]) -> String {
+        server_name(path, &self.prefix, self.delimiter.as_deref())
+    }
+}
+
+impl Backend for ImapBackend {
+    /// Splits names on the delimiter the server gives for each mailbox and
+    /// drops the personal namespace prefix (e.g. "INBOX.") so that those
+    /// mailboxes show up at the top level.
+    fn list(&mut self) -> Result<Vec<MailboxName>> {
+        let namespace = self.namespace();
+        let names = self.session.list(Some(""), Some("*"))?;
+
+        self.prefix = String::new();
+        self.delimiter = None;
+        if let Some((prefix, delimiter)) = &namespace {
+            self.prefix = prefix.clone();
+            self.delimiter = delimiter.clone();
+        }
+
+        self.special.clear();
+        let mut mailboxes = Vec::new();
+
+        for name in names.iter() {
+            let mut mailbox = MailboxName::new(name.name(), Vec::new());
+
+            for attr in name.attributes() {
+                match attr {
+                    NameAttribute::NoSelect => mailbox.selectable = false,
+                    NameAttribute::NoInferiors => mailbox.has_children = Some(false),
+                    NameAttribute::Custom(a) => {
+                        if a.eq_ignore_ascii_case("\\NonExistent") {
+                            mailbox.selectable = false;
+                        } else if a.eq_ignore_ascii_case("\\HasChildren") {
+                            mailbox.has_children = Some(true);
+                        } else if a.eq_ignore_ascii_case("\\HasNoChildren") {
+                            mailbox.has_children = Some(false);
+                        } else {
+                            self.special.insert(a.to_string(), name.name().to_string());
+                        }
+                    },
+                    _ => (),
+                }
+            }
+
+            if let Some(d) = name.delimiter() {
+                if self.delimiter.is_none() && !d.is_empty() {
+                    self.delimiter = Some(d.to_string());
+                }
+            }
+            mailbox.path = mailbox_path(name.name(), &self.prefix, name.delimiter());
+
+            if mailbox.path.is_empty() {
+                continue;
+            }
+
+            mailboxes.push(mailbox);
+        }
+
+        Ok(mailboxes)
+    }
+
+    fn examine(&mut self, mailbox: &str) -> Result<IMAPMailbox> {
+        self.selected = None;
+        let info = self.session.examine(mailbox)?;
+        self.selected = Some((mailbox.to_string(), false));
+        Ok(info)
+    }
+
+    fn messages(&mut self, mailbox: &str) -> Result<Vec<Summary>> {
+        self.open(mailbox, false)?;
+
+        let uids = self.session.uid_search("1:*")?;
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let fetches = self.session.uid_fetch("1:*", "(UID FLAGS RFC822.SIZE)")?;
+
+        let mut summaries: Vec<Summary> = fetches.iter()
+            .filter_map(|f| {
+                let mut summary = Summary::new(f.uid?.to_string());
+                summary.size = f.size;
+                summary.flags = Some(f.flags().iter().map(|f| flag_name(f)).collect());
+                Some(summary)
+            })
+            .collect();
+        summaries.sort_unstable_by_key(|s| s.uid.parse::<u32>().unwrap_or(0));
+
+        // imap doesn't hand out INTERNALDATE and can't parse X-GM-MSGID, so
+        // those come from the second connection
+        let items = if self.gmail { "UID INTERNALDATE X-GM-MSGID" } else { "UID INTERNALDATE" };
+        let responses = self.ext_open(mailbox)?.command(&format!("UID FETCH 1:* ({})", items))?;
+
+        let mut extra = BTreeMap::new();
+        for attributes in responses.iter().filter_map(|r| imap_raw::fetch_attributes(r)) {
+            if let Some(uid) = imap_raw::attribute(attributes, "UID").and_then(|u| u.as_str()) {
+                let received = imap_raw::attribute(attributes, "INTERNALDATE")
+                    .and_then(|d| d.as_str())
+                    .and_then(|d| internal_time(&d));
+                let id = imap_raw::attribute(attributes, "X-GM-MSGID").and_then(|i| i.as_str());
+                extra.insert(uid, (received, id));
+            }
+        }
+
+        for summary in summaries.iter_mut() {
+            if let Some((received, id)) = extra.remove(&summary.uid) {
+                summary.received = received;
+                summary.id = id;
+            }
+        }
+
+        Ok(summaries)
+    }
+
+    /// STATUS on the second connection, which leaves the mailbox selected
+    /// on the first alone. imap hands STATUS responses to its unsolicited
+    /// channel rather than back to the caller.
+    fn status(&mut self, mailbox: &str) -> Result<Status> {
+        let responses = self.ext()?
+            .command(&format!("STATUS {} (MESSAGES RECENT UNSEEN UIDNEXT UIDVALIDITY)", imap_raw::quote(mailbox)))?;
+
+        // STATUS name (MESSAGES 231 UIDNEXT 44292 ...)
+        let items = responses.iter()
+            .find(|r| r.first().map(|t| t.is_atom("STATUS")).unwrap_or(false))
+            .and_then(|r| r.get(2))
+            .and_then(|l| l.as_list());
+
+        let items = match items {
+            Some(i) => i,
+            None => return Err(Error::Protocol(format!("no STATUS for {}", mailbox))),
+        };
+        let value = |name: &str| imap_raw::attribute(items, name).and_then(|v| v.as_u64()).map(|v| v as u32);
+
+        Ok(Status {
+            messages: value("MESSAGES").unwrap_or(0),
+            recent: value("RECENT"),
+            unseen: value("UNSEEN"),
+            uid_next: value("UIDNEXT"),
+            uid_validity: value("UIDVALIDITY"),
+        })
+    }
+
+    fn fetch(&mut self, mailbox: &str, uid: &str) -> Result<Vec<u8>> {
+        self.open(mailbox, false)?;
+
+        let fetches = self.session.uid_fetch(uid, "BODY.PEEK[]")?;
+
+        match fetches.iter().next().and_then(|f| f.body()) {
+            Some(body) => Ok(body.to_vec()),
+            None => Err(Error::NotFound(format!("no body for UID {}", uid))),
+        }
+    }
+
+    /// Reads the BODYSTRUCTURE and downloads only the header and the text
+    /// parts, so attachments stay on the server.
+    fn fetch_text(&mut self, mailbox: &str, uid: &str) -> Result<Vec<u8>> {
+        let responses = self.ext_open(mailbox)?
+            .command(&format!("UID FETCH {} (BODYSTRUCTURE)", uid))?;
+
+        let structure = responses.iter()
+            .filter_map(|r| imap_raw::fetch_attributes(r))
+            .filter_map(|a| imap_raw::attribute(a, "BODYSTRUCTURE"))
+            .filter_map(|b| b.as_list())
+            .filter_map(|b| Structure::parse(b))
+            .next();
+
+        let structure = match structure {
+            Some(s) => s,
+            None => return self.fetch(mailbox, uid),
+        };
+
+        let mut items = vec!["BODY.PEEK[HEADER]".to_string()];
+        items.extend(structure.sections().iter().map(|s| format!("BODY.PEEK[{}]", s)));
+
+        let responses = self.ext_open(mailbox)?
+            .command(&format!("UID FETCH {} ({})", uid, items.join(" ")))?;
+
+        // BODY[1.MIME] -> 1.MIME
+        let mut sections = BTreeMap::new();
+        for attributes in responses.iter().filter_map(|r| imap_raw::fetch_attributes(r)) {
+            for pair in attributes.chunks(2) {
+                if let (Token::Atom(key), Some(data)) = (&pair[0], pair.get(1).and_then(|d| d.as_bytes())) {
+                    let key = key.to_uppercase();
+                    if let Some(rest) = key.strip_prefix("BODY[") {
+                        let section = rest.split(']').next().unwrap_or("");
+                        sections.insert(section.to_string(), data.to_vec());
+                    }
+                }
+            }
+        }
+
+        let header = sections.remove("HEADER").unwrap_or_default();
+        Ok(structure.assemble(&header, &sections))
+    }
+
+    fn delete(&mut self, mailbox: &str, uid: &str) -> Result<()> {
+        self.open(mailbox, true)?;
+        remove(&mut self.session, uid, self.expunge)
+    }
+
+    fn set_expunge(&mut self, expunge: bool) {
+        self.expunge = expunge;
+    }
+
+    fn has_flags(&self) -> bool {
+        true
+    }
+
+    fn has_search(&self) -> bool {
+        true
+    }
+
+    fn set_flags(&mut self, mailbox: &str, uid: &str, add: &[&str], remove: &[&str]) -> Result<()> {
+        self.open(mailbox, true)?;
+
+        if !add.is_empty() {
+            self.session.uid_store(uid, format!("+FLAGS ({})", add.join(" ")))?;
+        }
+        if !remove.is_empty() {
+            self.session.uid_store(uid, format!("-FLAGS ({})", remove.join(" ")))?;
+        }
+        Ok(())
+    }
+
+    fn move_to(&mut self, mailbox: &str, uid: &str, dest: &str) -> Result<()> {
+        self.open(mailbox, true)?;
+
+        let has_move = self.session.capabilities()?.has("MOVE");
+
+        // MOVE expunges the original
+        if has_move && self.expunge {
+            self.session.uid_mv(uid, dest)?;
+        } else {
+            self.session.uid_copy(uid, dest)?;
+            remove(&mut self.session, uid, self.expunge)?;
+        }
+        Ok(())
+    }
+
+    fn has_append(&self) -> bool {
+        true
+    }
+
+    fn append(&mut self, mailbox: &str, content: &[u8]) -> Result<()> {
+        self.session.append(mailbox, content)?;
+        Ok(())
+    }
+
+    fn search(&mut self, mailbox: &str, criteria: &[Criterion]) -> Result<Vec<String>> {
+        self.open(mailbox, false)?;
+
+        let mut uids: Vec<u32> = self.session.uid_search(search::to_imap(criteria))?
+            .into_iter()
+            .collect();
+        uids.sort_unstable();
+
+        Ok(uids.iter().map(|u| u.to_string()).collect())
+    }
+
+    fn summaries(&mut self, mailbox: &str, uids: &[String]) -> Result<Vec<Summary>> {
+        let mut summaries = Vec::new();
+
+        // keep the command lines a sensible length
+        for chunk in uids.chunks(500) {
+            summaries.extend(self.fetch_summaries(mailbox, &chunk.join(","), "SUBJECT DATE MESSAGE-ID")?);
+        }
+
+        summaries.sort_unstable_by_key(|s| s.uid.parse::<u32>().unwrap_or(0));
+        Ok(summaries)
+    }
+
+    /// Messages are in the order they arrived, so the first and last give
+    /// the range by INTERNALDATE. Date headers can be anything, so messages
+    /// sent outside that range are looked for with SENTBEFORE/SENTSINCE.
+    fn date_range(&mut self, mailbox: &str, sent: bool) -> Result<Option<(Month, Month)>> {
+        if self.examine(mailbox)?.exists == 0 {
+            return Ok(None);
+        }
+
+        let responses = self.ext_open(mailbox)?.command("FETCH 1,* (INTERNALDATE)")?;
+        let months: Vec<(i32, u32)> = responses.iter()
+            .filter_map(|r| imap_raw::fetch_attributes(r))
+            .filter_map(|a| imap_raw::attribute(a, "INTERNALDATE"))
+            .filter_map(|d| d.as_str())
+            .filter_map(|d| internal_month(&d))
+            .collect();
+
+        let (mut first, mut last) = match (months.iter().min(), months.iter().max()) {
+            (Some(f), Some(l)) => (*f, *l),
+            _ => return Ok(None),
+        };
+
+        if sent {
+            let (next_year, next_month) = if last.1 == 12 { (last.0 + 1, 1) } else { (last.0, last.1 + 1) };
+            let mut outside = self.search(mailbox, &[Criterion::SentBefore(first.0 as u32, first.1, 1)])?;
+            outside.extend(self.search(mailbox, &[Criterion::SentSince(next_year as u32, next_month, 1)])?);
+
+            for summary in self.summaries(mailbox, &outside)? {
+                if let Some(month) = summary.header.as_ref().and_then(|h| sent_month(h)) {
+                    first = std::cmp::min(first, month);
+                    last = std::cmp::max(last, month);
+                }
+            }
+        }
+
+        Ok(Some((first, last)))
+    }
+
+    fn rights(&mut self, mailbox: &str) -> Result<String> {
+        if !self.session.capabilities()?.has("ACL") {
+            return Err(Error::Unsupported);
+        }
+
+        // MYRIGHTS name lrswipkxtea
+        let responses = self.ext()?.command(&format!("MYRIGHTS {}", imap_raw::quote(mailbox)))?;
+        responses.iter()
+            .find(|r| r.first().map(|t| t.is_atom("MYRIGHTS")).unwrap_or(false))
+            .and_then(|r| r.get(2))
+            .and_then(|r| r.as_str())
+            .ok_or_else(|| Error::Protocol(format!("no MYRIGHTS for {}", mailbox)))
+    }
+
+    /// The quota root of INBOX, or the sizes of every message added up when
+    /// the server has no QUOTA extension.
+    fn quota(&mut self) -> Result<Quota> {
+        let mut quota = Quota { storage: 0, storage_limit: None, messages: 0, message_limit: None };
+
+        if self.session.capabilities()?.has("QUOTA") {
+            let responses = self.ext()?.command("GETQUOTAROOT INBOX")?;
+
+            // QUOTA "" (STORAGE 10 512 MESSAGE 1 1000), storage is in KiB
+            let resources = responses.iter()
+                .filter(|r| r.first().map(|t| t.is_atom("QUOTA")).unwrap_or(false))
+                .filter_map(|r| r.get(2))
+                .filter_map(|l| l.as_list())
+                .next();
+
+            let mut storage = false;
+            for resource in resources.map(|r| r.chunks(3)).into_iter().flatten() {
+                if resource.len() < 3 {
+                    continue;
+                }
+                let (used, limit) = (resource[1].as_u64().unwrap_or(0), resource[2].as_u64());
+
+                if resource[0].is_atom("STORAGE") {
+                    quota.storage = used * 1024;
+                    quota.storage_limit = limit.map(|l| l * 1024);
+                    storage = true;
+                } else if resource[0].is_atom("MESSAGE") {
+                    quota.messages = used;
+                    quota.message_limit = limit;
+                }
+            }
+
+            if storage {
+                return Ok(quota);
+            }
+        }
+
+        quota.messages = 0;
+        for mailbox in self.list()?.into_iter().filter(|m| m.selectable) {
+            if self.status(&mailbox.name)?.messages == 0 {
+                continue;
+            }
+
+            let responses = self.ext_open(&mailbox.name)?.command("FETCH 1:* (RFC822.SIZE)")?;
+            for size in responses.iter()
+                .filter_map(|r| imap_raw::fetch_attributes(r))
+                .filter_map(|a| imap_raw::attribute(a, "RFC822.SIZE"))
+                .filter_map(|s| s.as_u64()) {
+                quota.storage += size;
+                quota.messages += 1;
+            }
+        }
+
+        Ok(quota)
+    }
+
+    fn has_labels(&self) -> bool {
+        self.gmail
+    }
+
+    fn labels(&mut self, mailbox: &str, uid: &str) -> Result<Vec<String>> {
+        let responses = self.ext_open(mailbox)?
+            .command(&format!("UID FETCH {} (X-GM-LABELS)", uid))?;
+
+        let labels = responses.iter()
+            .filter_map(|r| imap_raw::fetch_attributes(r))
+            .filter_map(|a| imap_raw::attribute(a, "X-GM-LABELS"))
+            .filter_map(|l| l.as_list())
+            .next()
+            .map(|l| l.iter().filter_map(|l| l.as_str()).map(|l| utf7::decode(&l)).collect())
+            .unwrap_or_default();
+
+        Ok(labels)
+    }
+
+    /// STORE with .SILENT, the FETCH responses would have X-GM-LABELS in
+    /// them, which imap can't parse.
+    fn set_labels(&mut self, mailbox: &str, uid: &str, add: &[String], remove: &[String]) -> Result<()> {
+        self.open(mailbox, true)?;
+
+        // system labels such as \Important are atoms, the rest are strings
+        let list = |labels: &[String]| -> String {
+            labels.iter()
+                .map(|l| if l.starts_with('\\') { l.clone() } else { imap_raw::quote(&utf7::encode(l)) })
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+
+        if !add.is_empty() {
+            self.session.uid_store(uid, format!("+X-GM-LABELS.SILENT ({})", list(add)))?;
+        }
+        if !remove.is_empty() {
+            self.session.uid_store(uid, format!("-X-GM-LABELS.SILENT ({})", list(remove)))?;
+        }
+        Ok(())
+    }
+
+    fn has_threads(&self) -> bool {
+        true
+    }
+
+    /// Uses THREAD=REFERENCES (RFC 5256) when the server has it, otherwise
+    /// the headers are fetched and threaded here.
+    fn threads(&mut self, mailbox: &str) -> Result<Vec<Thread>> {
+        if self.examine(mailbox)?.exists == 0 {
+            return Ok(Vec::new());
+        }
+
+        if !self.session.capabilities()?.has("THREAD=REFERENCES") {
+            let headers: Vec<(String, Vec<u8>)> = self.fetch_summaries(mailbox, "1:*", "MESSAGE-ID REFERENCES IN-REPLY-TO SUBJECT DATE")?
+                .into_iter()
+                .filter_map(|s| Some((s.uid, s.header?)))
+                .collect();
+            return Ok(thread::thread(&headers));
+        }
+
+        // THREAD (1 2 (3)(4 5))(6), each top level list is a thread with
+        // its root first
+        let responses = self.ext_open(mailbox)?.command("UID THREAD REFERENCES UTF-8 ALL")?;
+        let mut threads: Vec<Vec<String>> = Vec::new();
+        for response in responses.iter().filter(|r| r.first().map(|t| t.is_atom("THREAD")).unwrap_or(false)) {
+            for tree in response[1..].iter() {
+                let mut uids = Vec::new();
+                thread_uids(tree, &mut uids);
+                if !uids.is_empty() {
+                    threads.push(uids);
+                }
+            }
+        }
+
+        let roots: Vec<String> = threads.iter().map(|t| t[0].clone()).collect();
+        let summaries: BTreeMap<String, Summary> = self.summaries(mailbox, &roots)?
+            .into_iter()
+            .map(|s| (s.uid.clone(), s))
+            .collect();
+
+        let mut threads: Vec<Thread> = threads.into_iter()
+            .map(|uids| {
+                let root = summaries.get(&uids[0]);
+                let headers = root.and_then(|s| s.header.as_ref())
+                    .and_then(|h| mailparse::parse_headers(h).ok())
+                    .map(|(h, _)| h)
+                    .unwrap_or_default();
+
+                Thread {
+                    subject: thread::header(&headers, "Subject"),
+                    date: thread::header(&headers, "Date")
+                        .and_then(|d| mailparse::dateparse(&d).ok())
+                        .or(root.and_then(|s| s.received)),
+                    uids,
+                }
+            })
+            .collect();
+
+        threads.sort_by_key(|t| t.date.unwrap_or(i64::MAX));
+        Ok(threads)
+    }
+
+    fn create(&mut self, path: &[String]) -> Result<String> {
+        let name = self.server_name(path);
+        self.session.create(&name)?;
+        Ok(name)
+    }
+
+    fn rename(&mut self, mailbox: &str, path: &[String]) -> Result<String> {
+        let name = self.server_name(path);
+
+        self.session.rename(mailbox, &name)?;
+
+        if self.selected.as_ref().map(|(s, _)| s == mailbox).unwrap_or(false) {
+            self.selected = None;
+        }
+        if self.ext_selected.as_ref().map(|s| s == mailbox).unwrap_or(false) {
+            self.ext_selected = None;
+        }
+        Ok(name)
+    }
+
+    fn special_use(&self, attribute: &str) -> Option<String> {
+        self.special.get(attribute).cloned()
+    }
+
+    fn logout(&mut self) {
+        if let Some(ext) = self.ext.as_mut() {
+            ext.logout();
+        }
+        let _ = self.session.logout();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+
+    use super::{mailbox_path, remove, server_name};
+
+    /// A mailbox of UIDs 1 and 2 with 1 already flagged \Deleted, as by a
+    /// --no-expunge mount or another client. Returns the UIDs left once the
+    /// client logs out.
+    fn mailbox(uidplus: bool) -> (u16, JoinHandle<Vec<u32>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut deleted: BTreeMap<u32, bool> = vec![(1, true), (2, false)].into_iter().collect();
+
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let mut words = line.trim_end().splitn(2, ' ');
+                let (tag, command) = (words.next().unwrap(), words.next().unwrap_or("").to_uppercase());
+
+                let untagged = if command.starts_with("CAPABILITY") {
+                    format!("* CAPABILITY IMAP4rev1{}\r\n", if uidplus { " UIDPLUS" } else { "" })
+                } else if let Some(args) = command.strip_prefix("UID STORE ") {
+                    let uid: u32 = args.split(' ').next().unwrap().parse().unwrap();
+                    deleted.insert(uid, true);
+                    String::new()
+                } else if let Some(args) = command.strip_prefix("UID EXPUNGE ") {
+                    let uid: u32 = args.parse().unwrap();
+                    let seq = deleted.keys().position(|u| *u == uid).unwrap() + 1;
+                    deleted.remove(&uid);
+                    format!("* {} EXPUNGE\r\n", seq)
+                } else if command == "EXPUNGE" {
+                    deleted.retain(|_, d| !*d);
+                    String::new()
+                } else if command == "LOGOUT" {
+                    let _ = write!(reader.get_mut(), "* BYE logging out\r\n{} OK done\r\n", tag);
+                    break;
+                } else {
+                    String::new()
+                };
+
+                write!(reader.get_mut(), "{}{} OK done\r\n", untagged, tag).unwrap();
+            }
+
+            deleted.keys().cloned().collect()
+        });
+
+        (port, handle)
+    }
+
+    fn delete(port: u16, uid: &str, expunge: bool) {
+        let client = imap::Client::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+        let mut session = client.login("alice", "secret").map_err(|(e, _)| e).unwrap();
+        remove(&mut session, uid, expunge).unwrap();
+        session.logout().unwrap();
+    }
+
+    #[test]
+    fn uid_expunge() {
+        let (port, server) = mailbox(true);
+        delete(port, "2", true);
+        assert_eq!(server.join().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn no_uidplus() {
+        let (port, server) = mailbox(false);
+        delete(port, "2", true);
+        assert_eq!(server.join().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn no_expunge() {
+        let (port, server) = mailbox(true);
+        delete(port, "2", false);
+        assert_eq!(server.join().unwrap(), vec![1, 2]);
+    }
+
+    fn path(components: &[&str]) -> Vec<String> {
+        components.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn names() {
+        let namespaces = [("", Some(".")), ("INBOX.", Some(".")), ("", Some("/")), ("INBOX/", Some("/")), ("", None)];
+        let paths = [
+            path(&["INBOX"]),
+            path(&["INBOX", "child"]),
+            path(&["Archive", "2019"]),
+            path(&["Sent"]),
+            path(&["Receipts \u{2215} Bills"]),
+            path(&["Entw\u{fc}rfe"]),
+        ];
+
+        for (prefix, delimiter) in namespaces.iter() {
+            // a "/" can't be inside a component when it is the delimiter
+            let fits = |p: &&Vec<String>| match delimiter {
+                Some(d) => !(*d == "/" && p.iter().any(|c| c.contains('\u{2215}'))),
+                None => p.len() == 1,
+            };
+            for p in paths.iter().filter(fits) {
+                let name = server_name(p, prefix, *delimiter);
+                assert_eq!(&mailbox_path(&name, prefix, *delimiter), p, "{:?} in {:?} {:?}", name, prefix, delimiter);
+            }
+        }
+
+        assert_eq!(server_name(&path(&["inbox"]), "INBOX.", Some(".")), "INBOX");
+        assert_eq!(server_name(&path(&["INBOX", "child"]), "", Some(".")), "INBOX.child");
+        assert_eq!(server_name(&path(&["INBOX", "child"]), "INBOX.", Some(".")), "INBOX.INBOX.child");
+        assert_eq!(server_name(&path(&["Entw\u{fc}rfe"]), "INBOX.", Some(".")), "INBOX.Entw&APw-rfe");
+        assert_eq!(mailbox_path("INBOX.Sent", "INBOX.", Some(".")), path(&["Sent"]));
+        assert_eq!(mailbox_path("Receipts/Bills", "", Some(".")), path(&["Receipts\u{2215}Bills"]));
+    }
+}
@@ -0,0 +1,259 @@
+// imap 1.0.1 gives up on any untagged response it doesn't know (NAMESPACE,
+// QUOTA, THREAD, BODYSTRUCTURE, ...) and leaves the connection out of step.
+// Commands using those extensions go through this second, minimal
+// connection which hands back the untagged responses as token trees.
+
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::error::{Error, Result};
+use crate::logger;
+use crate::net::Stream;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Atom(String),
+    Str(Vec<u8>),
+    List(Vec<Token>),
+    Nil,
+}
+
+impl Token {
+    /// The text of an atom or string.
+    pub fn as_str(&self) -> Option<String> {
+        match self {
+            Token::Atom(a) => Some(a.clone()),
+            Token::Str(s) => Some(String::from_utf8_lossy(s).to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Token::Atom(a) => Some(a.as_bytes()),
+            Token::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Token::Atom(a) => a.parse::<u64>().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Token>> {
+        match self {
+            Token::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn is_atom(&self, atom: &str) -> bool {
+        match self {
+            Token::Atom(a) => a.eq_ignore_ascii_case(atom),
+            _ => false,
+        }
+    }
+}
+
+/// Quotes a string for use as an IMAP astring.
+pub fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Splits a complete response (literals included) into tokens.
+pub fn tokenize(data: &[u8]) -> Vec<Token> {
+    let mut pos = 0;
+    tokens(data, &mut pos, None)
+}
+
+fn tokens(data: &[u8], pos: &mut usize, close: Option<u8>) -> Vec<Token> {
+    let mut out = Vec::new();
+
+    while *pos < data.len() {
+        let c = data[*pos];
+        match c {
+            b' ' | b'\r' | b'\n' => *pos += 1,
+            b'(' => {
+                *pos += 1;
+                out.push(Token::List(tokens(data, pos, Some(b')'))));
+            },
+            b')' => {
+                *pos += 1;
+                if close == Some(b')') {
+                    return out;
+                }
+            },
+            b'"' => {
+                *pos += 1;
+                let mut s = Vec::new();
+                while *pos < data.len() && data[*pos] != b'"' {
+                    if data[*pos] == b'\\' && *pos + 1 < data.len() {
+                        *pos += 1;
+                    }
+                    s.push(data[*pos]);
+                    *pos += 1;
+                }
+                *pos += 1;
+                out.push(Token::Str(s));
+            },
+            b'{' => {
+                let end = match data[*pos..].iter().position(|b| *b == b'}') {
+                    Some(e) => *pos + e,
+                    None => break,
+                };
+                let len = String::from_utf8_lossy(&data[*pos+1..end])
+                    .trim_end_matches('+')
+                    .parse::<usize>()
+                    .unwrap_or(0);
+
+                // skip "}\r\n"
+                *pos = end + 1;
+                while *pos < data.len() && (data[*pos] == b'\r' || data[*pos] == b'\n') {
+                    *pos += 1;
+                }
+
+                let end = std::cmp::min(*pos + len, data.len());
+                out.push(Token::Str(data[*pos..end].to_vec()));
+                *pos = end;
+            },
+            _ => {
+                // atoms may contain [...] sections with spaces and parens,
+                // as in BODY[HEADER.FIELDS (SUBJECT)]
+                let start = *pos;
+                let mut depth = 0;
+                while *pos < data.len() {
+                    let c = data[*pos];
+                    if c == b'[' {
+                        depth += 1;
+                    } else if c == b']' && depth > 0 {
+                        depth -= 1;
+                    } else if depth == 0 && (c == b' ' || c == b'(' || c == b')' || c == b'\r' || c == b'\n') {
+                        break;
+                    }
+                    *pos += 1;
+                }
+
+                let atom = String::from_utf8_lossy(&data[start..*pos]).to_string();
+                if atom.eq_ignore_ascii_case("NIL") {
+                    out.push(Token::Nil);
+                } else {
+                    out.push(Token::Atom(atom));
+                }
+            },
+        }
+    }
+
+    out
+}
+
+/// The attributes of a "n FETCH (...)" response.
+pub fn fetch_attributes(response: &[Token]) -> Option<&[Token]> {
+    if !response.get(1).map(|t| t.is_atom("FETCH")).unwrap_or(false) {
+        return None;
+    }
+
+    response.get(2)?.as_list().map(|l| &l[..])
+}
+
+/// Finds the value of an attribute in a FETCH response.
+pub fn attribute<'a>(attributes: &'a [Token], name: &str) -> Option<&'a Token> {
+    attributes.chunks(2)
+        .find(|a| a[0].is_atom(name))
+        .and_then(|a| a.get(1))
+}
+
+pub struct RawSession {
+    stream: BufReader<Stream>,
+    tag: u32,
+}
+
+impl RawSession {
+    pub fn connect(domain: &str, port: u16, uname: &str, pword: &str) -> Result<RawSession> {
+        let stream = Stream::connect(domain, port, true)?;
+        let mut session = RawSession {
+            stream: BufReader::new(stream),
+            tag: 0,
+        };
+
+        // greeting
+        session.read_response()?;
+        session.command(&format!("LOGIN {} {}", quote(uname), quote(pword))).map_err(|e| match e {
+            Error::Protocol(e) => Error::Auth(e),
+            e => e,
+        })?;
+
+        Ok(session)
+    }
+
+    /// Reads one response line, along with any literals it announces.
+    fn read_response(&mut self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        loop {
+            let start = data.len();
+            if self.stream.read_until(b'\n', &mut data)? == 0 {
+                return Err(Error::Connection("IMAP connection closed".to_string()));
+            }
+
+            let line = String::from_utf8_lossy(&data[start..]).to_string();
+            let line = line.trim_end();
+            trace!("S: {}", line);
+
+            // a line ending in {n} is followed by n bytes of literal data
+            let literal = if line.ends_with('}') {
+                line.rfind('{')
+                    .and_then(|i| line[i+1..line.len()-1].trim_end_matches('+').parse::<usize>().ok())
+            } else {
+                None
+            };
+
+            match literal {
+                Some(len) => {
+                    let mut buf = vec![0; len];
+                    self.stream.read_exact(&mut buf)?;
+                    trace!("S: {}", logger::body(&buf));
+                    data.extend_from_slice(&buf);
+                },
+                None => return Ok(data),
+            }
+        }
+    }
+
+    /// Runs a command and returns its untagged responses, without the
+    /// leading "*".
+    pub fn command(&mut self, cmd: &str) -> Result<Vec<Vec<Token>>> {
+        self.tag += 1;
+        let tag = format!("R{}", self.tag);
+        trace!("C: {} {}", tag, logger::command(cmd));
+
+        {
+            let stream = self.stream.get_mut();
+            stream.write_all(format!("{} {}\r\n", tag, cmd).as_bytes())?;
+            stream.flush()?;
+        }
+
+        let mut responses = Vec::new();
+        loop {
+            let data = self.read_response()?;
+
+            if data.starts_with(b"* ") {
+                responses.push(tokenize(&data[2..]));
+            } else if data.starts_with(tag.as_bytes()) {
+                let status = tokenize(&data[tag.len()..]);
+                if status.first().map(|s| s.is_atom("OK")).unwrap_or(false) {
+                    return Ok(responses);
+                }
+                let text = String::from_utf8_lossy(&data[tag.len()..]).trim().to_string();
+                if status.first().map(|s| s.is_atom("NO")).unwrap_or(false) {
+                    return Err(Error::refused(&text));
+                }
+                return Err(Error::Protocol(text));
+            }
+        }
+    }
+
+    pub fn logout(&mut self) {
+        let _ = self.command("LOGOUT");
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::BTreeMap;
+
+use crate::backend::{Backend, MailboxName, Summary};
+use crate::error::{Error, Result};
+use crate::http;
+use crate::json::Json;
+use crate::IMAPMailbox;
+
+const CORE: &str = "urn:ietf:params:jmap:core";
+const MAIL: &str = "urn:ietf:params:jmap:mail";
+
+// number of emails asked for per Email/query
+const PAGE_SIZE: u64 = 256;
+
+struct JmapMailbox {
+    id: String,
+    total: u32,
+    unread: u32,
+}
+
+pub struct JmapBackend {
+    auth: String,
+    api_url: String,
+    download_url: String,
+    upload_url: String,
+    account_id: String,
+    // mailbox path -> mailbox
+    mailboxes: BTreeMap<String, JmapMailbox>,
+    // mailbox role ("sent", "trash", ...) -> mailbox path
+    roles: BTreeMap<String, String>,
+    // email id -> blob id of the raw message
+    blobs: BTreeMap<String, String>,
+    // destroy deleted emails rather than giving them $deleted
+    expunge: bool,
+}
+
+/// Maps an IMAP flag onto its JMAP keyword.
+fn keyword(flag: &str) -> String {
+    match flag {
+        "\\Seen" => "$seen".to_string(),
+        "\\Flagged" => "$flagged".to_string(),
+        "\\Answered" => "$answered".to_string(),
+        "\\Draft" => "$draft".to_string(),
+        "\\Deleted" => "$deleted".to_string(),
+        f => f.to_lowercase(),
+    }
+}
+
+/// The IMAP flag for a keyword, the reverse of `keyword`.
+fn flag(keyword: &str) -> String {
+    match keyword {
+        "$seen" => "\\Seen".to_string(),
+        "$flagged" => "\\Flagged".to_string(),
+        "$answered" => "\\Answered".to_string(),
+        "$draft" => "\\Draft".to_string(),
+        "$deleted" => "\\Deleted".to_string(),
+        k => k.to_string(),
+    }
+}
+
+/// The error for an HTTP request that failed with `status`.
+fn http_error(what: &str, status: u16) -> Error {
+    let text = format!("{} failed with {}", what, status);
+    match status {
+        401 => Error::Auth(text),
+        403 => Error::Permission(text),
+        404 => Error::NotFound(text),
+        413 | 507 => Error::Quota(text),
+        _ => Error::Protocol(text),
+    }
+}
+
+/// The error for a method error or SetError of type `kind` (RFC 8620
+/// section 3.6).
+fn method_error(kind: &str, text: String) -> Error {
+    match kind {
+        "forbidden" | "accountReadOnly" => Error::Permission(text),
+        "notFound" | "accountNotFound" => Error::NotFound(text),
+        "overQuota" | "tooLarge" => Error::Quota(text),
+        _ => Error::Protocol(format!("{} ({})", text, kind)),
+    }
+}
+
+/// The type of the SetError for `id` in a `not...` map of a /set or
+/// /import response, if there is one.
+fn set_error(result: Option<&Json>, map: &str, id: &str) -> Option<String> {
+    result.and_then(|r| r.get(map))
+        .and_then(|n| n.get(id))
+        .map(|e| e.get("type").and_then(|t| t.as_str()).unwrap_or("unknown").to_string())
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl JmapBackend {
+    /// `domain` is either a host name, whose session resource is looked up
+    /// at `/.well-known/jmap`, or the full URL of the session resource.
+    pub fn connect(domain: &str, port: u16, uname: &str, pword: &str) -> Result<JmapBackend> {
+        let session_url = if domain.contains("://") {
+            domain.to_string()
+        } else {
+            format!("https://{}:{}/.well-known/jmap", domain, port)
+        };
+
+        let auth = format!("Basic {}", base64::encode(&format!("{}:{}", uname, pword)));
+
+        let response = http::request("GET", &session_url, &[
+            ("Authorization", &auth),
+            ("Accept", "application/json"),
+        ], None)?;
+
+        if !response.is_success() {
+            return Err(http_error("JMAP session request", response.status));
+        }
+
+        let session = Json::parse(&String::from_utf8_lossy(&response.body))?;
+        debug!("created JMAP session");
+
+        let base = http::Url::parse(&session_url)?;
+        let field = |name: &str| {
+            session.get(name)
+                .and_then(|v| v.as_str())
+                .map(|v| base.join(v))
+                .ok_or_else(|| Error::Protocol(format!("JMAP session has no {}", name)))
+        };
+
+        let api_url = field("apiUrl")?;
+        let download_url = field("downloadUrl")?;
+        let upload_url = field("uploadUrl")?;
+
+        let account_id = session.get("primaryAccounts")
+            .and_then(|a| a.get(MAIL))
+            .and_then(|a| a.as_str())
+            .ok_or_else(|| Error::Protocol("no primary mail account".to_string()))?
+            .to_string();
+
+        Ok(JmapBackend {
+            auth,
+            api_url,
+            download_url,
+            upload_url,
+            account_id,
+            mailboxes: BTreeMap::new(),
+            roles: BTreeMap::new(),
+            blobs: BTreeMap::new(),
+            expunge: true,
+        })
+    }
+
+    /// Sends a batch of method calls and returns the arguments of each
+    /// response, in order.
+    fn call(&mut self, calls: Vec<(&str, Json)>) -> Result<Vec<Json>> {
+        let method_calls = calls.into_iter()
+            .enumerate()
+            .map(|(i, (name, args))| {
+                Json::Array(vec![Json::str(name), args, Json::String(i.to_string())])
+            })
+            .collect();
+
+        let request = Json::object(vec![
+            ("using", Json::Array(vec![Json::str(CORE), Json::str(MAIL)])),
+            ("methodCalls", Json::Array(method_calls)),
+        ]);
+
+        let body = request.to_string();
+        let response = http::request("POST", &self.api_url, &[
+            ("Authorization", &self.auth),
+            ("Content-Type", "application/json"),
+            ("Accept", "application/json"),
+        ], Some(body.as_bytes()))?;
+
+        if !response.is_success() {
+            return Err(http_error("JMAP request", response.status));
+        }
+
+        let response = Json::parse(&String::from_utf8_lossy(&response.body))?;
+        let responses = response.get("methodResponses")
+            .and_then(|r| r.as_array())
+            .ok_or_else(|| Error::Parse("no methodResponses".to_string()))?;
+
+        let mut results = Vec::new();
+        for r in responses {
+            let name = r.as_array().and_then(|a| a.first()).and_then(|n| n.as_str());
+            let args = r.as_array().and_then(|a| a.get(1)).cloned().unwrap_or(Json::Null);
+
+            if name == Some("error") {
+                let kind = args.get("type").and_then(|t| t.as_str()).unwrap_or("unknown");
+                return Err(method_error(kind, "JMAP error".to_string()));
+            }
+            results.push(args);
+        }
+
+        Ok(results)
+    }
+
+    fn mailbox_id(&self, mailbox: &str) -> Result<String> {
+        match self.mailboxes.get(mailbox) {
+            Some(m) => Ok(m.id.clone()),
+            None => Err(Error::NotFound(format!("no mailbox {}", mailbox))),
+        }
+    }
+
+    /// Runs Email/set with a single update and checks it was applied.
+    fn update(&mut self, uid: &str, patch: Json) -> Result<()> {
+        let mut update = BTreeMap::new();
+        update.insert(uid.to_string(), patch);
+
+        let args = Json::object(vec![
+            ("accountId", Json::String(self.account_id.clone())),
+            ("update", Json::Object(update)),
+        ]);
+
+        let results = self.call(vec![("Email/set", args)])?;
+        match set_error(results.first(), "notUpdated", uid) {
+            Some(kind) => Err(method_error(&kind, format!("could not update email {}", uid))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Backend for JmapBackend {
+    fn list(&mut self) -> Result<Vec<MailboxName>> {
+        let args = Json::object(vec![
+            ("accountId", Json::String(self.account_id.clone())),
+            ("ids", Json::Null),
+            ("properties", Json::Array(vec![
+                Json::str("id"),
+                Json::str("name"),
+                Json::str("parentId"),
+                Json::str("role"),
+                Json::str("totalEmails"),
+                Json::str("unreadEmails"),
+            ])),
+        ]);
+
+        let results = self.call(vec![("Mailbox/get", args)])?;
+        let list = results.first()
+            .and_then(|r| r.get("list"))
+            .and_then(|l| l.as_array())
+            .cloned()
+            .unwrap_or_default();
+
+        let by_id: BTreeMap<String, &Json> = list.iter()
+            .filter_map(|m| Some((m.get("id")?.as_str()?.to_string(), m)))
+            .collect();
+
+        self.mailboxes.clear();
+        self.roles.clear();
+        let mut names = Vec::new();
+
+        for (id, mailbox) in by_id.iter() {
+            // walk up the parents to build the full path
+            let mut parts = Vec::new();
+            let mut current = Some(*mailbox);
+            while let Some(m) = current {
+                parts.push(m.get("name").and_then(|n| n.as_str()).unwrap_or("").to_string());
+                current = m.get("parentId")
+                    .and_then(|p| p.as_str())
+                    .and_then(|p| by_id.get(p).cloned());
+
+                if parts.len() > by_id.len() {
+                    break;
+                }
+            }
+            parts.reverse();
+
+            // JMAP mailboxes are known by their path, the id is looked up
+            // when needed
+            let name = parts.join("/");
+
+            if let Some(role) = mailbox.get("role").and_then(|r| r.as_str()) {
+                self.roles.insert(role.to_string(), name.clone());
+            }
+
+            names.push(MailboxName::new(&name, parts));
+
+            self.mailboxes.insert(name, JmapMailbox {
+                id: id.clone(),
+                total: mailbox.get("totalEmails").and_then(|t| t.as_u64()).unwrap_or(0) as u32,
+                unread: mailbox.get("unreadEmails").and_then(|t| t.as_u64()).unwrap_or(0) as u32,
+            });
+        }
+
+        Ok(names)
+    }
+
+    fn examine(&mut self, mailbox: &str) -> Result<IMAPMailbox> {
+        match self.mailboxes.get(mailbox) {
+            Some(m) => Ok(IMAPMailbox {
+                exists: m.total,
+                unseen: Some(m.unread),
+                ..IMAPMailbox::default()
+            }),
+            None => Err(Error::NotFound(format!("no mailbox {}", mailbox))),
+        }
+    }
+
+    fn messages(&mut self, mailbox: &str) -> Result<Vec<Summary>> {
+        let id = self.mailbox_id(mailbox)?;
+        let mut summaries = Vec::new();
+        let mut position = 0;
+
+        loop {
+            let query = Json::object(vec![
+                ("accountId", Json::String(self.account_id.clone())),
+                ("filter", Json::object(vec![("inMailbox", Json::String(id.clone()))])),
+                ("sort", Json::Array(vec![Json::object(vec![
+                    ("property", Json::str("receivedAt")),
+                    ("isAscending", Json::Bool(true)),
+                ])])),
+                ("position", Json::Number(position as f64)),
+                ("limit", Json::Number(PAGE_SIZE as f64)),
+                ("calculateTotal", Json::Bool(true)),
+            ]);
+
+            let get = Json::object(vec![
+                ("accountId", Json::String(self.account_id.clone())),
+                ("#ids", Json::object(vec![
+                    ("resultOf", Json::str("0")),
+                    ("name", Json::str("Email/query")),
+                    ("path", Json::str("/ids")),
+                ])),
+                ("properties", Json::Array(vec![
+                    Json::str("id"),
+                    Json::str("blobId"),
+                    Json::str("size"),
+                    Json::str("receivedAt"),
+                    Json::str("header:Subject"),
+                    Json::str("keywords"),
+                ])),
+            ]);
+
+            let results = self.call(vec![("Email/query", query), ("Email/get", get)])?;
+
+            let total = results.first()
+                .and_then(|r| r.get("total"))
+                .and_then(|t| t.as_u64())
+                .unwrap_or(0);
+
+            let list = results.get(1)
+                .and_then(|r| r.get("list"))
+                .and_then(|l| l.as_array())
+                .cloned()
+                .unwrap_or_default();
+
+            for email in list.iter() {
+                let uid = match email.get("id").and_then(|i| i.as_str()) {
+                    Some(i) => i.to_string(),
+                    None => continue,
+                };
+
+                if let Some(blob) = email.get("blobId").and_then(|b| b.as_str()) {
+                    self.blobs.insert(uid.clone(), blob.to_string());
+                }
+
+                let mut summary = Summary::new(uid);
+                summary.size = email.get("size").and_then(|s| s.as_u64()).map(|s| s as u32);
+                // UTCDate, 2014-10-30T06:12:00Z
+                summary.received = email.get("receivedAt")
+                    .and_then(|r| r.as_str())
+                    .and_then(|r| time::strptime(r, "%Y-%m-%dT%H:%M:%SZ").ok())
+                    .map(|t| t.to_timespec().sec);
+                summary.header = email.get("header:Subject")
+                    .and_then(|s| s.as_str())
+                    .map(|s| format!("Subject:{}\r\n\r\n", s).into_bytes());
+                summary.flags = email.get("keywords")
+                    .and_then(|k| k.as_object())
+                    .map(|k| k.keys().map(|k| flag(k)).collect());
+
+                summaries.push(summary);
+            }
+
+            position += PAGE_SIZE;
+            if list.is_empty() || position >= total {
+                break;
+            }
+        }
+
+        Ok(summaries)
+    }
+
+    fn fetch(&mut self, _mailbox: &str, uid: &str) -> Result<Vec<u8>> {
+        let blob = match self.blobs.get(uid) {
+            Some(b) => b.clone(),
+            None => return Err(Error::NotFound(format!("no blob for email {}", uid))),
+        };
+
+        let url = self.download_url
+            .replace("{accountId}", &percent_encode(&self.account_id))
+            .replace("{blobId}", &percent_encode(&blob))
+            .replace("{type}", &percent_encode("message/rfc822"))
+            .replace("{name}", "email.eml");
+
+        let response = http::request("GET", &url, &[("Authorization", &self.auth)], None)?;
+
+        if response.is_success() {
+            Ok(response.body)
+        } else {
+            Err(http_error("blob download", response.status))
+        }
+    }
+
+    fn delete(&mut self, mailbox: &str, uid: &str) -> Result<()> {
+        if !self.expunge {
+            return self.set_flags(mailbox, uid, &["\\Deleted"], &[]);
+        }
+
+        let args = Json::object(vec![
+            ("accountId", Json::String(self.account_id.clone())),
+            ("destroy", Json::Array(vec![Json::str(uid)])),
+        ]);
+
+        let results = self.call(vec![("Email/set", args)])?;
+        if let Some(kind) = set_error(results.first(), "notDestroyed", uid) {
+            return Err(method_error(&kind, format!("could not destroy email {}", uid)));
+        }
+
+        self.blobs.remove(uid);
+        Ok(())
+    }
+
+    fn set_expunge(&mut self, expunge: bool) {
+        self.expunge = expunge;
+    }
+
+    fn has_flags(&self) -> bool {
+        true
+    }
+
+    fn set_flags(&mut self, _mailbox: &str, uid: &str, add: &[&str], remove: &[&str]) -> Result<()> {
+        let mut patch = BTreeMap::new();
+        for flag in add {
+            patch.insert(format!("keywords/{}", keyword(flag)), Json::Bool(true));
+        }
+        for flag in remove {
+            patch.insert(format!("keywords/{}", keyword(flag)), Json::Null);
+        }
+
+        self.update(uid, Json::Object(patch))
+    }
+
+    fn move_to(&mut self, mailbox: &str, uid: &str, dest: &str) -> Result<()> {
+        let from = self.mailbox_id(mailbox)?;
+        let to = self.mailbox_id(dest)?;
+
+        let mut patch = BTreeMap::new();
+        patch.insert(format!("mailboxIds/{}", from), Json::Null);
+        patch.insert(format!("mailboxIds/{}", to), Json::Bool(true));
+
+        self.update(uid, Json::Object(patch))
+    }
+
+    fn has_append(&self) -> bool {
+        true
+    }
+
+    /// Uploads the message as a blob and imports it with Email/import.
+    fn append(&mut self, mailbox: &str, content: &[u8]) -> Result<()> {
+        let id = self.mailbox_id(mailbox)?;
+        let url = self.upload_url.replace("{accountId}", &percent_encode(&self.account_id));
+
+        let response = http::request("POST", &url, &[
+            ("Authorization", &self.auth),
+            ("Content-Type", "message/rfc822"),
+        ], Some(content))?;
+
+        if !response.is_success() {
+            return Err(http_error("upload", response.status));
+        }
+
+        let uploaded = Json::parse(&String::from_utf8_lossy(&response.body))?;
+        let blob = uploaded.get("blobId")
+            .and_then(|b| b.as_str())
+            .ok_or_else(|| Error::Parse("upload returned no blobId".to_string()))?
+            .to_string();
+
+        let mut mailbox_ids = BTreeMap::new();
+        mailbox_ids.insert(id, Json::Bool(true));
+
+        let mut emails = BTreeMap::new();
+        emails.insert("0".to_string(), Json::object(vec![
+            ("blobId", Json::String(blob)),
+            ("mailboxIds", Json::Object(mailbox_ids)),
+            ("keywords", Json::object(vec![("$seen", Json::Bool(true))])),
+        ]));
+
+        let args = Json::object(vec![
+            ("accountId", Json::String(self.account_id.clone())),
+            ("emails", Json::Object(emails)),
+        ]);
+
+        let results = self.call(vec![("Email/import", args)])?;
+        match set_error(results.first(), "notCreated", "0") {
+            Some(kind) => Err(method_error(&kind, format!("could not import email into {}", mailbox))),
+            None => Ok(()),
+        }
+    }
+
+    fn special_use(&self, attribute: &str) -> Option<String> {
+        let role = attribute.trim_start_matches('\\').to_lowercase();
+        self.roles.get(&role).cloned()
+    }
+
+    fn logout(&mut self) {}
+}
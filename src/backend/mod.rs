@@ -0,0 +1,273 @@
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::search::Criterion;
+use crate::IMAPMailbox;
+
+pub mod imap;
+pub mod imap_raw;
+pub mod jmap;
+pub mod pop3;
+mod structure;
+
+pub use self::imap::ImapBackend;
+pub use self::jmap::JmapBackend;
+pub use self::pop3::Pop3Backend;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    IMAP,
+    POP3,
+    JMAP,
+}
+
+impl FromStr for Protocol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Protocol> {
+        match s.to_lowercase().as_str() {
+            "imap" => Ok(Protocol::IMAP),
+            "pop3" | "pop" => Ok(Protocol::POP3),
+            "jmap" => Ok(Protocol::JMAP),
+            _ => Err(Error::Parse(format!("unknown protocol {}", s))),
+        }
+    }
+}
+
+impl Protocol {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Protocol::IMAP => 993,
+            Protocol::POP3 => 995,
+            Protocol::JMAP => 443,
+        }
+    }
+}
+
+/// What a backend knows about a message when listing a mailbox. `uid` is
+/// whatever the server uses as a stable identity for the message (an IMAP
+/// UID, a POP3 UIDL value, ...).
+pub struct Summary {
+    pub uid: String,
+    pub size: Option<u32>,
+    pub header: Option<Vec<u8>>,
+    // IMAP style flags, None if the backend has no flags
+    pub flags: Option<Vec<String>>,
+    // when the server received the message, in seconds since the epoch
+    pub received: Option<i64>,
+    // an id the message has in every mailbox it is in (Gmail's
+    // X-GM-MSGID), for servers that show one message in several mailboxes
+    pub id: Option<String>,
+}
+
+impl Summary {
+    pub fn new(uid: String) -> Summary {
+        Summary {
+            uid,
+            size: None,
+            header: None,
+            flags: None,
+            received: None,
+            id: None,
+        }
+    }
+}
+
+/// The counts of a mailbox, as IMAP STATUS gives them.
+pub struct Status {
+    pub messages: u32,
+    pub recent: Option<u32>,
+    pub unseen: Option<u32>,
+    pub uid_next: Option<u32>,
+    pub uid_validity: Option<u32>,
+}
+
+/// How much of an account's storage is used, as IMAP QUOTA (RFC 2087) gives
+/// it. The limits are None when the server doesn't set one.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    // in bytes
+    pub storage: u64,
+    pub storage_limit: Option<u64>,
+    pub messages: u64,
+    pub message_limit: Option<u64>,
+}
+
+/// A (year, month), 1 for January.
+pub type Month = (i32, u32);
+
+/// A conversation in a mailbox.
+pub struct Thread {
+    // the subject and date of the first message
+    pub subject: Option<String>,
+    pub date: Option<i64>,
+    // the messages in the thread, the first one first
+    pub uids: Vec<String>,
+}
+
+/// A mailbox as listed by the server. `name` is what the server calls it and
+/// is what gets passed back to the other `Backend` methods, `path` is where it
+/// belongs in the directory tree.
+pub struct MailboxName {
+    pub name: String,
+    pub path: Vec<String>,
+    // false for \Noselect and \NonExistent mailboxes, which only hold
+    // other mailboxes
+    pub selectable: bool,
+    // from \HasChildren / \HasNoChildren / \NoInferiors, if the server said
+    pub has_children: Option<bool>,
+}
+
+impl MailboxName {
+    pub fn new(name: &str, path: Vec<String>) -> MailboxName {
+        MailboxName {
+            name: name.to_string(),
+            path,
+            selectable: true,
+            has_children: None,
+        }
+    }
+}
+
+/// The operations REmailFS needs from a mail store.
+pub trait Backend: Send {
+    fn list(&mut self) -> Result<Vec<MailboxName>>;
+
+    fn examine(&mut self, mailbox: &str) -> Result<IMAPMailbox>;
+
+    fn messages(&mut self, mailbox: &str) -> Result<Vec<Summary>>;
+
+    /// The current counts of a mailbox. This shouldn't change which mailbox
+    /// is selected.
+    fn status(&mut self, mailbox: &str) -> Result<Status> {
+        let info = self.examine(mailbox)?;
+
+        Ok(Status {
+            messages: info.exists,
+            recent: Some(info.recent),
+            unseen: info.unseen,
+            uid_next: info.uid_next,
+            uid_validity: info.uid_validity,
+        })
+    }
+
+    fn fetch(&mut self, mailbox: &str, uid: &str) -> Result<Vec<u8>>;
+
+    /// Fetches enough of a message to show its text. Backends that can
+    /// download parts of a message leave the attachments out, the rest
+    /// fetch the whole message. Neither should mark the message as read.
+    fn fetch_text(&mut self, mailbox: &str, uid: &str) -> Result<Vec<u8>> {
+        self.fetch(mailbox, uid)
+    }
+
+    fn delete(&mut self, mailbox: &str, uid: &str) -> Result<()>;
+
+    /// Whether `delete` and `move_to` may expunge messages. When they may
+    /// not, messages are only flagged `\Deleted`.
+    fn set_expunge(&mut self, _expunge: bool) {
+    }
+
+    /// Whether the listed messages come with their flags.
+    fn has_flags(&self) -> bool {
+        false
+    }
+
+    /// Adds and removes IMAP style flags (`\\Seen`, `\\Flagged`, ...).
+    fn set_flags(&mut self, _mailbox: &str, _uid: &str, _add: &[&str], _remove: &[&str]) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+
+    /// Moves a message to another mailbox. The message may get a new uid in
+    /// the destination.
+    fn move_to(&mut self, _mailbox: &str, _uid: &str, _dest: &str) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+
+    /// Whether `append` is supported.
+    fn has_append(&self) -> bool {
+        false
+    }
+
+    /// Stores a message in a mailbox.
+    fn append(&mut self, _mailbox: &str, _content: &[u8]) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+
+    /// Whether `search` is supported.
+    fn has_search(&self) -> bool {
+        false
+    }
+
+    /// The uids of the messages in a mailbox matching all of the criteria.
+    fn search(&mut self, _mailbox: &str, _criteria: &[Criterion]) -> Result<Vec<String>> {
+        Err(Error::Unsupported)
+    }
+
+    /// Summaries of some of the messages in a mailbox, with the Subject and
+    /// Date headers and the time each message was received.
+    fn summaries(&mut self, _mailbox: &str, _uids: &[String]) -> Result<Vec<Summary>> {
+        Err(Error::Unsupported)
+    }
+
+    /// The (year, month) of the oldest and newest messages in a mailbox, by
+    /// the time they were received or by their Date header. None if the
+    /// mailbox is empty.
+    fn date_range(&mut self, _mailbox: &str, _sent: bool) -> Result<Option<(Month, Month)>> {
+        Err(Error::Unsupported)
+    }
+
+    /// Whether messages have labels, as on Gmail.
+    fn has_labels(&self) -> bool {
+        false
+    }
+
+    /// The labels of a message.
+    fn labels(&mut self, _mailbox: &str, _uid: &str) -> Result<Vec<String>> {
+        Err(Error::Unsupported)
+    }
+
+    /// Adds and removes labels of a message.
+    fn set_labels(&mut self, _mailbox: &str, _uid: &str, _add: &[String], _remove: &[String]) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+
+    /// Whether `threads` is supported.
+    fn has_threads(&self) -> bool {
+        false
+    }
+
+    /// The conversations in a mailbox in date order.
+    fn threads(&mut self, _mailbox: &str) -> Result<Vec<Thread>> {
+        Err(Error::Unsupported)
+    }
+
+    /// The rights the user has on a mailbox as RFC 4314 letters, "lrswi"
+    /// etc.
+    fn rights(&mut self, _mailbox: &str) -> Result<String> {
+        Err(Error::Unsupported)
+    }
+
+    /// The storage the account uses and is allowed.
+    fn quota(&mut self) -> Result<Quota> {
+        Err(Error::Unsupported)
+    }
+
+    /// Creates a mailbox at a directory path and returns its server name.
+    fn create(&mut self, _path: &[String]) -> Result<String> {
+        Err(Error::Unsupported)
+    }
+
+    /// Renames a mailbox, along with the mailboxes below it, and returns its
+    /// new server name.
+    fn rename(&mut self, _mailbox: &str, _path: &[String]) -> Result<String> {
+        Err(Error::Unsupported)
+    }
+
+    /// Finds the mailbox with a special-use attribute such as `\Sent` or
+    /// `\Trash` (RFC 6154).
+    fn special_use(&self, _attribute: &str) -> Option<String> {
+        None
+    }
+
+    fn logout(&mut self);
+}
@@ -0,0 +1,424 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+
+use crate::backend::{Backend, MailboxName, Summary};
+use crate::error::{Error, Result};
+use crate::logger;
+use crate::net::Stream;
+use crate::md5;
+use crate::IMAPMailbox;
+
+/// POP3 only knows about a single maildrop, which is presented as this
+/// mailbox.
+pub const MAILDROP: &str = "INBOX";
+
+pub struct Pop3Backend {
+    stream: BufReader<Stream>,
+    // UIDL value -> message number in the current session
+    numbers: BTreeMap<String, u32>,
+    // POP3 has no flags, so messages can't be deleted without this
+    expunge: bool,
+}
+
+impl Pop3Backend {
+    /// Connects over implicit TLS and authenticates with APOP when `apop` is
+    /// set, USER/PASS otherwise.
+    pub fn connect(domain: &str, port: u16, uname: &str, pword: &str, apop: bool) -> Result<Pop3Backend> {
+        let stream = Stream::connect(domain, port, true)?;
+        debug!("created POP3 connection");
+
+        Pop3Backend::login(stream, uname, pword, apop)
+    }
+
+    /// Reads the greeting on a new connection and authenticates.
+    fn login(stream: Stream, uname: &str, pword: &str, apop: bool) -> Result<Pop3Backend> {
+        let mut pop = Pop3Backend {
+            stream: BufReader::new(stream),
+            numbers: BTreeMap::new(),
+            expunge: true,
+        };
+
+        let greeting = pop.read_status()?;
+
+        if apop {
+            // the timestamp is the <...> part of the greeting
+            let timestamp = match (greeting.find('<'), greeting.rfind('>')) {
+                (Some(s), Some(e)) if s < e => &greeting[s..e+1],
+                _ => return Err(Error::Auth("server does not support APOP".to_string())),
+            };
+
+            let mut secret = timestamp.to_string();
+            secret.push_str(pword);
+
+            pop.command(&format!("APOP {} {}", uname, md5::hex_digest(secret.as_bytes())))
+                .map_err(Pop3Backend::login_error)?;
+        } else {
+            pop.command(&format!("USER {}", uname)).map_err(Pop3Backend::login_error)?;
+            pop.command(&format!("PASS {}", pword)).map_err(Pop3Backend::login_error)?;
+        }
+        debug!("created session");
+
+        Ok(pop)
+    }
+
+    /// Any -ERR while logging in is a refused login.
+    fn login_error(error: Error) -> Error {
+        match error {
+            Error::Protocol(e) => Error::Auth(e),
+            e => e,
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(Error::Connection("connection closed".to_string()));
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        trace!("S: {}", line);
+        Ok(line)
+    }
+
+    fn read_status(&mut self) -> Result<String> {
+        let line = self.read_line()?;
+
+        match line.strip_prefix("+OK") {
+            Some(text) => Ok(text.trim_start().to_string()),
+            None => Err(Error::refused(&line)),
+        }
+    }
+
+    fn command(&mut self, cmd: &str) -> Result<String> {
+        trace!("C: {}", logger::command(cmd));
+        let stream = self.stream.get_mut();
+        stream.write_all(cmd.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+
+        self.read_status()
+    }
+
+    /// Sends a command with a multi-line response and returns the response
+    /// body with the terminating "." removed and dot-stuffing undone.
+    fn multiline(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        self.command(cmd)?;
+
+        let mut data = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            if self.stream.read_until(b'\n', &mut line)? == 0 {
+                return Err(Error::Connection("connection closed".to_string()));
+            }
+
+            if line == b".\r\n" || line == b".\n" {
+                break;
+            }
+
+            if line.starts_with(b"..") {
+                data.extend_from_slice(&line[1..]);
+            } else {
+                data.extend_from_slice(&line);
+            }
+        }
+
+        trace!("S: {}", logger::body(&data));
+        Ok(data)
+    }
+
+    /// Parses the "<number> <value>" lines returned by LIST and UIDL.
+    fn listing(&mut self, cmd: &str) -> Result<Vec<(u32, String)>> {
+        let data = self.multiline(cmd)?;
+
+        Ok(String::from_utf8_lossy(&data)
+            .lines()
+            .filter_map(|l| {
+                let mut split = l.split_whitespace();
+                let num = split.next()?.parse::<u32>().ok()?;
+                let val = split.next()?.to_string();
+                Some((num, val))
+            })
+            .collect())
+    }
+
+    fn number(&mut self, uid: &str) -> Result<u32> {
+        if !self.numbers.contains_key(uid) {
+            self.numbers = self.listing("UIDL")?
+                .into_iter()
+                .map(|(n, u)| (u, n))
+                .collect();
+        }
+
+        match self.numbers.get(uid) {
+            Some(n) => Ok(*n),
+            None => Err(Error::NotFound(format!("no message with UIDL {}", uid))),
+        }
+    }
+
+    fn check_mailbox(mailbox: &str) -> Result<()> {
+        if mailbox == MAILDROP {
+            Ok(())
+        } else {
+            Err(Error::NotFound(format!("no mailbox {}", mailbox)))
+        }
+    }
+}
+
+impl Backend for Pop3Backend {
+    fn list(&mut self) -> Result<Vec<MailboxName>> {
+        let mut maildrop = MailboxName::new(MAILDROP, vec![MAILDROP.to_string()]);
+        maildrop.has_children = Some(false);
+        Ok(vec![maildrop])
+    }
+
+    fn examine(&mut self, mailbox: &str) -> Result<IMAPMailbox> {
+        Pop3Backend::check_mailbox(mailbox)?;
+
+        let stat = self.command("STAT")?;
+        let exists = stat.split_whitespace()
+            .next()
+            .and_then(|n| n.parse::<u32>().ok())
+            .unwrap_or(0);
+
+        Ok(IMAPMailbox { exists, ..IMAPMailbox::default() })
+    }
+
+    fn messages(&mut self, mailbox: &str) -> Result<Vec<Summary>> {
+        Pop3Backend::check_mailbox(mailbox)?;
+
+        let uidl = self.listing("UIDL")?;
+        let sizes: BTreeMap<u32, u32> = self.listing("LIST")?
+            .into_iter()
+            .filter_map(|(n, s)| Some((n, s.parse::<u32>().ok()?)))
+            .collect();
+
+        self.numbers.clear();
+
+        let mut summaries = Vec::new();
+        for (num, uid) in uidl {
+            let mut summary = Summary::new(uid.clone());
+            summary.size = sizes.get(&num).cloned();
+            summary.header = self.multiline(&format!("TOP {} 0", num)).ok();
+
+            self.numbers.insert(uid, num);
+            summaries.push(summary);
+        }
+
+        Ok(summaries)
+    }
+
+    fn fetch(&mut self, mailbox: &str, uid: &str) -> Result<Vec<u8>> {
+        Pop3Backend::check_mailbox(mailbox)?;
+
+        let num = self.number(uid)?;
+        self.multiline(&format!("RETR {}", num))
+    }
+
+    /// Marks the message for deletion, the server removes it once the
+    /// session ends with QUIT.
+    fn delete(&mut self, mailbox: &str, uid: &str) -> Result<()> {
+        Pop3Backend::check_mailbox(mailbox)?;
+        if !self.expunge {
+            return Err(Error::Unsupported);
+        }
+
+        let num = self.number(uid)?;
+        self.command(&format!("DELE {}", num))?;
+        self.numbers.remove(uid);
+        Ok(())
+    }
+
+    fn set_expunge(&mut self, expunge: bool) {
+        self.expunge = expunge;
+    }
+
+    fn logout(&mut self) {
+        let _ = self.command("QUIT");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::{Pop3Backend, MAILDROP};
+    use crate::backend::Backend;
+    use crate::error::Error;
+    use crate::net::Stream;
+
+    // the example of RFC 1939 section 7
+    const GREETING: &str = "POP3 server ready <1896.697170952@dbc.mtview.ca.us>";
+
+    const FIRST: &[u8] = b"Subject: First\r\n\r\nHello\r\n";
+    // lines starting with "." are stuffed with another one when sent
+    const SECOND: &[u8] = b"Subject: Second\r\n\r\n.hidden\r\n.\r\n..\r\nend\r\n";
+
+    fn stuffed(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for line in data.split_inclusive(|b| *b == b'\n') {
+            if line.starts_with(b".") {
+                out.push(b'.');
+            }
+            out.extend_from_slice(line);
+        }
+        out.extend_from_slice(b".\r\n");
+        out
+    }
+
+    // the commands the client sent and the UIDLs left once it quit
+    type Session = (Vec<String>, Vec<String>);
+
+    /// A maildrop holding messages 1 and 2 with the UIDLs "a1" and "b2". The
+    /// password is "secret".
+    fn maildrop() -> (u16, JoinHandle<Session>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut messages = [Some(("a1", FIRST)), Some(("b2", SECOND))];
+            let mut commands = Vec::new();
+            write!(reader.get_mut(), "+OK {}\r\n", GREETING).unwrap();
+
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                commands.push(line.clone());
+
+                let words: Vec<&str> = line.split(' ').collect();
+                let message = |n: &str| n.parse::<usize>().ok()
+                    .and_then(|n| messages.get(n.wrapping_sub(1)).cloned().flatten());
+                let listing = |value: &dyn Fn(&[u8], &str) -> String| {
+                    let mut text = "+OK\r\n".to_string();
+                    for (n, (uidl, m)) in messages.iter().enumerate().filter_map(|(n, m)| Some((n, (*m)?))) {
+                        text.push_str(&format!("{} {}\r\n", n + 1, value(m, uidl)));
+                    }
+                    text + ".\r\n"
+                };
+
+                let reply: Vec<u8> = match (words[0], words.get(1)) {
+                    ("USER", _) => b"+OK\r\n".to_vec(),
+                    ("PASS", Some(&"secret")) | ("APOP", _) => b"+OK logged in\r\n".to_vec(),
+                    ("PASS", _) => b"-ERR [AUTH] invalid password\r\n".to_vec(),
+                    ("STAT", _) => format!("+OK {} 100\r\n", messages.iter().flatten().count()).into_bytes(),
+                    ("UIDL", None) => listing(&|_, uidl| uidl.to_string()).into_bytes(),
+                    ("LIST", None) => listing(&|m, _| m.len().to_string()).into_bytes(),
+                    ("TOP", Some(n)) | ("RETR", Some(n)) => match message(n) {
+                        Some((_, m)) => {
+                            let data = if words[0] == "TOP" { &m[..m.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4] } else { m };
+                            [&b"+OK\r\n"[..], &stuffed(data)].concat()
+                        },
+                        None => b"-ERR no such message\r\n".to_vec(),
+                    },
+                    ("DELE", Some(n)) => match message(n) {
+                        Some(_) => {
+                            messages[n.parse::<usize>().unwrap() - 1] = None;
+                            b"+OK deleted\r\n".to_vec()
+                        },
+                        None => b"-ERR no such message\r\n".to_vec(),
+                    },
+                    ("QUIT", _) => {
+                        reader.get_mut().write_all(b"+OK bye\r\n").unwrap();
+                        break;
+                    },
+                    _ => b"-ERR unknown command\r\n".to_vec(),
+                };
+                reader.get_mut().write_all(&reply).unwrap();
+            }
+
+            let left = messages.iter().flatten().map(|(u, _)| u.to_string()).collect();
+            (commands, left)
+        });
+
+        (port, handle)
+    }
+
+    fn login(port: u16, password: &str, apop: bool) -> crate::error::Result<Pop3Backend> {
+        Pop3Backend::login(Stream::connect("127.0.0.1", port, false).unwrap(), "alice", password, apop)
+    }
+
+    #[test]
+    fn user_pass() {
+        let (port, server) = maildrop();
+        let mut pop = login(port, "secret", false).unwrap();
+        pop.logout();
+        assert_eq!(server.join().unwrap().0, vec!["USER alice", "PASS secret", "QUIT"]);
+
+        let (port, server) = maildrop();
+        match login(port, "wrong", false) {
+            Err(Error::Auth(e)) => assert_eq!(e, "-ERR [AUTH] invalid password"),
+            r => panic!("expected the login to be refused, got {:?}", r.map(|_| ())),
+        }
+        drop(server);
+    }
+
+    #[test]
+    fn apop() {
+        let (port, server) = maildrop();
+        login(port, "tanstaaf", true).unwrap().logout();
+        assert_eq!(server.join().unwrap().0[0], "APOP alice c4c9334bac560ecc979e58001b3e22fb");
+    }
+
+    #[test]
+    fn messages() {
+        let (port, server) = maildrop();
+        let mut pop = login(port, "secret", false).unwrap();
+
+        assert_eq!(pop.examine(MAILDROP).unwrap().exists, 2);
+        let summaries = pop.messages(MAILDROP).unwrap();
+        let found: Vec<_> = summaries.iter()
+            .map(|s| (s.uid.clone(), s.size, s.header.clone()))
+            .collect();
+        assert_eq!(found, vec![
+            ("a1".to_string(), Some(FIRST.len() as u32), Some(b"Subject: First\r\n\r\n".to_vec())),
+            ("b2".to_string(), Some(SECOND.len() as u32), Some(b"Subject: Second\r\n\r\n".to_vec())),
+        ]);
+
+        // the lines starting with "." come back as they were
+        assert_eq!(pop.fetch(MAILDROP, "b2").unwrap(), SECOND);
+        assert!(matches!(pop.fetch("Sent", "b2"), Err(Error::NotFound(_))));
+        pop.logout();
+
+        let commands = server.join().unwrap().0;
+        assert_eq!(commands[2..], ["STAT", "UIDL", "LIST", "TOP 1 0", "TOP 2 0", "RETR 2", "QUIT"]);
+    }
+
+    #[test]
+    fn uidl() {
+        let (port, server) = maildrop();
+        let mut pop = login(port, "secret", false).unwrap();
+
+        // message numbers are looked up by UIDL when they aren't known yet
+        assert_eq!(pop.fetch(MAILDROP, "a1").unwrap(), FIRST);
+        assert_eq!(pop.fetch(MAILDROP, "b2").unwrap(), SECOND);
+        assert!(matches!(pop.fetch(MAILDROP, "c3"), Err(Error::NotFound(_))));
+        pop.logout();
+
+        let commands = server.join().unwrap().0;
+        assert_eq!(commands[2..], ["UIDL", "RETR 1", "RETR 2", "UIDL", "QUIT"]);
+    }
+
+    #[test]
+    fn dele() {
+        let (port, server) = maildrop();
+        let mut pop = login(port, "secret", false).unwrap();
+
+        // without expunging nothing is deleted, POP3 has no flags
+        pop.set_expunge(false);
+        assert!(matches!(pop.delete(MAILDROP, "a1"), Err(Error::Unsupported)));
+
+        pop.set_expunge(true);
+        pop.delete(MAILDROP, "a1").unwrap();
+        assert!(matches!(pop.fetch(MAILDROP, "a1"), Err(Error::NotFound(_))));
+        pop.logout();
+
+        let (commands, left) = server.join().unwrap();
+        assert_eq!(commands[2..], ["UIDL", "DELE 1", "UIDL", "QUIT"]);
+        assert_eq!(left, vec!["b2"]);
+    }
+}
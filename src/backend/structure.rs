@@ -0,0 +1,294 @@
+// BODYSTRUCTURE (RFC 3501 section 7.4.2) parsing, used to download only the
+// text parts of a message and put a message back together around them.
+
+use std::collections::BTreeMap;
+
+use crate::backend::imap_raw::Token;
+
+pub enum Structure {
+    Multipart {
+        subtype: String,
+        boundary: Option<String>,
+        parts: Vec<Structure>,
+    },
+    Part {
+        mimetype: String,
+        // the section number to fetch the part with, "1.2" etc
+        section: String,
+        wanted: bool,
+    },
+    // an email attached to another
+    Message {
+        section: String,
+        body: Box<Structure>,
+    },
+}
+
+impl Structure {
+    /// Parses a BODYSTRUCTURE list.
+    pub fn parse(body: &[Token]) -> Option<Structure> {
+        Structure::parse_body(body, "")
+    }
+
+    /// Parses the body of a message whose parts are numbered under
+    /// `prefix`. The body of a message that isn't multipart is part 1.
+    fn parse_body(body: &[Token], prefix: &str) -> Option<Structure> {
+        match body.first() {
+            Some(Token::List(_)) => Structure::parse_part(body, prefix),
+            _ => Structure::parse_part(body, &child(prefix, 0)),
+        }
+    }
+
+    fn parse_part(body: &[Token], section: &str) -> Option<Structure> {
+        // a multipart body is its parts followed by the subtype
+        if let Some(Token::List(_)) = body.first() {
+            let mut parts = Vec::new();
+            let mut i = 0;
+            while let Some(Token::List(p)) = body.get(i) {
+                parts.push(Structure::parse_part(p, &child(section, i))?);
+                i += 1;
+            }
+
+            let subtype = body.get(i)?.as_str()?.to_lowercase();
+            let boundary = body.get(i + 1)
+                .and_then(|p| p.as_list())
+                .and_then(|p| param(p, "boundary"));
+
+            return Some(Structure::Multipart { subtype, boundary, parts });
+        }
+
+        let mimetype = format!("{}/{}", body.first()?.as_str()?, body.get(1)?.as_str()?).to_lowercase();
+
+        // a message has its envelope, body structure and number of lines
+        // after the 7 basic fields, its parts are numbered under its own
+        if mimetype == "message/rfc822" {
+            let inner = body.get(8)?.as_list()?;
+            let body = Box::new(Structure::parse_body(inner, section)?);
+            return Some(Structure::Message { section: section.to_string(), body });
+        }
+
+        // the MD5 and then the disposition follow the 7 basic fields, text
+        // parts have the number of lines before them
+        let disposition = if mimetype.starts_with("text/") { 9 } else { 8 };
+        let attachment = body.get(disposition)
+            .and_then(|d| d.as_list())
+            .and_then(|d| d.first())
+            .map(|d| d.is_atom("attachment") || d.as_str().map(|s| s.eq_ignore_ascii_case("attachment")).unwrap_or(false))
+            .unwrap_or(false);
+
+        let wanted = (mimetype == "text/plain" || mimetype == "text/html") && !attachment;
+
+        Some(Structure::Part { mimetype, section: section.to_string(), wanted })
+    }
+
+    /// The sections to fetch, MIME headers included. The parts of a message
+    /// that isn't multipart are described by the message header.
+    pub fn sections(&self) -> Vec<String> {
+        let mut sections = Vec::new();
+        self.body_sections(&mut sections);
+        sections
+    }
+
+    /// The sections of the body of a message, whose header describes it.
+    fn body_sections(&self, sections: &mut Vec<String>) {
+        match self {
+            Structure::Part { section, wanted: true, .. } => sections.push(section.clone()),
+            Structure::Part { .. } => (),
+            Structure::Multipart { .. } => self.nested_sections(sections),
+            Structure::Message { section, body } => {
+                sections.push(format!("{}.HEADER", section));
+                body.body_sections(sections);
+            },
+        }
+    }
+
+    fn nested_sections(&self, sections: &mut Vec<String>) {
+        match self {
+            Structure::Part { section, wanted: true, .. } => {
+                sections.push(format!("{}.MIME", section));
+                sections.push(section.clone());
+            },
+            Structure::Part { .. } => (),
+            Structure::Multipart { parts, .. } => {
+                for part in parts.iter() {
+                    part.nested_sections(sections);
+                }
+            },
+            Structure::Message { section, .. } => {
+                sections.push(format!("{}.MIME", section));
+                self.body_sections(sections);
+            },
+        }
+    }
+
+    /// Puts the message back together from its header and the fetched
+    /// sections. Parts that weren't fetched are left empty and marked as
+    /// attachments.
+    pub fn assemble(&self, header: &[u8], sections: &BTreeMap<String, Vec<u8>>) -> Vec<u8> {
+        let mut message = header.to_vec();
+        self.assemble_message(sections, &mut message);
+        message
+    }
+
+    /// The body of a message, after its header.
+    fn assemble_message(&self, sections: &BTreeMap<String, Vec<u8>>, out: &mut Vec<u8>) {
+        match self {
+            Structure::Part { section, .. } => {
+                if let Some(body) = sections.get(section) {
+                    out.extend_from_slice(body);
+                }
+            },
+            Structure::Multipart { .. } => self.assemble_body(sections, out),
+            Structure::Message { section, body } => {
+                if let Some(header) = sections.get(&format!("{}.HEADER", section)) {
+                    out.extend_from_slice(header);
+                    body.assemble_message(sections, out);
+                }
+            },
+        }
+    }
+
+    /// The boundary of a multipart, made up if the server didn't give one.
+    fn boundary(&self) -> String {
+        match self {
+            Structure::Multipart { boundary: Some(b), .. } => b.clone(),
+            Structure::Multipart { subtype, parts, .. } => format!("remailfs-{}-{}", subtype, parts.len()),
+            Structure::Part { section, .. } | Structure::Message { section, .. } => format!("remailfs-{}", section),
+        }
+    }
+
+    fn assemble_body(&self, sections: &BTreeMap<String, Vec<u8>>, out: &mut Vec<u8>) {
+        if let Structure::Multipart { parts, .. } = self {
+            let boundary = self.boundary();
+
+            for part in parts.iter() {
+                out.extend_from_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+                part.assemble_part(sections, out);
+            }
+            out.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        }
+    }
+
+    fn assemble_part(&self, sections: &BTreeMap<String, Vec<u8>>, out: &mut Vec<u8>) {
+        match self {
+            Structure::Multipart { subtype, .. } => {
+                out.extend_from_slice(format!("Content-Type: multipart/{}; boundary=\"{}\"\r\n\r\n", subtype, self.boundary()).as_bytes());
+                self.assemble_body(sections, out);
+            },
+            Structure::Part { mimetype, section, .. } => {
+                match (sections.get(&format!("{}.MIME", section)), sections.get(section)) {
+                    (Some(mime), Some(body)) => {
+                        out.extend_from_slice(mime);
+                        out.extend_from_slice(body);
+                    },
+                    _ => {
+                        out.extend_from_slice(format!("Content-Type: {}\r\nContent-Disposition: attachment\r\n\r\n", mimetype).as_bytes());
+                    },
+                }
+            },
+            Structure::Message { section, body } => {
+                match (sections.get(&format!("{}.MIME", section)), sections.get(&format!("{}.HEADER", section))) {
+                    (Some(mime), Some(header)) => {
+                        out.extend_from_slice(mime);
+                        out.extend_from_slice(header);
+                        body.assemble_message(sections, out);
+                    },
+                    _ => {
+                        out.extend_from_slice(b"Content-Type: message/rfc822\r\nContent-Disposition: attachment\r\n\r\n");
+                    },
+                }
+            },
+        }
+    }
+}
+
+/// The section number of the `i`th part under `section`.
+fn child(section: &str, i: usize) -> String {
+    if section.is_empty() {
+        (i + 1).to_string()
+    } else {
+        format!("{}.{}", section, i + 1)
+    }
+}
+
+/// Finds a parameter in a ("name" "value" ...) list.
+fn param(params: &[Token], name: &str) -> Option<String> {
+    params.chunks(2)
+        .find(|p| p[0].as_str().map(|n| n.eq_ignore_ascii_case(name)).unwrap_or(false))
+        .and_then(|p| p.get(1))
+        .and_then(|v| v.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::Structure;
+    use crate::backend::imap_raw::tokenize;
+
+    fn parse(bodystructure: &str) -> Structure {
+        let tokens = tokenize(bodystructure.as_bytes());
+        Structure::parse(tokens[0].as_list().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn dispositions() {
+        // a text attachment, and an inline image with an MD5 and a disposition
+        let structure = parse(concat!(
+            r#"(("TEXT" "PLAIN" ("CHARSET" "utf-8") NIL NIL "7BIT" 12 1 NIL NIL NIL)"#,
+            r#"("TEXT" "PLAIN" ("NAME" "notes.txt") NIL NIL "BASE64" 30 1 NIL ("ATTACHMENT" ("FILENAME" "notes.txt")) NIL)"#,
+            r#"("IMAGE" "PNG" NIL NIL NIL "BASE64" 400 NIL ("INLINE" NIL) NIL) "MIXED" ("BOUNDARY" "b1") NIL NIL)"#));
+
+        assert_eq!(structure.sections(), vec!["1.MIME", "1"]);
+    }
+
+    #[test]
+    fn single_part() {
+        let structure = parse(r#"("TEXT" "PLAIN" ("CHARSET" "utf-8") NIL NIL "7BIT" 12 1 NIL NIL NIL)"#);
+        assert_eq!(structure.sections(), vec!["1"]);
+
+        let mut sections = BTreeMap::new();
+        sections.insert("1".to_string(), b"Hello\r\n".to_vec());
+        assert_eq!(structure.assemble(b"Subject: Hi\r\n\r\n", &sections), b"Subject: Hi\r\n\r\nHello\r\n");
+    }
+
+    #[test]
+    fn attached_message() {
+        // a forwarded email with a text part and an attachment of its own
+        let structure = parse(concat!(
+            r#"(("TEXT" "PLAIN" NIL NIL NIL "7BIT" 4 1 NIL NIL NIL)"#,
+            r#"("MESSAGE" "RFC822" NIL NIL NIL "7BIT" 500 (NIL "Fwd" NIL NIL NIL NIL NIL NIL NIL NIL) "#,
+            r#"(("TEXT" "PLAIN" NIL NIL NIL "7BIT" 6 1 NIL NIL NIL)"#,
+            r#"("APPLICATION" "PDF" NIL NIL NIL "BASE64" 300 NIL ("ATTACHMENT" NIL) NIL) "MIXED" ("BOUNDARY" "inner") NIL NIL) 20 NIL NIL NIL)"#,
+            r#" "MIXED" ("BOUNDARY" "outer") NIL NIL)"#));
+
+        assert_eq!(structure.sections(), vec!["1.MIME", "1", "2.MIME", "2.HEADER", "2.1.MIME", "2.1"]);
+
+        let mut sections = BTreeMap::new();
+        sections.insert("1.MIME".to_string(), b"Content-Type: text/plain\r\n\r\n".to_vec());
+        sections.insert("1".to_string(), b"See:".to_vec());
+        sections.insert("2.MIME".to_string(), b"Content-Type: message/rfc822\r\n\r\n".to_vec());
+        sections.insert("2.HEADER".to_string(), b"Subject: Fwd\r\n\r\n".to_vec());
+        sections.insert("2.1.MIME".to_string(), b"Content-Type: text/plain\r\n\r\n".to_vec());
+        sections.insert("2.1".to_string(), b"Inside".to_vec());
+
+        let message = String::from_utf8(structure.assemble(b"Subject: Out\r\n\r\n", &sections)).unwrap();
+        assert_eq!(message, concat!(
+            "Subject: Out\r\n\r\n",
+            "\r\n--outer\r\nContent-Type: text/plain\r\n\r\nSee:",
+            "\r\n--outer\r\nContent-Type: message/rfc822\r\n\r\nSubject: Fwd\r\n\r\n",
+            "\r\n--inner\r\nContent-Type: text/plain\r\n\r\nInside",
+            "\r\n--inner\r\nContent-Type: application/pdf\r\nContent-Disposition: attachment\r\n\r\n",
+            "\r\n--inner--\r\n",
+            "\r\n--outer--\r\n"));
+    }
+
+    #[test]
+    fn attached_single_part() {
+        let structure = parse(concat!(
+            r#"("MESSAGE" "RFC822" NIL NIL NIL "7BIT" 50 (NIL "Fwd" NIL NIL NIL NIL NIL NIL NIL NIL) "#,
+            r#"("TEXT" "HTML" NIL NIL NIL "7BIT" 6 1 NIL NIL NIL) 3 NIL NIL NIL)"#));
+
+        assert_eq!(structure.sections(), vec!["1.HEADER", "1.1"]);
+    }
+}
@@ -0,0 +1,129 @@
+// Running in the background, and the signals that unmount or reload.
+
+use std::env;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::FromRawFd;
+use std::process::Command;
+use libc::{c_int, SIGHUP, SIGINT, SIGTERM};
+
+/// Forks into the background. The parent waits until the child writes to
+/// the returned pipe, which it does once the mount is ready, and exits with
+/// 1 if the pipe is closed before that. Paths have to be absolute by now,
+/// the child moves to /.
+pub fn daemonize() -> io::Result<File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (mut read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => (),
+        _ => {
+            // the child owns the connections, nothing is dropped here
+            drop(write);
+            let mut byte = [0; 1];
+            let ready = read.read(&mut byte).map(|n| n == 1).unwrap_or(false);
+            if !ready {
+                eprintln!("remailfs failed to mount, see the log for why");
+            }
+            unsafe { libc::_exit(if ready { 0 } else { 1 }) };
+        },
+    }
+    drop(read);
+
+    unsafe {
+        libc::setsid();
+        libc::chdir(b"/\0".as_ptr() as *const libc::c_char);
+
+        let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+        if null >= 0 {
+            for fd in 0..3 {
+                libc::dup2(null, fd);
+            }
+            if null > 2 {
+                libc::close(null);
+            }
+        }
+    }
+
+    Ok(write)
+}
+
+/// Blocks SIGINT, SIGTERM and SIGHUP in this thread and the threads started
+/// after, so they are only taken by `wait`.
+pub fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in &[SIGINT, SIGTERM, SIGHUP] {
+            libc::sigaddset(&mut set, *signal);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        set
+    }
+}
+
+/// Waits for one of the signals in `set`.
+pub fn wait(set: &libc::sigset_t) -> c_int {
+    let mut signal = 0;
+    unsafe { libc::sigwait(set, &mut signal) };
+    signal
+}
+
+/// Tells systemd how the service is doing (sd_notify(3)), "READY=1" once
+/// mounted, when it asked to be told by setting NOTIFY_SOCKET.
+pub fn notify(state: &str) {
+    let socket = match env::var("NOTIFY_SOCKET") {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+
+    unsafe {
+        let mut addr: libc::sockaddr_un = mem::zeroed();
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        if socket.is_empty() || socket.len() >= addr.sun_path.len() {
+            return;
+        }
+        // a leading @ is a socket in the abstract namespace
+        for (i, b) in socket.bytes().enumerate() {
+            addr.sun_path[i] = if i == 0 && b == b'@' { 0 } else { b as libc::c_char };
+        }
+        let len = mem::size_of::<libc::sa_family_t>() + socket.len();
+
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return;
+        }
+        let sent = libc::sendto(fd, state.as_ptr() as *const libc::c_void, state.len(), libc::MSG_NOSIGNAL,
+                                &addr as *const libc::sockaddr_un as *const libc::sockaddr, len as libc::socklen_t);
+        if sent < 0 {
+            warn!("unable to notify systemd: {}", io::Error::last_os_error());
+        }
+        libc::close(fd);
+    }
+}
+
+/// Detaches the mount, the session ends once nothing has a file in it
+/// open. Only root can unmount itself, everyone else goes through
+/// fusermount like FUSE does.
+pub fn unmount(mountpoint: &str) {
+    let path = match CString::new(mountpoint) {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+
+    if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } == 0 {
+        return;
+    }
+
+    match Command::new("fusermount").args(["-u", "-z", mountpoint]).status() {
+        Ok(s) if s.success() => (),
+        Ok(s) => error!("fusermount -u {} failed with {}", mountpoint, s),
+        Err(e) => error!("unable to run fusermount: {}", e),
+    }
+}
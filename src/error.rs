@@ -1,14 +1,115 @@
 use imap;
+use libc::{c_int, EACCES, EIO, ENOENT, ENOSPC, ENOTSUP, ETIMEDOUT};
+use native_tls;
+use std::fmt;
+use std::io;
 use std::result;
 
 pub type Result<T> = result::Result<T, Error>;
 
+#[derive(Debug)]
 pub enum Error {
-    IMAPError(imap::error::Error),
+    // the server couldn't be reached or the connection broke
+    Connection(String),
+    // the server stopped answering
+    Timeout,
+    // the server refused the username or password
+    Auth(String),
+    // the server refused a command or answered something unexpected
+    Protocol(String),
+    // a response or message that couldn't be read
+    Parse(String),
+    NotFound(String),
+    Permission(String),
+    // the account is over its quota
+    Quota(String),
+    Unsupported,
 }
 
+impl Error {
+    /// The errno a filesystem callback replies with.
+    pub fn errno(&self) -> c_int {
+        match self {
+            Error::Connection(_) | Error::Protocol(_) | Error::Parse(_) => EIO,
+            Error::Timeout => ETIMEDOUT,
+            Error::Auth(_) | Error::Permission(_) => EACCES,
+            Error::NotFound(_) => ENOENT,
+            Error::Quota(_) => ENOSPC,
+            Error::Unsupported => ENOTSUP,
+        }
+    }
+
+    /// Sorts out a refusal from the server by its response code (RFC 5530
+    /// for IMAP, RFC 3206 for POP3), "[OVERQUOTA] Quota exceeded" etc.
+    pub fn refused(text: &str) -> Error {
+        let code = text.find('[')
+            .and_then(|i| text[i+1..].split([']', ' ']).next())
+            .map(|c| c.to_uppercase())
+            .unwrap_or_default();
+
+        match code.as_str() {
+            "AUTHENTICATIONFAILED" | "AUTHORIZATIONFAILED" | "EXPIRED" | "AUTH" => Error::Auth(text.to_string()),
+            "NOPERM" | "PRIVACYREQUIRED" | "SYS/PERM" => Error::Permission(text.to_string()),
+            "OVERQUOTA" | "LIMIT" => Error::Quota(text.to_string()),
+            "NONEXISTENT" | "TRYCREATE" => Error::NotFound(text.to_string()),
+            _ => Error::Protocol(text.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Connection(e) => write!(f, "connection failed: {}", e),
+            Error::Timeout => write!(f, "the server timed out"),
+            Error::Auth(e) => write!(f, "login refused: {}", e),
+            Error::Protocol(e) => write!(f, "{}", e),
+            Error::Parse(e) => write!(f, "parse error: {}", e),
+            Error::NotFound(e) => write!(f, "not found: {}", e),
+            Error::Permission(e) => write!(f, "permission denied: {}", e),
+            Error::Quota(e) => write!(f, "over quota: {}", e),
+            Error::Unsupported => write!(f, "not supported by the server"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<imap::error::Error> for Error {
     fn from(error: imap::error::Error) -> Self {
-        Error::IMAPError(error)
+        match error {
+            imap::error::Error::Io(e) => Error::from(e),
+            imap::error::Error::TlsHandshake(e) => Error::Connection(e.to_string()),
+            imap::error::Error::Tls(e) => Error::Connection(e.to_string()),
+            imap::error::Error::ConnectionLost => Error::Connection("connection lost".to_string()),
+            imap::error::Error::No(e) => Error::refused(&e),
+            imap::error::Error::Bad(e) => Error::Protocol(e),
+            imap::error::Error::Parse(e) => Error::Parse(format!("{:?}", e)),
+            e => Error::Protocol(e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::Connection(error.to_string()),
+        }
+    }
+}
+
+impl From<native_tls::Error> for Error {
+    fn from(error: native_tls::Error) -> Self {
+        Error::Connection(error.to_string())
+    }
+}
+
+impl<S> From<native_tls::HandshakeError<S>> for Error {
+    fn from(error: native_tls::HandshakeError<S>) -> Self {
+        match error {
+            native_tls::HandshakeError::Failure(e) => Error::from(e),
+            native_tls::HandshakeError::WouldBlock(_) => Error::Timeout,
+        }
     }
 }
@@ -0,0 +1,360 @@
+// Converts HTML email bodies to plain text. This only needs to cope with
+// what mail clients produce, not the whole of HTML.
+
+/// Elements that start on a new line.
+const BLOCKS: [&str; 22] = [
+    "p", "div", "br", "tr", "table", "ul", "ol", "li", "h1", "h2", "h3", "h4",
+    "h5", "h6", "blockquote", "pre", "hr", "dl", "dt", "dd", "section", "article",
+];
+
+/// Elements whose contents are never shown.
+const HIDDEN: [&str; 5] = ["head", "script", "style", "title", "template"];
+
+/// Converts HTML to text wrapped at `width` columns, or not at all for 0.
+/// Links are numbered in the text and listed at the end, table rows become
+/// lines with their cells separated by " | ".
+pub fn to_text(html: &str, width: usize) -> String {
+    let mut text = String::new();
+    let mut links: Vec<String> = Vec::new();
+    // href of the link being read, if any
+    let mut link: Option<String> = None;
+    let mut hidden = 0;
+    let mut pre = 0;
+    let mut first_cell = true;
+
+    let mut rest = html;
+    while !rest.is_empty() {
+        let lt = match rest.find('<') {
+            Some(i) => i,
+            None => rest.len(),
+        };
+
+        if hidden == 0 {
+            push_text(&mut text, &decode_entities(&rest[..lt]), pre > 0);
+        }
+        rest = &rest[lt..];
+
+        if rest.is_empty() {
+            break;
+        }
+
+        // comments can contain '>'
+        if rest.starts_with("<!--") {
+            rest = match rest.find("-->") {
+                Some(i) => &rest[i+3..],
+                None => "",
+            };
+            continue;
+        }
+
+        let gt = match rest.find('>') {
+            Some(i) => i,
+            None => break,
+        };
+        let tag = &rest[1..gt];
+        rest = &rest[gt+1..];
+
+        let closing = tag.starts_with('/');
+        let name: String = tag.trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+
+        if HIDDEN.contains(&name.as_str()) {
+            if closing {
+                hidden = if hidden > 0 { hidden - 1 } else { 0 };
+            } else if !tag.ends_with('/') {
+                hidden += 1;
+            }
+            continue;
+        }
+
+        if hidden > 0 {
+            continue;
+        }
+
+        match (name.as_str(), closing) {
+            ("a", false) => link = attribute(tag, "href"),
+            ("a", true) => {
+                if let Some(href) = link.take() {
+                    if !href.starts_with('#') && !href.starts_with("mailto:") {
+                        links.push(href);
+                        text.push_str(&format!("[{}]", links.len()));
+                    }
+                }
+            },
+            ("img", false) => {
+                if let Some(alt) = attribute(tag, "alt") {
+                    push_text(&mut text, &alt, false);
+                }
+            },
+            ("pre", false) => pre += 1,
+            ("pre", true) => pre = if pre > 0 { pre - 1 } else { 0 },
+            ("tr", false) => first_cell = true,
+            ("td", false) | ("th", false) => {
+                if !first_cell {
+                    text.push_str(" | ");
+                }
+                first_cell = false;
+            },
+            ("li", false) => {
+                new_line(&mut text);
+                text.push_str("* ");
+            },
+            ("hr", false) => {
+                new_line(&mut text);
+                text.push_str("----");
+            },
+            _ => (),
+        }
+
+        if BLOCKS.contains(&name.as_str()) && name != "li" {
+            if name == "p" || name.starts_with('h') || name == "blockquote" || name == "table" {
+                blank_line(&mut text);
+            } else {
+                new_line(&mut text);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for line in text.trim().lines() {
+        out.push_str(&wrap(line.trim_end(), width));
+        out.push('\n');
+    }
+
+    if !links.is_empty() {
+        out.push('\n');
+        for (i, href) in links.iter().enumerate() {
+            out.push_str(&format!("[{}] {}\n", i + 1, href));
+        }
+    }
+
+    out
+}
+
+/// Adds text, collapsing whitespace unless it is preformatted.
+fn push_text(text: &mut String, s: &str, preformatted: bool) {
+    if preformatted {
+        text.push_str(s);
+        return;
+    }
+
+    for c in s.chars() {
+        if c.is_whitespace() && c != '\u{a0}' {
+            if !text.is_empty() && !text.ends_with(' ') && !text.ends_with('\n') {
+                text.push(' ');
+            }
+        } else if c == '\u{a0}' {
+            text.push(' ');
+        } else {
+            text.push(c);
+        }
+    }
+}
+
+fn new_line(text: &mut String) {
+    while text.ends_with(' ') {
+        text.pop();
+    }
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+fn blank_line(text: &mut String) {
+    new_line(text);
+    if !text.is_empty() && !text.ends_with("\n\n") {
+        text.push('\n');
+    }
+}
+
+/// Finds an attribute in the inside of a tag, `<a href="...">`.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+
+    while let Some(i) = lower[from..].find(name) {
+        let start = from + i;
+        from = start + name.len();
+
+        // make sure this is the whole attribute name
+        let before = lower[..start].chars().last();
+        if before.map(|c| !c.is_whitespace()).unwrap_or(true) {
+            continue;
+        }
+
+        let after = lower[from..].trim_start();
+        if !after.starts_with('=') {
+            continue;
+        }
+        let value = tag[tag.len() - after.len() + 1..].trim_start();
+
+        let value = match value.chars().next() {
+            Some(q) if q == '"' || q == '\'' => value[1..].split(q).next().unwrap_or(""),
+            _ => value.split(|c: char| c.is_whitespace() || c == '>').next().unwrap_or(""),
+        };
+
+        return Some(decode_entities(value));
+    }
+
+    None
+}
+
+/// Decodes character references and the common named entities.
+fn decode_entities(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let end = match rest.find(';') {
+            Some(e) if e < 12 => e,
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let entity = &rest[1..end];
+        let decoded = if entity.starts_with("#x") || entity.starts_with("#X") {
+            u32::from_str_radix(&entity[2..], 16).ok().and_then(std::char::from_u32)
+        } else if let Some(number) = entity.strip_prefix('#') {
+            number.parse::<u32>().ok().and_then(std::char::from_u32)
+        } else {
+            match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                "copy" => Some('©'),
+                "reg" => Some('®'),
+                "hellip" => Some('…'),
+                "mdash" => Some('—'),
+                "ndash" => Some('–'),
+                "lsquo" => Some('‘'),
+                "rsquo" => Some('’'),
+                "ldquo" => Some('“'),
+                "rdquo" => Some('”'),
+                "bull" => Some('•'),
+                "euro" => Some('€'),
+                _ => None,
+            }
+        };
+
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end+1..];
+            },
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Wraps a single line at `width` columns, breaking at spaces. Words longer
+/// than the width are left whole.
+pub fn wrap(line: &str, width: usize) -> String {
+    if width == 0 || line.chars().count() <= width {
+        return line.to_string();
+    }
+
+    let mut out = String::new();
+    let mut len = 0;
+
+    for word in line.split(' ') {
+        let word_len = word.chars().count();
+
+        if len > 0 && len + 1 + word_len > width {
+            out.push('\n');
+            len = 0;
+        } else if len > 0 {
+            out.push(' ');
+            len += 1;
+        }
+
+        out.push_str(word);
+        len += word_len;
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_text, wrap};
+
+    #[test]
+    fn links() {
+        let html = r#"<p>See <a href="https://example.com/a?x=1&amp;y=2">the page</a>,
+            <a href='#top'>top</a>, <a href="mailto:bob@example.com">Bob</a> and
+            <A HREF=https://example.com/b>this</A>.</p>"#;
+
+        assert_eq!(to_text(html, 0), "See the page[1], top, Bob and this[2].\n\n\
+                                      [1] https://example.com/a?x=1&y=2\n\
+                                      [2] https://example.com/b\n");
+    }
+
+    #[test]
+    fn tables() {
+        let html = "<table><tr><th>Item</th><th>Price</th></tr>\
+                    <tr><td>Tea</td><td>2.50</td></tr></table><p>Thanks</p>";
+
+        assert_eq!(to_text(html, 0), "Item | Price\nTea | 2.50\n\nThanks\n");
+    }
+
+    #[test]
+    fn entities() {
+        let html = "Fish &amp; chips &lt;b&gt; &quot;caf&#233;&quot; &#x1F600; \
+                    1&nbsp;000&euro; &unknown; a & b &hellip;";
+
+        assert_eq!(to_text(html, 0), "Fish & chips <b> \"caf\u{e9}\" \u{1f600} 1 000\u{20ac} &unknown; a & b \u{2026}\n");
+    }
+
+    #[test]
+    fn hidden() {
+        let html = "<html><head><title>Newsletter</title><style>p { color: red; }</style></head>\
+                    <body><script>if (a > b) {}</script><!-- <p>old</p> -->\
+                    <p>Hello<img src=\"x.png\" alt=\" there\"></p><template><p>no</p></template></body></html>";
+
+        assert_eq!(to_text(html, 0), "Hello there\n");
+    }
+
+    #[test]
+    fn pre() {
+        let html = "<p>Output:</p><pre>  a    b\n  c    d</pre><p>and   some\n   more</p>";
+
+        assert_eq!(to_text(html, 0), "Output:\n\n  a    b\n  c    d\n\nand some more\n");
+    }
+
+    #[test]
+    fn lists() {
+        let html = "<ul><li>one</li><li>two</li></ul><hr><h1>Title</h1>";
+
+        assert_eq!(to_text(html, 0), "* one\n* two\n----\n\nTitle\n");
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(wrap("the quick brown fox jumps", 10), "the quick\nbrown fox\njumps");
+        assert_eq!(wrap("a https://example.com/a/long/link b", 10), "a\nhttps://example.com/a/long/link\nb");
+        assert_eq!(wrap("short", 10), "short");
+        assert_eq!(wrap("not wrapped at all", 0), "not wrapped at all");
+        assert_eq!(wrap("caf\u{e9} caf\u{e9}", 9), "caf\u{e9} caf\u{e9}");
+
+        let html = "<p>one two three four five six</p><p>seven</p>";
+        assert_eq!(to_text(html, 9), "one two\nthree\nfour five\nsix\n\nseven\n");
+    }
+}
@@ -0,0 +1,422 @@
+// Just enough of an HTTP/1.1 client to talk to a JMAP server. Every request
+// uses its own connection.
+
+use std::io::{BufRead, BufReader, Write};
+
+use crate::error::{Error, Result};
+use crate::logger;
+use crate::net::Stream;
+
+const MAX_REDIRECTS: usize = 5;
+
+pub struct Url {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(Error::Protocol(format!("unsupported URL {}", url)));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => {
+                let port = authority[i+1..].parse::<u16>()
+                    .map_err(|_| Error::Parse(format!("bad port in {}", url)))?;
+                (&authority[..i], port)
+            },
+            None => (authority, if tls { 443 } else { 80 }),
+        };
+
+        Ok(Url {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    fn scheme(&self) -> &'static str {
+        if self.tls { "https" } else { "http" }
+    }
+
+    /// The host, with the port when it isn't the scheme's own, as the Host
+    /// header wants it.
+    pub fn authority(&self) -> String {
+        if self.port == if self.tls { 443 } else { 80 } {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// Whether both URLs have the same scheme, host and port.
+    pub fn same_origin(&self, other: &Url) -> bool {
+        self.tls == other.tls && self.host.eq_ignore_ascii_case(&other.host) && self.port == other.port
+    }
+
+    /// Resolves a Location header or JMAP URL relative to this one.
+    pub fn join(&self, location: &str) -> String {
+        if location.contains("://") {
+            return location.to_string();
+        }
+        if location.starts_with("//") {
+            return format!("{}:{}", self.scheme(), location);
+        }
+
+        let base = self.path.split(['?', '#']).next().unwrap_or("/");
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else if location.is_empty() || location.starts_with('?') {
+            format!("{}{}", base, location)
+        } else {
+            // relative to the directory of the current path
+            format!("{}{}", &base[..base.rfind('/').map(|i| i + 1).unwrap_or(0)], location)
+        };
+
+        format!("{}://{}{}", self.scheme(), self.authority(), remove_dots(&path))
+    }
+}
+
+/// Removes the "." and ".." segments of a path (RFC 3986 5.2.4).
+fn remove_dots(path: &str) -> String {
+    let (path, query) = match path.find('?') {
+        Some(i) => (&path[..i], &path[i..]),
+        None => (path, ""),
+    };
+
+    let mut segments: Vec<&str> = Vec::new();
+    let parts: Vec<&str> = path.split('/').skip(1).collect();
+    for (i, part) in parts.iter().enumerate() {
+        let last = i + 1 == parts.len();
+        match *part {
+            "." => if last { segments.push("") },
+            ".." => {
+                segments.pop();
+                if last {
+                    segments.push("");
+                }
+            },
+            p => segments.push(p),
+        }
+    }
+
+    format!("/{}{}", segments.join("/"), query)
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+/// Sends a request, following redirects. The credentials in an
+/// Authorization header are only sent to the origin they were meant for.
+pub fn request(method: &str, url: &str, headers: &[(&str, &str)], body: Option<&[u8]>) -> Result<Response> {
+    let origin = Url::parse(url)?;
+    let mut url = url.to_string();
+    let mut method = method;
+    let mut body = body;
+
+    for _ in 0..MAX_REDIRECTS {
+        let parsed = Url::parse(&url)?;
+        let sent: Vec<(&str, &str)> = headers.iter()
+            .filter(|(k, _)| parsed.same_origin(&origin) || !k.eq_ignore_ascii_case("Authorization"))
+            .cloned()
+            .collect();
+        let response = send(method, &parsed, &sent, body)?;
+
+        match (response.status, response.header("Location")) {
+            (301, Some(l)) | (302, Some(l)) | (307, Some(l)) | (308, Some(l)) => {
+                url = parsed.join(l);
+            },
+            // see other is always fetched with GET
+            (303, Some(l)) => {
+                url = parsed.join(l);
+                method = "GET";
+                body = None;
+            },
+            _ => return Ok(response),
+        }
+    }
+
+    Err(Error::Protocol(format!("too many redirects for {}", url)))
+}
+
+fn send(method: &str, url: &Url, headers: &[(&str, &str)], body: Option<&[u8]>) -> Result<Response> {
+    let mut stream = Stream::connect(&url.host, url.port, url.tls)?;
+
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, url.path, url.authority());
+    for (k, v) in headers {
+        head.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    if let Some(b) = body {
+        head.push_str(&format!("Content-Length: {}\r\n", b.len()));
+    }
+    head.push_str("\r\n");
+
+    trace!("{} {}://{}{} {}", method, url.scheme(), url.authority(), url.path,
+           body.map(logger::body).unwrap_or_default());
+    stream.write_all(head.as_bytes())?;
+    if let Some(b) = body {
+        stream.write_all(b)?;
+    }
+    stream.flush()?;
+
+    let response = read_response(&mut BufReader::new(stream))?;
+    trace!("{} {}", response.status, logger::body(&response.body));
+    Ok(response)
+}
+
+/// Reads the status line, headers and body of a response.
+fn read_response<R: BufRead>(reader: &mut R) -> Result<Response> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line.split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| Error::Parse(format!("bad HTTP status line {}", line.trim())))?;
+
+    let mut response = Response {
+        status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let l = line.trim_end();
+        if l.is_empty() {
+            break;
+        }
+        if let Some(i) = l.find(':') {
+            response.headers.push((l[..i].trim().to_string(), l[i+1..].trim().to_string()));
+        }
+    }
+
+    let chunked = response.header("Transfer-Encoding")
+        .map(|t| t.eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false);
+    let length = response.header("Content-Length")
+        .and_then(|l| l.parse::<usize>().ok());
+
+    if chunked {
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = usize::from_str_radix(line.trim().split(';').next().unwrap_or(""), 16)
+                .map_err(|_| Error::Parse("bad chunk size".to_string()))?;
+            if size == 0 {
+                break;
+            }
+
+            let mut chunk = vec![0; size];
+            reader.read_exact(&mut chunk)?;
+            response.body.extend_from_slice(&chunk);
+
+            line.clear();
+            reader.read_line(&mut line)?;
+        }
+    } else if let Some(len) = length {
+        response.body = vec![0; len];
+        reader.read_exact(&mut response.body)?;
+    } else {
+        reader.read_to_end(&mut response.body)?;
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::{read_response, request, Url};
+
+    /// Answers one connection per response in turn and returns the requests
+    /// it was sent.
+    fn serve(responses: Vec<String>) -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+
+                let mut text = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(l) = line.strip_prefix("Content-Length: ") {
+                        length = l.trim().parse().unwrap();
+                    }
+                    text.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                text.push_str(&String::from_utf8(body).unwrap());
+                requests.push(text);
+
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        (port, handle)
+    }
+
+    #[test]
+    fn parse_url() {
+        let url = Url::parse("https://jmap.example.com/.well-known/jmap").unwrap();
+        assert!(url.tls);
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("jmap.example.com", 443, "/.well-known/jmap"));
+        assert_eq!(url.authority(), "jmap.example.com");
+
+        let url = Url::parse("http://localhost:8080").unwrap();
+        assert!(!url.tls);
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("localhost", 8080, "/"));
+        assert_eq!(url.authority(), "localhost:8080");
+
+        assert!(Url::parse("ftp://example.com/").is_err());
+        assert!(Url::parse("http://example.com:http/").is_err());
+    }
+
+    #[test]
+    fn join() {
+        let url = Url::parse("http://example.com:8080/jmap/session?x=1").unwrap();
+        assert_eq!(url.join("https://other.example/api"), "https://other.example/api");
+        assert_eq!(url.join("//other.example/api"), "http://other.example/api");
+        assert_eq!(url.join("/api/"), "http://example.com:8080/api/");
+        assert_eq!(url.join("api"), "http://example.com:8080/jmap/api");
+        assert_eq!(url.join("./api?y=2"), "http://example.com:8080/jmap/api?y=2");
+        assert_eq!(url.join("../up/../api"), "http://example.com:8080/api");
+        assert_eq!(url.join("?y=2"), "http://example.com:8080/jmap/session?y=2");
+
+        let url = Url::parse("https://example.com/").unwrap();
+        assert_eq!(url.join("download/{blobId}"), "https://example.com/download/{blobId}");
+    }
+
+    #[test]
+    fn chunked() {
+        let text = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nTrailer: x\r\n\r\n";
+        let response = read_response(&mut text.as_bytes()).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello, world");
+
+        let text = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        assert!(read_response(&mut text.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn content_length() {
+        let text = "HTTP/1.1 404 Not Found\r\ncontent-length: 3\r\n\r\nabcdef";
+        let response = read_response(&mut text.as_bytes()).unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.header("Content-Length"), Some("3"));
+        assert_eq!(response.body, b"abc");
+        assert!(!response.is_success());
+
+        assert!(read_response(&mut "garbage\r\n\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn relative_redirect() {
+        let (port, server) = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: other\r\nContent-Length: 0\r\n\r\n".to_string(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_string(),
+        ]);
+
+        let url = format!("http://127.0.0.1:{}/dir/start", port);
+        let response = request("GET", &url, &[("Authorization", "Basic c2VjcmV0")], None).unwrap();
+        assert_eq!(response.body, b"ok");
+
+        let requests = server.join().unwrap();
+        assert!(requests[1].starts_with("GET /dir/other HTTP/1.1\r\n"));
+        assert!(requests[1].contains(&format!("Host: 127.0.0.1:{}\r\n", port)));
+        // same origin, the credentials go along
+        assert!(requests[1].contains("Authorization: Basic c2VjcmV0\r\n"));
+    }
+
+    #[test]
+    fn cross_origin_redirect() {
+        let (other, other_server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string(),
+        ]);
+        let (port, server) = serve(vec![
+            format!("HTTP/1.1 307 Temporary Redirect\r\nLocation: http://127.0.0.1:{}/api\r\n\r\n", other),
+        ]);
+
+        let url = format!("http://127.0.0.1:{}/api", port);
+        request("POST", &url, &[("Authorization", "Basic c2VjcmV0"), ("Accept", "application/json")], Some(b"{}")).unwrap();
+
+        assert!(server.join().unwrap()[0].contains("Authorization: "));
+        let requests = other_server.join().unwrap();
+        assert!(requests[0].starts_with("POST /api HTTP/1.1\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\n{}"));
+        assert!(requests[0].contains("Accept: application/json\r\n"));
+        assert!(!requests[0].contains("Authorization"));
+    }
+
+    #[test]
+    fn see_other() {
+        let (port, server) = serve(vec![
+            "HTTP/1.1 303 See Other\r\nLocation: /result\r\n\r\n".to_string(),
+            "HTTP/1.1 200 OK\r\n\r\ndone".to_string(),
+        ]);
+
+        let url = format!("http://127.0.0.1:{}/upload", port);
+        let response = request("POST", &url, &[], Some(b"data")).unwrap();
+        assert_eq!(response.body, b"done");
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /upload"));
+        assert!(requests[1].starts_with("GET /result HTTP/1.1\r\n"));
+        assert!(!requests[1].contains("Content-Length"));
+        assert!(requests[1].ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn too_many_redirects() {
+        let redirect = "HTTP/1.1 301 Moved Permanently\r\nLocation: /again\r\n\r\n".to_string();
+        let (port, server) = serve(vec![redirect; 5]);
+
+        assert!(request("GET", &format!("http://127.0.0.1:{}/", port), &[], None).is_err());
+        assert_eq!(server.join().unwrap().len(), 5);
+    }
+}
@@ -0,0 +1,343 @@
+// A small JSON value type with a parser and serializer, enough to speak JMAP.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::error::{Error, Result};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json> {
+        let mut parser = Parser { chars: text.chars().collect(), pos: 0 };
+        let value = parser.value()?;
+        parser.whitespace();
+
+        if parser.pos != parser.chars.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    /// Builds an object from a list of key/value pairs.
+    pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+        Json::Object(pairs.into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect())
+    }
+
+    pub fn str(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(o) => o.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(n) if *n >= 0.0 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&BTreeMap<String, Json>> {
+        match self {
+            Json::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => {
+                if n.fract() == 0.0 && n.abs() < 1e15 {
+                    write!(f, "{}", *n as i64)
+                } else {
+                    write!(f, "{}", n)
+                }
+            },
+            Json::String(s) => write_string(f, s),
+            Json::Array(a) => {
+                write!(f, "[")?;
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(o) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in o.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write_string(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> Error {
+        Error::Parse(format!("invalid JSON at {}: {}", self.pos, msg))
+    }
+
+    fn whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn next(&mut self) -> Result<char> {
+        match self.chars.get(self.pos) {
+            Some(c) => {
+                self.pos += 1;
+                Ok(*c)
+            },
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn expect(&mut self, word: &str) -> Result<()> {
+        for c in word.chars() {
+            if self.next()? != c {
+                return Err(self.error("unexpected character"));
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.whitespace();
+
+        match self.chars.get(self.pos) {
+            Some('n') => self.expect("null").map(|_| Json::Null),
+            Some('t') => self.expect("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(_) => self.number(),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let mut n = 0;
+        for _ in 0..4 {
+            let d = self.next()?
+                .to_digit(16)
+                .ok_or_else(|| self.error("bad unicode escape"))?;
+            n = n * 16 + d;
+        }
+        Ok(n)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect("\"")?;
+
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(s),
+                '\\' => {
+                    let c = match self.next()? {
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'u' => {
+                            let mut code = self.hex4()?;
+                            // surrogate pair
+                            if (0xd800..0xdc00).contains(&code) {
+                                self.expect("\\u")?;
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            std::char::from_u32(code).unwrap_or('\u{fffd}')
+                        },
+                        c => c,
+                    };
+                    s.push(c);
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        while self.pos < self.chars.len() && "+-0123456789.eE".contains(self.chars[self.pos]) {
+            self.pos += 1;
+        }
+
+        let text: String = self.chars[start..self.pos].iter().collect();
+        match text.parse::<f64>() {
+            Ok(n) => Ok(Json::Number(n)),
+            Err(_) => Err(self.error("bad number")),
+        }
+    }
+
+    fn array(&mut self) -> Result<Json> {
+        self.expect("[")?;
+
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.chars.get(self.pos) == Some(&']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.next()? {
+                ',' => (),
+                ']' => return Ok(Json::Array(items)),
+                _ => return Err(self.error("expected , or ]")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json> {
+        self.expect("{")?;
+
+        let mut items = BTreeMap::new();
+        self.whitespace();
+        if self.chars.get(self.pos) == Some(&'}') {
+            self.pos += 1;
+            return Ok(Json::Object(items));
+        }
+
+        loop {
+            self.whitespace();
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(":")?;
+            let value = self.value()?;
+            items.insert(key, value);
+
+            self.whitespace();
+            match self.next()? {
+                ',' => (),
+                '}' => return Ok(Json::Object(items)),
+                _ => return Err(self.error("expected , or }")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+    #[test]
+    fn escapes() {
+        let json = Json::parse(r#""a\"b\\c\/d\n\r\t\b\fé""#).unwrap();
+        assert_eq!(json, Json::str("a\"b\\c/d\n\r\t\u{8}\u{c}é"));
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        assert_eq!(Json::parse(r#""\ud83d\ude00""#).unwrap(), Json::str("\u{1f600}"));
+        assert_eq!(Json::parse(r#""x\uD834\uDD1Ey""#).unwrap(), Json::str("x\u{1d11e}y"));
+        assert_eq!(Json::parse(r#""\u00e9\u20ac""#).unwrap(), Json::str("é€"));
+        assert!(Json::parse(r#""\ud83d""#).is_err());
+    }
+
+    #[test]
+    fn nesting() {
+        let json = Json::parse(r#" { "a" : [1, {"b": [true, false, null]}, []], "c": {} } "#).unwrap();
+        let a = json.get("a").and_then(|a| a.as_array()).unwrap();
+        assert_eq!(a.len(), 3);
+        assert_eq!(a[1].get("b"), Some(&Json::Array(vec![Json::Bool(true), Json::Bool(false), Json::Null])));
+        assert_eq!(a[2], Json::Array(vec![]));
+        assert_eq!(json.get("c").and_then(|c| c.as_object()).map(|c| c.len()), Some(0));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(Json::parse("0").unwrap(), Json::Number(0.0));
+        assert_eq!(Json::parse("-12").unwrap(), Json::Number(-12.0));
+        assert_eq!(Json::parse("3.25").unwrap(), Json::Number(3.25));
+        assert_eq!(Json::parse("1e3").unwrap(), Json::Number(1000.0));
+        assert_eq!(Json::parse("2.5E-1").unwrap(), Json::Number(0.25));
+        assert_eq!(Json::parse("4294967296").unwrap().as_u64(), Some(4294967296));
+        assert_eq!(Json::parse("-1").unwrap().as_u64(), None);
+        assert!(Json::parse("1.2.3").is_err());
+        assert!(Json::parse("-").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        for text in &["", "[1,", "[1 2]", "{\"a\" 1}", "{1: 2}", "nul", "\"abc", "[] x"] {
+            assert!(Json::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let json = Json::object(vec![
+            ("s", Json::str("quote \" backslash \\ newline \n control \u{1}")),
+            ("n", Json::Array(vec![Json::Number(42.0), Json::Number(-0.5)])),
+            ("o", Json::object(vec![("x", Json::Null), ("y", Json::Bool(true))])),
+        ]);
+
+        let text = json.to_string();
+        assert_eq!(text, r#"{"n":[42,-0.5],"o":{"x":null,"y":true},"s":"quote \" backslash \\ newline \n control \u0001"}"#);
+        assert_eq!(Json::parse(&text).unwrap(), json);
+    }
+}
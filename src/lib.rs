@@ -5,91 +5,272 @@ extern crate imap;
 extern crate mailparse;
 extern crate imap_proto;
 extern crate native_tls;
+#[macro_use]
+extern crate log;
 
-use std::borrow::BorrowMut;
 use std::cmp::Ord;
 use std::ffi::OsStr;
-use std::cmp::Ordering;
 use std::vec::Vec;
 use std::collections::{BTreeMap,BTreeSet};
 use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
 use libc::c_int;
 use imap::Session;
 use fuse::Filesystem;
 use fuse::*;
-use native_tls::{TlsConnector, TlsStream};
-use imap_proto::types::Address;
-use imap::types::{Uid, Name, Fetch};
-use libc::{ENOENT, ENOSYS};
+use native_tls::TlsStream;
+use imap::types::Uid;
+use libc::{ENOENT, ENOSYS, EIO, EXDEV, EEXIST, EACCES, EINVAL, ENODATA, ENOTSUP, ERANGE, EROFS};
 use time::Timespec;
 use time::strptime;
-
-mod error;
+use backend::{Backend, Quota, Status, Summary};
+use smtp::SmtpConfig;
+use encoding::EncodingRef;
+use render::Template;
+use search::Criterion;
+
+pub mod backend;
+pub mod error;
+mod html;
+mod http;
+mod json;
+pub mod logger;
+mod md5;
+mod net;
+pub mod render;
+pub mod search;
+pub mod smtp;
+mod thread;
+mod undo;
+mod utf7;
 
 pub type IMAPFlag<'a> = imap::types::Flag<'a>;
+pub use net::set_timeout;
+
 pub type IMAPMailbox = imap::types::Mailbox;
 pub type IMAPSession = Session<TlsStream<TcpStream>>;
 pub type IMAPFetch = imap::types::Fetch;
 
-enum EmailObject<'a> {
-    E(&'a Email),
-    M(&'a Mailbox),
-}
-
 pub struct Email {
     abs_path: String,
-    contents: Option<String>,
+    mailbox: u64,
+    uid: String,
+    // the raw message as fetched from the server
+    contents: Option<Vec<u8>>,
+    // IMAP style flags, None if the server doesn't keep any
+    flags: Option<BTreeSet<String>>,
+    // the same message in other mailboxes, as (mailbox, uid)
+    copies: Vec<(u64, String)>,
     subject: Option<String>,
-    from: Option<String>,
     date: Option<String>,
 }
 
 impl Email {
-    fn new(abs_path: &str) -> Email {
+    fn new(abs_path: &str, mailbox: u64, uid: &str) -> Email {
         Email {
             abs_path: abs_path.to_string(),
+            mailbox,
+            uid: uid.to_string(),
             contents: None,
+            flags: None,
+            copies: Vec::new(),
             subject: None,
-            from: None,
             date: None,
         }
     }
 
-    fn set_contents(&mut self, contents: String) {
-        self.contents = Some(contents);
-    }
-
     fn set_subject(&mut self, subject: String) {
         self.subject = Some(subject);
     }
 
-    fn set_from(&mut self, from: String) {
-        self.from = Some(from);
-    }
-
     fn set_date(&mut self, date: String) {
         self.date = Some(date)
     }
+}
+
+/// A virtual directory listing the emails of its parent mailbox that have
+/// a flag. The emails are the same inodes as in the mailbox.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum View {
+    Unread,
+    Flagged,
+    Recent,
+}
+
+const VIEWS: [(&str, View); 3] = [
+    (".unread", View::Unread),
+    (".flagged", View::Flagged),
+    (".recent", View::Recent),
+];
+
+impl View {
+    fn matches(&self, flags: &BTreeSet<String>) -> bool {
+        match self {
+            View::Unread => !flags.contains("\\Seen"),
+            View::Flagged => flags.contains("\\Flagged"),
+            View::Recent => flags.contains("\\Recent"),
+        }
+    }
+
+    /// The search for the emails in the view, for mailboxes whose emails
+    /// aren't all known.
+    fn criterion(&self) -> Criterion {
+        match self {
+            View::Unread => Criterion::Flag("\\Seen".to_string(), false),
+            View::Flagged => Criterion::Flag("\\Flagged".to_string(), true),
+            View::Recent => Criterion::Flag("\\Recent".to_string(), true),
+        }
+    }
+}
+
+/// How the emails of a mailbox are laid out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    // every email directly in the mailbox directory
+    Flat,
+    // YYYY/MM/ directories by when the server received each email
+    Received,
+    // the same by the Date header
+    Sent,
+}
+
+impl std::str::FromStr for Layout {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> error::Result<Layout> {
+        match s.to_lowercase().as_str() {
+            "flat" => Ok(Layout::Flat),
+            "date" => Ok(Layout::Received),
+            "sent-date" => Ok(Layout::Sent),
+            _ => Err(error::Error::Parse(format!("unknown layout {}", s))),
+        }
+    }
+}
+
+/// A YYYY/ or YYYY/MM/ directory of a mailbox in a date layout. Months are
+/// listed with SEARCH the first time they are looked at.
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    mailbox: u64,
+    year: i32,
+    // None for year directories, which only hold months
+    month: Option<u32>,
+    loaded: bool,
+}
+
+impl Bucket {
+    /// The search for the emails of a month.
+    fn criteria(&self, sent: bool) -> Vec<Criterion> {
+        let month = self.month.unwrap_or(1);
+        let year = self.year as u32;
+        let (next_year, next_month) = if self.month.is_none() || month == 12 {
+            (year + 1, 1)
+        } else {
+            (year, month + 1)
+        };
 
-    fn contents_as_bytes(&self) -> Vec<u8> {
-        if let Some(c) = &self.contents {
-            c.clone().as_bytes().to_vec()
+        if sent {
+            vec![Criterion::SentSince(year, month, 1), Criterion::SentBefore(next_year, next_month, 1)]
         } else {
-            "".as_bytes().to_vec()
+            vec![Criterion::Since(year, month, 1), Criterion::Before(next_year, next_month, 1)]
+        }
+    }
+}
+
+/// A file name made from a subject: slashes become look-alikes that
+/// aren't path separators, NULs and line breaks become spaces, and it is cut
+/// short enough to fit NAME_MAX with a uid added. None when nothing usable
+/// is left.
+fn entry_name_for(subject: &str) -> Option<String> {
+    let mut name = String::new();
+    for c in subject.trim().chars() {
+        let c = match c {
+            '/' => '\u{2215}',
+            c if c.is_control() => ' ',
+            c => c,
+        };
+        if name.len() + c.len_utf8() > 200 {
+            break;
         }
+        name.push(c);
+    }
+
+    let name = name.trim().to_string();
+    match name.as_str() {
+        "" | "." | ".." => None,
+        _ => Some(name),
+    }
+}
+
+/// The number of 512 byte blocks a file of `size` bytes takes, as `du`
+/// counts them.
+fn blocks(size: u64) -> u64 {
+    size.div_ceil(512)
+}
+
+/// The permission bits of a mailbox directory with RFC 4314 rights. It can
+/// be listed with l or r, and changed with any of i, k, t, e or x.
+fn rights_mode(rights: &str) -> u16 {
+    let mut mode = 0;
+    if rights.contains('l') || rights.contains('r') {
+        mode |= 0o555;
     }
+    if rights.chars().any(|c| "iktex".contains(c)) {
+        mode |= 0o200;
+    }
+    mode
+}
+
+/// The Message-ID of a message or header block.
+fn message_id(header: &[u8]) -> Option<String> {
+    let (headers, _) = mailparse::parse_headers(header).ok()?;
+    thread::header(&headers, "Message-ID").map(|i| i.trim().to_string())
+}
+
+/// When a message was sent, from the Date header in a header block.
+fn sent_time(header: &[u8]) -> Option<i64> {
+    let (headers, _) = mailparse::parse_headers(header).ok()?;
+    let date = headers.iter()
+        .find(|h| h.get_key().map(|k| k.eq_ignore_ascii_case("Date")).unwrap_or(false))?
+        .get_value()
+        .ok()?;
+
+    mailparse::dateparse(&date).ok()
 }
 
 pub struct Mailbox {
     abs_path: String,
+    // the name of the mailbox on the server, empty for directories that
+    // only exist locally
+    name: String,
+    account: usize,
+    // the directory this one is in
+    parent: u64,
+    view: Option<View>,
+    // the criteria of a saved search directory
+    search: Option<Vec<Criterion>>,
+    bucket: Option<Bucket>,
+    // a .threads directory, holding a directory per conversation
+    threads: bool,
+    // the user's ACL rights, None if the server doesn't have ACLs
+    rights: Option<String>,
     info: Option<IMAPMailbox>,
     contents: BTreeSet<u64>,
 }
 
 impl Mailbox {
-    fn new(abs_path: &str) -> Mailbox {
+    fn new(abs_path: &str, name: &str, account: usize) -> Mailbox {
         Mailbox {
             abs_path: abs_path.to_string(),
+            name: name.to_string(),
+            account,
+            parent: 1,
+            view: None,
+            search: None,
+            bucket: None,
+            threads: false,
+            rights: None,
             info: None,
             contents: BTreeSet::new(),
         }
@@ -99,28 +280,12 @@ impl Mailbox {
         self.contents.insert(inode);
     }
 
-    fn set_info(&mut self, info: IMAPMailbox) {
-        self.info = Some(info);
+    fn flags(&self) -> Option<Vec<IMAPFlag<'_>>> {
+        self.info.as_ref().map(|i| i.flags.clone())
     }
 
-    fn has_info(&self) -> bool {
-        self.info.is_some()
-    }
-
-    fn flags(&self) -> Option<Vec<IMAPFlag>> {
-        if let Some(i) = &self.info {
-            Some(i.flags.clone())
-        } else {
-            None
-        }
-    }
-
-    fn permanent_flags(&self) -> Option<Vec<IMAPFlag>> {
-        if let Some(i) = &self.info {
-            Some(i.permanent_flags.clone())
-        } else {
-            None
-        }
+    fn permanent_flags(&self) -> Option<Vec<IMAPFlag<'_>>> {
+        self.info.as_ref().map(|i| i.permanent_flags.clone())
     }
 
     fn exists(&self) -> u32 {
@@ -139,14 +304,6 @@ impl Mailbox {
         }
     }
 
-    fn unseen(&self) -> Option<u32> {
-        if let Some(i) = &self.info {
-            i.unseen
-        } else {
-            None
-        }
-    }
-
     fn uid_next(&self) -> Option<Uid> {
         if let Some(i) = &self.info {
             i.uid_next
@@ -162,414 +319,3073 @@ impl Mailbox {
             None
         }
     }
+}
 
-    fn print(&self, tabs: usize) {
-        println!("{:\t<0$}mailbox: {}", tabs, self.abs_path);
-    }
+pub struct Account {
+    name: String,
+    backend: Box<dyn Backend>,
+    smtp: Option<SmtpConfig>,
+    // the directory holding the account's mailboxes
+    root: u64,
+    outbox: Option<u64>,
+    // the .search directory and the searches to put in it
+    search: Option<u64>,
+    searches: Vec<(String, Vec<Criterion>)>,
+    // the last quota and when it was asked for
+    quota: Option<(i64, Quota)>,
 }
 
 pub struct REmailFS {
-    username: String,
-    password: String,
-    domain: String,
-    port: u16,
     next_inode: u64,
-    imap_session: IMAPSession,
+    accounts: Vec<Account>,
     inodes: BTreeMap<String, u64>,
     emails: BTreeMap<u64, Email>,
     mailboxes: BTreeMap<u64, Mailbox>,
     attributes: BTreeMap<u64, FileAttr>,
+    // messages written into an outbox that haven't been sent yet
+    outgoing: BTreeMap<u64, Vec<u8>>,
+    // (account, server-wide message id) -> email, so a message in several
+    // mailboxes of an account is one inode
+    message_ids: BTreeMap<(usize, String), u64>,
+    // .status file -> the mailbox it describes
+    statuses: BTreeMap<u64, u64>,
+    // the charset emails are rendered in
+    charset: EncodingRef,
+    template: Template,
+    // set \Seen on emails when they are read
+    mark_read: bool,
+    layout: Layout,
+    // owner of every file, the user that mounted when None
+    uid: Option<u32>,
+    gid: Option<u32>,
+    umask: u16,
+    // nothing on the server is changed
+    read_only: bool,
+    // deleted emails are expunged rather than only flagged \Deleted
+    expunge: bool,
+    // destructive operations, the last one last
+    journal: Vec<undo::Entry>,
+    // where the journal is saved, it only lasts until unmounting when None
+    state_dir: Option<PathBuf>,
+    // the .remailfs directory and the undo file in it
+    control: Option<u64>,
+    undo_file: Option<u64>,
+    // a filesystem built from the configuration read again, left here from
+    // outside to be taken up
+    reload: Arc<Mutex<Option<REmailFS>>>,
+    // called once init has built the tree
+    ready: Option<Box<dyn FnMut() + Send>>,
 }
 
-impl REmailFS {
-    pub fn new(uname: String, pword: String, domain: String, port: u16) -> Result<REmailFS, &'static str> {
-        let tls = TlsConnector::builder().build().unwrap();
-        println!("created tls");
-
-        let client = match imap::connect((domain.as_str(), port), domain.as_str(), &tls){
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("{:?}", e);
-                return Err("failed to create IMAP client")
-            },
-        };
-        println!("created client");
-
-        let session = match client.login(uname.as_str(), pword.as_str()) {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("{:?}", e);
-                return Err("failed to login");
-            },
-        };
-        println!("created session");
+const OUTBOX: &str = "Outbox";
+const SEARCH: &str = ".search";
+const THREADS: &str = ".threads";
+const LABELS: &str = "user.gmail.labels";
+const STATUS: &str = ".status";
+const CONTROL: &str = ".remailfs";
+const UNDO: &str = "undo";
+// seconds a quota is reused for, df and file managers ask often
+const QUOTA_TTL: i64 = 60;
+
+impl Default for REmailFS {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        Ok(REmailFS { 
-            username: uname, 
-            password: pword, 
-            domain: domain, 
-            port: port,
+impl REmailFS {
+    pub fn new() -> REmailFS {
+        REmailFS { 
             next_inode: 2,
-            imap_session: session,
+            accounts: Vec::new(),
             inodes: BTreeMap::new(),
             emails: BTreeMap::new(),
             mailboxes: BTreeMap::new(),
             attributes: BTreeMap::new(),
-        })
+            outgoing: BTreeMap::new(),
+            message_ids: BTreeMap::new(),
+            statuses: BTreeMap::new(),
+            charset: encoding::all::UTF_8,
+            template: Template::default(),
+            mark_read: false,
+            layout: Layout::Flat,
+            uid: None,
+            gid: None,
+            umask: 0,
+            read_only: true,
+            expunge: true,
+            journal: Vec::new(),
+            state_dir: None,
+            control: None,
+            undo_file: None,
+            reload: Arc::new(Mutex::new(None)),
+            ready: None,
+        }
     }
-}
 
-impl Filesystem for REmailFS {
-    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
-        println!("Entered init!");
+    /// Sets how the emails of each mailbox are laid out. The date layouts
+    /// need a backend that can search, other accounts stay flat.
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
 
-        let now = time::now().to_timespec();;
+    /// Sets who the files belong to instead of the user that mounted.
+    pub fn set_owner(&mut self, uid: Option<u32>, gid: Option<u32>) {
+        self.uid = uid;
+        self.gid = gid;
+    }
 
-        let mut root_attrs = FileAttr {
-            ino: 1,
-            size: 4096,
-            blocks: 1,
-            atime: now,
-            mtime: now,
-            ctime: Timespec::new(0,0),
-            crtime: Timespec::new(0,0),
-            kind: FileType::Directory,
-            perm: 0o444,
-            nlink: 1,
-            uid: _req.uid(),
-            gid: _req.gid(),
-            rdev: 0,
-            flags: 0,
-        };
+    /// Sets the permission bits taken away from every file.
+    pub fn set_umask(&mut self, umask: u16) {
+        self.umask = umask & 0o777;
+    }
 
-        let root_mailbox = Mailbox::new("/");
+    /// Allows the filesystem to change the server, which is off by default.
+    /// Read-only mounts answer every change with EROFS.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
 
-        self.inodes.insert("/".to_string(), 1);
-        self.mailboxes.insert(1, root_mailbox);
-        self.attributes.insert(1, root_attrs);
+    /// Makes deleting and moving emails only flag them `\Deleted` instead
+    /// of expunging them.
+    pub fn set_expunge(&mut self, expunge: bool) {
+        self.expunge = expunge;
+        for account in self.accounts.iter_mut() {
+            account.backend.set_expunge(expunge);
+        }
+    }
 
-        let mut root_mailbox = self.mailboxes.get_mut(&1)
-            .unwrap();
+    /// Keeps the undo journal in `dir`, so that what was done before can be
+    /// undone after mounting again. It is read when the mount is ready.
+    pub fn set_state_dir(&mut self, dir: &Path) {
+        self.state_dir = Some(dir.to_path_buf());
+    }
 
-        let mut all_boxes = match self.imap_session.list(Some(""), Some("*")) {
-            Ok(ab) => ab,
-            Err(_) => return Err(-1)
-        };
+    /// Makes reading an email mark it as read on the server, which doesn't
+    /// happen otherwise.
+    pub fn set_mark_read(&mut self, mark_read: bool) {
+        self.mark_read = mark_read;
+    }
 
-        let mut all_boxes: Vec<&str> = all_boxes.iter()
-            .map(|n| n.name().clone())
-            .collect();
+    /// Sets how emails are laid out when they are read.
+    pub fn set_template(&mut self, template: Template) {
+        self.template = template;
+    }
 
-        all_boxes.sort_unstable();
+    /// Sets the charset emails are rendered in, `label` is any name the
+    /// WHATWG Encoding standard knows ("utf-8", "latin1", "shift_jis", ...).
+    /// Returns false if the charset isn't known.
+    pub fn set_charset(&mut self, label: &str) -> bool {
+        match encoding::label::encoding_from_whatwg_label(label) {
+            Some(c) => {
+                self.charset = c;
+                true
+            },
+            None => false,
+        }
+    }
 
-        for mb in all_boxes.iter() {
-            println!("adding {}", *mb);
-            let inode = self.next_inode;
-            let mut abs_path = mb.to_string();
-            let mut mailbox = Mailbox::new(&abs_path);
+    /// Where a filesystem built from the configuration read again is left.
+    /// Its settings and accounts replace these ones, and every mailbox is
+    /// listed again, the next time the mount is looked at.
+    pub fn reload_slot(&self) -> Arc<Mutex<Option<REmailFS>>> {
+        self.reload.clone()
+    }
 
-            self.next_inode += 1;
+    /// Sets what is called once the tree has been built and the mount can
+    /// be used.
+    pub fn on_ready<F>(&mut self, ready: F) where F: FnMut() + Send + 'static {
+        self.ready = Some(Box::new(ready));
+    }
 
-            mailbox.info = match self.imap_session.examine(*mb) {
-                Ok(mb) => Some(mb),
-                Err(_) => None
-            };
+    /// Logs out of every account.
+    pub fn logout(&mut self) {
+        for account in self.accounts.iter_mut() {
+            account.backend.logout();
+        }
+    }
 
-            let mailbox_attrs =  FileAttr {
-                ino: inode,
-                size: 4096,
-                blocks: 1,
-                atime: now,
-                mtime: now,
-                ctime: Timespec::new(0,0),
-                crtime: Timespec::new(0,0),
-                kind: FileType::Directory,
-                perm: 0o444,
-                nlink: 1,
-                uid: _req.uid(),
-                gid: _req.gid(),
-                rdev: 0,
-                flags: 0,
-            };
-                
-            self.inodes.insert(abs_path.clone(), inode);
-            self.mailboxes.insert(inode, mailbox);
-            self.attributes.insert(inode, mailbox_attrs);
-
-            let mut parent = self.mailboxes.get_mut(&inode).unwrap();
-            let uids = match self.imap_session.uid_search("1:*") {
-                Ok(u) => Some(u),
-                Err(_) => None,
-            };
+    /// Adds an account to the mount. With a single account its mailboxes
+    /// sit at the root of the mount, otherwise each account gets a top
+    /// level directory named `name`. When `smtp` is given the account gets an
+    /// Outbox directory whose messages are sent with that server.
+    pub fn add_account(&mut self, name: &str, mut backend: Box<dyn Backend>, smtp: Option<SmtpConfig>) {
+        backend.set_expunge(self.expunge);
+        self.accounts.push(Account {
+            name: name.to_string(),
+            backend,
+            smtp,
+            root: 1,
+            outbox: None,
+            search: None,
+            searches: Vec::new(),
+            quota: None,
+        });
+    }
 
-            if uids.is_some() {
-            for uid in uids.unwrap() {
-                let mut path = abs_path.clone();
-                let u_inode = self.next_inode;
-
-                path.push('/');
-                path.push_str(uid.to_string().as_str());
-                self.next_inode += 1;
-
-                let email = Email::new(&path);
-
-                let email_attrs =  FileAttr {
-                    ino: u_inode,
-                    size: 4096,
-                    blocks: 1,
-                    atime: now,
-                    mtime: now,
-                    ctime: Timespec::new(0,0),
-                    crtime: Timespec::new(0,0),
-                    kind: FileType::RegularFile,
-                    perm: 0o444,
-                    nlink: 1,
-                    uid: _req.uid(),
-                    gid: _req.gid(),
-                    rdev: 0,
-                    flags: 0,
-                };
+    /// Adds a saved search to the `.search` directory of the account that
+    /// was added last, see `search::parse` for the query syntax. Returns
+    /// false if the query can't be parsed.
+    pub fn add_search(&mut self, name: &str, query: &str) -> bool {
+        let criteria = match search::parse(query) {
+            Some(c) => c,
+            None => return false,
+        };
 
-                self.inodes.insert(path.clone(), u_inode);
-                self.emails.insert(u_inode, email);
-                self.attributes.insert(u_inode, email_attrs);
+        match self.accounts.last_mut() {
+            Some(a) => {
+                a.searches.push((name.to_string(), criteria));
+                true
+            },
+            None => false,
+        }
+    }
 
-                parent.add_content(u_inode);
+    /// Takes the settings and connections of a freshly read configuration,
+    /// if one was left, and lists every mailbox again. Accounts are matched
+    /// by name, the layout and saved searches stay as mounted.
+    fn check_reload(&mut self) {
+        let new = match self.reload.lock().ok().and_then(|mut r| r.take()) {
+            Some(n) => n,
+            None => return,
+        };
+        info!("reloading the configuration");
+
+        self.charset = new.charset;
+        self.template = new.template;
+        self.mark_read = new.mark_read;
+        self.uid = new.uid;
+        self.gid = new.gid;
+        self.umask = new.umask;
+        self.read_only = new.read_only;
+        if new.layout != self.layout {
+            warn!("the layout only changes when mounting again");
+        }
 
-                //println!("{}", path); 
-            }
+        for account in new.accounts {
+            match self.accounts.iter_mut().find(|a| a.name == account.name) {
+                Some(old) => {
+                    old.backend.logout();
+                    old.backend = account.backend;
+                    old.smtp = account.smtp;
+                    old.quota = None;
+                },
+                None => warn!("account {} only appears when mounting again", account.name),
             }
-            let mut split_path: Vec<&str> = abs_path.rsplitn(2, "/")
-                .collect();
-            let mut p_inode = &1;
+        }
+        self.set_expunge(new.expunge);
 
-            if split_path.len() > 1 { 
-                p_inode = self.inodes.get(split_path[1]).unwrap();
+        // emails are rendered again with the new template
+        for (ino, email) in self.emails.iter_mut() {
+            if !self.outgoing.contains_key(ino) && !self.statuses.contains_key(ino) && self.undo_file != Some(*ino) {
+                email.contents = None;
             }
+        }
 
-            let mut parent = self.mailboxes.get_mut(p_inode).unwrap();
-            parent.add_content(inode);
+        let mailboxes: Vec<u64> = self.mailboxes.iter()
+            .filter(|(i, m)| !m.name.is_empty() && !self.is_virtual(**i))
+            .map(|(i, _)| *i)
+            .collect();
+        for mailbox in mailboxes {
+            if let Err(e) = self.sync_mailbox(mailbox) {
+                warn!("{}", e);
+            }
         }
-        println!("REmailFS is ready to use!");
-        Ok(())
     }
 
-    fn destroy(&mut self, _req: &Request) {
-        println!("Entered destroy!");
-
-        let _ = self.imap_session.logout();
+    fn is_search(&self, ino: u64) -> bool {
+        self.accounts.iter().any(|a| a.search == Some(ino))
     }
 
-    fn getattr(&mut self, _req: &Request, _ino: u64, reply: ReplyAttr) {
-        println!("getattr(ino={})", _ino);
-        let attrs = self.attributes.get(&_ino);
-        if let Some(a) = attrs {
-            let ttl = Timespec::new(1, 0);
-            reply.attr(&ttl, a);
-        } else {
-            println!("ENOENT in getattr");
-            reply.error(ENOENT);
-        }
+    /// Directories whose contents REmailFS makes up, rather than mailboxes
+    /// on the server.
+    fn is_virtual(&self, ino: u64) -> bool {
+        self.control == Some(ino) || self.is_search(ino) || self.is_thread(ino) || self.mailboxes.get(&ino)
+            .map(|m| m.view.is_some() || m.search.is_some() || m.bucket.is_some() || m.threads)
+            .unwrap_or(false)
     }
 
-    fn readdir(&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, mut reply: ReplyDirectory) {
-        println!("readdir(ino = {}, fh = {})", _ino, _fh);
+    /// Whether a directory is a conversation in a .threads directory.
+    fn is_thread(&self, ino: u64) -> bool {
+        self.mailboxes.get(&ino)
+            .and_then(|m| self.mailboxes.get(&m.parent))
+            .map(|p| p.threads)
+            .unwrap_or(false)
+    }
 
-        let mailbox = self.mailboxes.get(&_ino);
+    fn is_outbox(&self, ino: u64) -> bool {
+        self.accounts.iter().any(|a| a.outbox == Some(ino))
+    }
 
-        let mut mailbox = if mailbox.is_none() {
-            println!("ENOENT in readdir");
-            reply.error(ENOENT);
-            return;
-        } else {
-            mailbox.unwrap()
+    fn mailbox_inode(&self, account: usize, name: &str) -> Option<u64> {
+        self.mailboxes.iter()
+            .find(|(_, m)| m.account == account && m.name == name)
+            .map(|(i, _)| *i)
+    }
 
-        };
+    fn add_directory(&mut self, abs_path: &str, name: &str, account: usize, parent: u64, template: &FileAttr) -> u64 {
+        let inode = self.next_inode;
+        self.next_inode += 1;
 
-        if _offset == 0 {
-            let mut count = 2;
-            let mut info = &mailbox.info;
-            let contents = &mailbox.contents;
+        let mut attrs = *template;
+        attrs.ino = inode;
 
-            reply.add(1, 0, FileType::Directory, ".");
-            reply.add(1, 1, FileType::Directory, "..");
+        let mut mailbox = Mailbox::new(abs_path, name, account);
+        mailbox.parent = parent;
 
-            for inode in contents.iter() {
-                let base: EmailObject = if self.mailboxes.get(inode).is_some() {
-                    EmailObject::M(self.mailboxes.get(inode).unwrap())
-                } else {
-                    println!(">>> READDIR ON EMAIL <<<");
-                    EmailObject::E(self.emails.get(inode).unwrap())
-                };
+        self.inodes.insert(abs_path.to_string(), inode);
+        self.mailboxes.insert(inode, mailbox);
+        self.attributes.insert(inode, attrs);
+        self.mailboxes.get_mut(&parent).unwrap().add_content(inode);
 
-                let rel_path = match base {
-                    EmailObject::E(e) => e.abs_path.clone(),
-                    EmailObject::M(m) => m.abs_path.clone(),
-                };
+        inode
+    }
 
-                let rel_path = rel_path
-                    .rsplitn(2, "/")
-                    .next()
-                    .unwrap();
+    /// The account's quota. Backends without one are counted from the sizes
+    /// of the emails listed so far.
+    fn quota(&mut self, account: usize) -> Quota {
+        let now = time::get_time().sec;
+        if let Some((when, quota)) = self.accounts[account].quota {
+            if now - when < QUOTA_TTL {
+                return quota;
+            }
+        }
 
-                let f_type = self.attributes.get(inode)
-                    .unwrap()
-                    .kind;
+        let quota = match self.accounts[account].backend.quota() {
+            Ok(q) => q,
+            Err(error::Error::Unsupported) => self.listed_quota(account),
+            Err(e) => {
+                warn!("{}", e);
+                self.listed_quota(account)
+            },
+        };
 
-                reply.add(*inode, 2+count as i64, f_type, rel_path); 
-                count += 1;
-            }
+        self.accounts[account].quota = Some((now, quota));
+        quota
+    }
 
-            println!("CONTENT COUNT = {}", count);
+    /// The storage used by the emails of an account listed so far.
+    fn listed_quota(&self, account: usize) -> Quota {
+        let sizes: Vec<u64> = self.emails.iter()
+            .filter(|(i, _)| !self.statuses.contains_key(i))
+            .filter(|(_, e)| self.mailboxes.get(&e.mailbox).map(|m| m.account == account && !m.name.is_empty()).unwrap_or(false))
+            .filter_map(|(i, _)| self.attributes.get(i))
+            .map(|a| a.size)
+            .collect();
 
-            reply.ok();
+        Quota {
+            storage: sizes.iter().sum(),
+            storage_limit: None,
+            messages: sizes.len() as u64,
+            message_limit: None,
         }
     }
 
-    fn lookup(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
-        println!("lookup(parent = {}, name = {:#?})", _parent, _name);
+    /// Builds the tree under the root directory, owned by `uid` and `gid`.
+    fn build(&mut self, uid: u32, gid: u32) -> Result<(), c_int> {
+        let now = time::now().to_timespec();
 
-        let _name = _name.to_str()
-            .unwrap();
+        let root_attrs = FileAttr {
+            ino: 1,
+            size: 4096,
+            blocks: 8,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::Directory,
+            perm: 0o555,
+            nlink: 2,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+        };
 
-        let mut abs_path = if _parent != 1 {
-            self.mailboxes.get(&_parent)
-                .unwrap()
-                .abs_path
-                .clone()
+        let root_mailbox = Mailbox::new("/", "", 0);
+
+        self.inodes.insert("/".to_string(), 1);
+        self.mailboxes.insert(1, root_mailbox);
+        self.attributes.insert(1, root_attrs);
+
+        for account in 0..self.accounts.len() {
+            self.build_account(account, &root_attrs)?;
+        }
+        self.add_control(&root_attrs);
+        Ok(())
+    }
+
+    /// Lists the account's mailboxes and their emails.
+    fn build_account(&mut self, account: usize, dir_attrs: &FileAttr) -> Result<(), c_int> {
+        let (root, prefix) = if self.accounts.len() == 1 {
+            (1, "".to_string())
         } else {
-            "".to_string()
+            let name = self.accounts[account].name.clone();
+            let root = self.add_directory(&name, "", account, 1, dir_attrs);
+            (root, format!("{}/", name))
+        };
+        self.accounts[account].root = root;
+
+        let mut all_boxes = match self.accounts[account].backend.list() {
+            Ok(ab) => ab,
+            Err(e) => {
+                warn!("{}", e);
+                return Err(e.errno());
+            },
         };
 
-        if _parent != 1 { abs_path.push('/') }; 
+        all_boxes.sort_by(|a, b| a.path.cmp(&b.path));
 
-        abs_path.push_str(_name);
+        for mb in all_boxes.iter() {
+            debug!("adding {}", mb.name);
+
+            // parents the server didn't list become plain local directories
+            let mut p_inode = root;
+            for i in 1..mb.path.len() {
+                let parent_path = format!("{}{}", prefix, mb.path[..i].join("/"));
+                p_inode = match self.inodes.get(&parent_path) {
+                    Some(ino) => *ino,
+                    None => self.add_directory(&parent_path, "", account, p_inode, dir_attrs),
+                };
+            }
 
-        let inode = match self.inodes.get(&abs_path) {
-            Some(i) => i,
-            None => {
-                reply.error(ENOENT);
-                return;
+            let abs_path = format!("{}{}", prefix, mb.path.join("/"));
+            let name = if mb.selectable { mb.name.as_str() } else { "" };
+
+            let inode = match self.inodes.get(&abs_path) {
+                Some(ino) => *ino,
+                None => self.add_directory(&abs_path, name, account, p_inode, dir_attrs),
+            };
+
+            if !mb.selectable {
+                continue;
+            }
+            self.mailboxes.get_mut(&inode).unwrap().name = mb.name.clone();
+
+            let info = self.accounts[account].backend.examine(&mb.name).ok();
+            self.mailboxes.get_mut(&inode).unwrap().info = info;
+
+            // without ACLs the mailbox is taken to be ours to change
+            let rights = match self.accounts[account].backend.rights(&mb.name) {
+                Ok(r) => Some(r),
+                Err(error::Error::Unsupported) => None,
+                Err(e) => {
+                    warn!("{}", e);
+                    None
+                },
+            };
+            self.attributes.get_mut(&inode).unwrap().perm = rights.as_ref().map(|r| rights_mode(r)).unwrap_or(0o755);
+            self.mailboxes.get_mut(&inode).unwrap().rights = rights;
+
+            // in a date layout only the YYYY/MM/ directories are made now,
+            // a month's emails are listed when it is first looked at
+            if self.is_dated(inode) {
+                let sent = self.layout == Layout::Sent;
+                match self.accounts[account].backend.date_range(&mb.name, sent) {
+                    Ok(Some((first, last))) => self.add_buckets(inode, first, last),
+                    Ok(None) => (),
+                    Err(e) => warn!("{}", e),
+                }
+            } else {
+                match self.accounts[account].backend.messages(&mb.name) {
+                    Ok(summaries) => for summary in summaries {
+                        self.add_email(inode, summary);
+                    },
+                    Err(e) => warn!("{}", e),
+                }
             }
-        };
 
-        let attrs = self.attributes.get(inode);
+            if self.accounts[account].backend.has_flags() {
+                self.add_views(inode, dir_attrs);
+            }
 
-        if let Some(a) = attrs {
-            let ttl = Timespec::new(1, 0);
-            reply.entry(&ttl, a, 1);
-        } else {
-            reply.error(ENOENT);
+            self.add_status(inode);
+
+            // the conversations are only worked out when listed
+            let threads_path = format!("{}/{}", abs_path, THREADS);
+            if self.accounts[account].backend.has_threads() && !self.inodes.contains_key(&threads_path) {
+                let threads = self.add_directory(&threads_path, "", account, inode, dir_attrs);
+                self.mailboxes.get_mut(&threads).unwrap().threads = true;
+            }
+        }
+
+        if self.accounts[account].smtp.is_some() {
+            let path = format!("{}{}", prefix, OUTBOX);
+
+            if self.inodes.contains_key(&path) {
+                warn!("the server already has a mailbox called {}, not adding the outbox", OUTBOX);
+            } else {
+                let mut outbox_attrs = *dir_attrs;
+                outbox_attrs.perm = 0o755;
+
+                let inode = self.add_directory(&path, "", account, root, &outbox_attrs);
+                self.accounts[account].outbox = Some(inode);
+            }
         }
+
+        let path = format!("{}{}", prefix, SEARCH);
+        if self.accounts[account].backend.has_search() && !self.inodes.contains_key(&path) {
+            let inode = self.add_directory(&path, "", account, root, dir_attrs);
+            self.accounts[account].search = Some(inode);
+
+            let searches = self.accounts[account].searches.clone();
+            for (name, criteria) in searches {
+                self.add_search_directory(inode, &name, criteria);
+            }
+        }
+
+        Ok(())
     }
 
-    fn read(&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: ReplyData) {
-        println!("read(_ino = {})", _ino);
-        let mut email = self.emails.get_mut(&_ino);
+    /// Adds a saved search to a `.search` directory and runs it.
+    fn add_search_directory(&mut self, search: u64, name: &str, criteria: Vec<Criterion>) -> u64 {
+        let (path, account) = match self.mailboxes.get(&search) {
+            Some(m) => (format!("{}/{}", m.abs_path, name), m.account),
+            None => return 0,
+        };
 
-        if email.is_none() {
-            reply.error(ENOENT);
-            return;
+        let attrs = *self.attributes.get(&search).unwrap();
+        let inode = self.add_directory(&path, "", account, search, &attrs);
+        self.mailboxes.get_mut(&inode).unwrap().search = Some(criteria);
+
+        if let Err(e) = self.run_search(inode) {
+            warn!("{}", e);
         }
 
-        let mut email = email.unwrap();
+        inode
+    }
 
-        if email.contents.is_none() {
-            let split_path: Vec<&str> = email.abs_path.rsplitn(2, "/").collect();    
+    /// Runs a saved search in every mailbox of its account, or a view in
+    /// its mailbox, and links the matching emails into its directory.
+    fn run_search(&mut self, ino: u64) -> error::Result<()> {
+        let (account, criteria, only) = match self.mailboxes.get(&ino) {
+            Some(Mailbox { account, search: Some(c), .. }) => (*account, c.clone(), None),
+            Some(Mailbox { account, view: Some(v), parent, .. }) => (*account, vec![v.criterion()], Some(*parent)),
+            _ => return Ok(()),
+        };
 
-            let uid = split_path[0];
-            let parent = split_path[1];
+        let sources: Vec<(u64, String)> = self.mailboxes.iter()
+            .filter(|(i, m)| m.account == account && !m.name.is_empty() && only.map(|o| o == **i).unwrap_or(true))
+            .map(|(i, m)| (*i, m.name.clone()))
+            .collect();
 
-            self.imap_session.examine(parent);
+        let mut found = BTreeSet::new();
+        for (source, name) in sources {
+            let uids = self.accounts[account].backend.search(&name, &criteria)?;
+            if uids.is_empty() {
+                continue;
+            }
 
-            let contents = self.imap_session.uid_fetch(uid, "RFC822");
+            let mut known = self.mailbox_uids(source);
 
-            if contents.is_err() {
-                reply.error(ENOENT);
-                return;
-            } 
-
-            let mut data = "".to_string();
-
-            let fetch: &Fetch = &contents.unwrap()[0];
-            let body = fetch.body();;
-            let mut body_str = "".to_string();
-                
-            if body.is_some() {
-                let new_body_str = std::str::from_utf8(body.unwrap());
-                if new_body_str.is_ok() {
-                    body_str.push_str(&new_body_str.unwrap().to_string());
+            // mail that arrived since the mailbox was listed, or that is in
+            // a month that hasn't been looked at
+            let unknown: Vec<String> = uids.iter()
+                .filter(|u| !known.contains_key(*u))
+                .cloned()
+                .collect();
+            if !unknown.is_empty() {
+                if self.is_dated(source) {
+                    self.load_uids(source, &unknown)?;
+                } else {
+                    self.sync_mailbox(source)?;
                 }
+                known = self.mailbox_uids(source);
             }
 
-            data.push_str(&body_str);
-            email.contents = Some(data);
+            found.extend(uids.iter().filter_map(|u| known.get(u)));
         }
 
-        let mut email = self.emails.get_mut(&_ino).unwrap();
-        let contents = email.contents.clone().unwrap();
+        let old = std::mem::replace(&mut self.mailboxes.get_mut(&ino).unwrap().contents, found.clone());
+        for email in old.union(&found) {
+            self.update_nlink(*email);
+        }
 
-        println!(">>> EMAIL = {}", email.contents.clone().unwrap());
+        Ok(())
+    }
 
-        let mut reply_text = "".to_string();
-        let mut add_key_val = |k: &str, v: &str| {
-            reply_text.push_str(k.clone());
-            reply_text.push_str(": ");
-            reply_text.push_str(v.clone());
-            reply_text.push('\n');
+    /// Fills a .threads directory with a directory per conversation, named
+    /// after the subject of its first email and dated by it.
+    fn run_threads(&mut self, ino: u64) -> error::Result<()> {
+        let (account, mailbox, path) = match self.mailboxes.get(&ino) {
+            Some(m) if m.threads => (m.account, m.parent, m.abs_path.clone()),
+            _ => return Ok(()),
+        };
+        let name = match self.mailboxes.get(&mailbox) {
+            Some(m) => m.name.clone(),
+            None => return Ok(()),
         };
 
-        let parsed = mailparse::parse_mail(contents.as_bytes()).unwrap();
+        let threads = self.accounts[account].backend.threads(&name)?;
 
-        for header in parsed.headers {
-            let key = header.get_key().unwrap();
-            let val = header.get_value().unwrap();
+        let mut known = self.mailbox_uids(mailbox);
 
-            match key.as_str() {
-                "Subject" => {
-                    let mut new_path = "".to_string(); 
-                    let split_path: Vec<&str> = email.abs_path.rsplitn(2, "/").collect();
-                    new_path.push_str(split_path[1]);
-                    new_path.push('/');
-                    new_path.push_str(val.as_str());
+        let unknown: Vec<String> = threads.iter()
+            .flat_map(|t| t.uids.iter())
+            .filter(|u| !known.contains_key(*u))
+            .cloned()
+            .collect();
+        if !unknown.is_empty() {
+            if self.is_dated(mailbox) {
+                self.load_uids(mailbox, &unknown)?;
+            } else {
+                self.sync_mailbox(mailbox)?;
+            }
+            known = self.mailbox_uids(mailbox);
+        }
 
-                    email.abs_path = new_path.to_string();
-                    self.inodes.insert(new_path, _ino);
+        let old = self.mailboxes.get(&ino).unwrap().contents.clone();
+        let mut linked: BTreeSet<u64> = old.iter()
+            .filter_map(|d| self.mailboxes.get(d))
+            .flat_map(|d| d.contents.iter().cloned())
+            .collect();
 
-                    add_key_val(key.as_str(), val.as_str())
-                }
-                "From"      => add_key_val(key.as_str(), val.as_str()), 
-                "Date"      => {
-                    // Mon, 15 Apr 2019 17:49:15 -0500 (CDT)   
-                    let tm = strptime(val.as_str(), "%a, %d %b %Y %H:%M:%S");
-                    if tm.is_ok() {
-                        let tm = tm.unwrap().to_timespec();
-                        let mut attr = self.attributes.get_mut(&_ino).unwrap();
-                        attr.atime = tm;
-                        attr.mtime = tm;
-                        attr.ctime = tm;
-                        attr.crtime = tm;
-                        println!(">>> FORMATTED TIME");
-                    } else {
-                        println!(">>> Unable to format time");
+        let attrs = *self.attributes.get(&ino).unwrap();
+        let mut current = BTreeSet::new();
+        let mut names = BTreeSet::new();
+
+        for thread in threads {
+            let subject = thread.subject.as_deref()
+                .and_then(entry_name_for)
+                .unwrap_or_else(|| "(no subject)".to_string());
+
+            // conversations with the same subject get a number
+            let mut dir_name = subject.clone();
+            let mut n = 2;
+            while names.contains(&dir_name) {
+                dir_name = format!("{} ({})", subject, n);
+                n += 1;
+            }
+            names.insert(dir_name.clone());
+
+            let dir_path = format!("{}/{}", path, dir_name);
+            let dir = match self.inodes.get(&dir_path) {
+                Some(i) if self.mailboxes.contains_key(i) => *i,
+                _ => self.add_directory(&dir_path, "", account, ino, &attrs),
+            };
+
+            if let (Some(date), Some(a)) = (thread.date, self.attributes.get_mut(&dir)) {
+                a.mtime = Timespec::new(date, 0);
+            }
+
+            let emails: BTreeSet<u64> = thread.uids.iter()
+                .filter_map(|u| known.get(u))
+                .cloned()
+                .collect();
+            linked.extend(emails.iter());
+            self.mailboxes.get_mut(&dir).unwrap().contents = emails;
+
+            current.insert(dir);
+        }
+
+        for dir in old.difference(&current) {
+            if let Some(m) = self.mailboxes.remove(dir) {
+                self.inodes.remove(&m.abs_path);
+            }
+            self.attributes.remove(dir);
+        }
+        self.mailboxes.get_mut(&ino).unwrap().contents = current;
+
+        for email in linked {
+            self.update_nlink(email);
+        }
+
+        Ok(())
+    }
+
+    /// The (account, mailbox, uid) to ask for an email's labels with, if
+    /// its account has labels.
+    fn labelled(&self, ino: u64) -> Option<(usize, String, String)> {
+        let email = self.emails.get(&ino)?;
+        let mailbox = self.mailboxes.get(&email.mailbox)?;
+
+        if !self.accounts[mailbox.account].backend.has_labels() {
+            return None;
+        }
+        Some((mailbox.account, mailbox.name.clone(), email.uid.clone()))
+    }
+
+    /// Adds the .status file to a mailbox directory, filled in from what
+    /// EXAMINE said until it is first refreshed.
+    fn add_status(&mut self, mailbox: u64) {
+        let (path, status) = match self.mailboxes.get(&mailbox) {
+            Some(m) => (format!("{}/{}", m.abs_path, STATUS), Status {
+                messages: m.exists(),
+                recent: Some(m.recent()),
+                unseen: None,
+                uid_next: m.uid_next(),
+                uid_validity: m.uid_validity(),
+            }),
+            None => return,
+        };
+        if self.inodes.contains_key(&path) {
+            return;
+        }
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+
+        let mut attrs = *self.attributes.get(&mailbox).unwrap();
+        attrs.ino = inode;
+        attrs.kind = FileType::RegularFile;
+        attrs.perm = 0o444;
+        attrs.nlink = 1;
+
+        self.inodes.insert(path.clone(), inode);
+        self.emails.insert(inode, Email::new(&path, mailbox, ""));
+        self.attributes.insert(inode, attrs);
+        self.statuses.insert(inode, mailbox);
+        self.mailboxes.get_mut(&mailbox).unwrap().add_content(inode);
+
+        self.set_status(inode, &status);
+    }
+
+    /// Asks the server for the counts of a .status file's mailbox.
+    fn refresh_status(&mut self, file: u64) -> error::Result<()> {
+        let mailbox = match self.statuses.get(&file) {
+            Some(m) => *m,
+            None => return Ok(()),
+        };
+        let (account, name) = match self.mailboxes.get(&mailbox) {
+            Some(m) => (m.account, m.name.clone()),
+            None => return Ok(()),
+        };
+
+        let status = self.accounts[account].backend.status(&name)?;
+
+        if let Some(info) = self.mailboxes.get_mut(&mailbox).and_then(|m| m.info.as_mut()) {
+            info.exists = status.messages;
+            info.recent = status.recent.unwrap_or(info.recent);
+            info.uid_next = status.uid_next.or(info.uid_next);
+            info.uid_validity = status.uid_validity.or(info.uid_validity);
+        }
+
+        self.set_status(file, &status);
+        Ok(())
+    }
+
+    /// Writes out the contents of a .status file, one key=value per line.
+    fn set_status(&mut self, file: u64, status: &Status) {
+        let mailbox = match self.statuses.get(&file).and_then(|m| self.mailboxes.get(m)) {
+            Some(m) => m,
+            None => return,
+        };
+
+        let mut text = format!("exists={}\n", status.messages);
+        let counts = [
+            ("recent", status.recent),
+            ("unseen", status.unseen),
+            ("uid_next", status.uid_next),
+            ("uid_validity", status.uid_validity),
+        ];
+        for (key, value) in counts.iter() {
+            if let Some(v) = value {
+                text.push_str(&format!("{}={}\n", key, v));
+            }
+        }
+
+        let flags = [("flags", mailbox.flags()), ("permanent_flags", mailbox.permanent_flags())];
+        for (key, value) in flags.iter() {
+            if let Some(f) = value {
+                let names: Vec<String> = f.iter().map(|f| backend::imap::flag_name(f)).collect();
+                text.push_str(&format!("{}={}\n", key, names.join(" ")));
+            }
+        }
+
+        if let Some(a) = self.attributes.get_mut(&file) {
+            a.size = text.len() as u64;
+            a.blocks = blocks(a.size);
+        }
+        if let Some(e) = self.emails.get_mut(&file) {
+            e.contents = Some(text.into_bytes());
+        }
+    }
+
+    /// Adds the .remailfs directory and the undo file in it.
+    fn add_control(&mut self, dir_attrs: &FileAttr) {
+        if self.inodes.contains_key(CONTROL) {
+            warn!("the server already has a mailbox called {}, not adding it", CONTROL);
+            return;
+        }
+
+        let dir = self.add_directory(CONTROL, "", 0, 1, dir_attrs);
+        self.control = Some(dir);
+
+        let path = format!("{}/{}", CONTROL, UNDO);
+        let inode = self.next_inode;
+        self.next_inode += 1;
+
+        let mut attrs = *dir_attrs;
+        attrs.ino = inode;
+        attrs.kind = FileType::RegularFile;
+        attrs.perm = 0o644;
+        attrs.nlink = 1;
+
+        self.inodes.insert(path.clone(), inode);
+        self.emails.insert(inode, Email::new(&path, dir, ""));
+        self.attributes.insert(inode, attrs);
+        self.mailboxes.get_mut(&dir).unwrap().add_content(inode);
+        self.undo_file = Some(inode);
+
+        self.update_undo();
+    }
+
+    /// The text of an email as it is read, rendered by the template. The
+    /// message is fetched the first time.
+    fn read_email(&mut self, ino: u64) -> Result<Vec<u8>, c_int> {
+        let email = match self.emails.get_mut(&ino) {
+            Some(e) => e,
+            None => return Err(ENOENT),
+        };
+        let mailbox = email.mailbox;
+
+        if email.contents.is_none() {
+            let (account, parent) = match self.mailboxes.get(&email.mailbox) {
+                Some(m) => (m.account, m.name.clone()),
+                None => return Err(ENOENT),
+            };
+
+            // the raw template shows attachments too
+            let backend = &mut self.accounts[account].backend;
+            let contents = if self.template.raw {
+                backend.fetch(&parent, &email.uid)
+            } else {
+                backend.fetch_text(&parent, &email.uid)
+            };
+
+            let contents = match contents {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(e.errno());
+                }
+            };
+
+            if self.mark_read && !self.read_only {
+                match backend.set_flags(&parent, &email.uid, &["\\Seen"], &[]) {
+                    Ok(()) => {
+                        if let Some(flags) = email.flags.as_mut() {
+                            flags.insert("\\Seen".to_string());
+                        }
+                    },
+                    Err(e) => warn!("{}", e),
+                }
+            }
+
+            email.contents = Some(contents);
+
+            if self.mark_read && !self.read_only {
+                self.update_views(mailbox);
+            }
+        }
+
+        let email = &self.emails[&ino];
+        let contents = email.contents.clone().unwrap_or_default();
+
+        let parsed = match mailparse::parse_mail(&contents) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("{}", e);
+                return Err(EIO);
+            }
+        };
+
+        let mut subject = None;
+
+        for header in parsed.headers.iter() {
+            let key = header.get_key().unwrap_or_default();
+
+            match key.as_str() {
+                "Subject" => subject = Some(render::header_value(header)),
+                // the times are already known for emails listed with one
+                "Date" if email.date.is_none() => {
+                    // Mon, 15 Apr 2019 17:49:15 -0500 (CDT)   
+                    let val = render::header_value(header);
+                    let tm = strptime(val.as_str(), "%a, %d %b %Y %H:%M:%S");
+                    match (tm, self.attributes.get_mut(&ino)) {
+                        (Ok(tm), Some(attr)) => {
+                            let tm = tm.to_timespec();
+                            attr.atime = tm;
+                            attr.mtime = tm;
+                            attr.ctime = tm;
+                            attr.crtime = tm;
+                            trace!(">>> FORMATTED TIME");
+                        },
+                        _ => trace!(">>> Unable to format time"),
                     }
-                    add_key_val(key.as_str(), val.as_str())
                 },
                 _ => (),
             }
         }
 
-        println!("Getting subparts...");
+        let text = if self.template.raw {
+            contents.clone()
+        } else {
+            render::encode(&render::render(&parsed, &self.template), self.charset)
+        };
+
+        if let Some(s) = subject {
+            self.set_subject(ino, &s);
+        }
+
+        // the size listed before was the size of the message on the server
+        if let Some(a) = self.attributes.get_mut(&ino) {
+            a.size = text.len() as u64;
+            a.blocks = blocks(a.size);
+        }
+
+        Ok(text)
+    }
+
+    /// Lists the journal in the undo file, the last operation first.
+    fn update_undo(&mut self) {
+        let file = match self.undo_file {
+            Some(f) => f,
+            None => return,
+        };
+
+        let mut text = String::new();
+        for (i, entry) in self.journal.iter().rev().enumerate() {
+            if self.accounts.len() > 1 {
+                text.push_str(&format!("{} {}: {}\n", i + 1, self.accounts[entry.account].name, entry.describe()));
+            } else {
+                text.push_str(&format!("{} {}\n", i + 1, entry.describe()));
+            }
+        }
+
+        if let Some(a) = self.attributes.get_mut(&file) {
+            a.size = text.len() as u64;
+            a.blocks = blocks(a.size);
+        }
+        if let Some(e) = self.emails.get_mut(&file) {
+            e.contents = Some(text.into_bytes());
+        }
+    }
+
+    /// Adds an operation to the journal, forgetting the oldest past
+    /// `undo::LIMIT` operations or `undo::MAX_BYTES` of messages.
+    fn record(&mut self, entry: undo::Entry) {
+        if entry.size() > undo::MAX_BYTES {
+            warn!("{} is too large to undo", entry.describe());
+            return;
+        }
+
+        self.journal.push(entry);
+        let mut size: usize = self.journal.iter().map(|e| e.size()).sum();
+        while self.journal.len() > undo::LIMIT || size > undo::MAX_BYTES {
+            size -= self.journal.remove(0).size();
+        }
+        self.update_undo();
+        self.save_journal();
+    }
+
+    /// Writes the journal to the state directory, if there is one.
+    fn save_journal(&self) {
+        if let Some(dir) = &self.state_dir {
+            let accounts: Vec<String> = self.accounts.iter().map(|a| a.name.clone()).collect();
+            if let Err(e) = undo::save(&self.journal, &accounts, dir) {
+                warn!("can't save the undo journal in {}: {}", dir.display(), e);
+            }
+        }
+    }
+
+    /// Reads the journal saved by an earlier mount, if there is one.
+    fn load_journal(&mut self) {
+        if let Some(dir) = &self.state_dir {
+            let accounts: Vec<String> = self.accounts.iter().map(|a| a.name.clone()).collect();
+            match undo::load(&accounts, dir) {
+                Ok(journal) => self.journal = journal,
+                Err(e) => warn!("can't read the undo journal in {}: {}", dir.display(), e),
+            }
+        }
+        self.update_undo();
+    }
+
+    /// The Message-ID of an email, from the cached message or the server.
+    fn email_message_id(&mut self, ino: u64, account: usize, mailbox: &str, uid: &str) -> Option<String> {
+        let header = match self.emails.get(&ino).and_then(|e| e.contents.clone()) {
+            Some(c) => c,
+            None => self.accounts[account].backend.summaries(mailbox, &[uid.to_string()])
+                .ok()?
+                .into_iter()
+                .next()?
+                .header?,
+        };
+
+        message_id(&header)
+    }
+
+    /// The uid a journalled email has in the mailbox it was moved to. Uids
+    /// change when emails move, so it is found by its Message-ID on servers
+    /// that can search. Those that can't keep the same id everywhere.
+    fn moved_uid(&mut self, mailbox: &str, entry: &undo::Entry) -> error::Result<String> {
+        let backend = &mut self.accounts[entry.account].backend;
+        if !backend.has_search() {
+            return Ok(entry.uid.clone());
+        }
+
+        let id = match &entry.message_id {
+            Some(i) => i.clone(),
+            None => return Err(error::Error::NotFound(format!("{}/{} has no Message-ID to find it by", entry.mailbox, entry.uid))),
+        };
+
+        backend.search(mailbox, &[Criterion::Header("Message-ID".to_string(), id.clone())])?
+            .pop()
+            .ok_or_else(|| error::Error::NotFound(format!("{} is no longer in {}", id, mailbox)))
+    }
+
+    /// Puts back what a journalled operation changed.
+    fn restore(&mut self, entry: &undo::Entry) -> error::Result<()> {
+        let account = entry.account;
+
+        let moved = match &entry.operation {
+            undo::Operation::Search(name, criteria) => {
+                let search = match self.accounts[account].search {
+                    Some(s) => s,
+                    None => return Ok(()),
+                };
+                if self.find_child(search, OsStr::new(name)).is_none() {
+                    self.add_search_directory(search, name, criteria.clone());
+                }
+                return Ok(());
+            },
+            undo::Operation::Deleted(message) => {
+                self.accounts[account].backend.append(&entry.mailbox, message)?;
+                None
+            },
+            undo::Operation::Trashed(from) | undo::Operation::Moved(from) => {
+                let uid = self.moved_uid(from, entry)?;
+                self.accounts[account].backend.move_to(from, &uid, &entry.mailbox)?;
+                Some(from.clone())
+            },
+        };
+
+        for name in moved.iter().chain(Some(&entry.mailbox)) {
+            if let Some(ino) = self.mailbox_inode(account, name) {
+                self.sync_mailbox(ino)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Undoes the last `count` operations, the newest first. Stops at the
+    /// first one that can't be undone, which stays in the journal.
+    fn undo(&mut self, count: usize) -> error::Result<()> {
+        for _ in 0..count {
+            let entry = match self.journal.pop() {
+                Some(e) => e,
+                None => break,
+            };
+
+            if let Err(e) = self.restore(&entry) {
+                self.journal.push(entry);
+                self.update_undo();
+                self.save_journal();
+                return Err(e);
+            }
+        }
+
+        self.update_undo();
+        self.save_journal();
+        Ok(())
+    }
+
+    /// Whether a mailbox is laid out in YYYY/MM/ directories.
+    fn is_dated(&self, mailbox: u64) -> bool {
+        if self.layout == Layout::Flat {
+            return false;
+        }
+
+        match self.mailboxes.get(&mailbox) {
+            Some(m) => self.accounts[m.account].backend.has_search(),
+            None => false,
+        }
+    }
+
+    /// Adds the month directories from `first` to `last` to a mailbox.
+    fn add_buckets(&mut self, mailbox: u64, first: (i32, u32), last: (i32, u32)) {
+        for year in first.0..=last.0 {
+            let from = if year == first.0 { first.1 } else { 1 };
+            let to = if year == last.0 { last.1 } else { 12 };
+
+            for month in from..=to {
+                self.bucket_dir(mailbox, year, month);
+            }
+        }
+    }
+
+    /// The YYYY/MM directory of a mailbox, made if it isn't there.
+    fn bucket_dir(&mut self, mailbox: u64, year: i32, month: u32) -> u64 {
+        let (path, account) = match self.mailboxes.get(&mailbox) {
+            Some(m) => (m.abs_path.clone(), m.account),
+            None => return mailbox,
+        };
+        let attrs = *self.attributes.get(&mailbox).unwrap();
+
+        let year_path = format!("{}/{:04}", path, year);
+        let year_ino = match self.inodes.get(&year_path) {
+            Some(i) => *i,
+            None => {
+                let i = self.add_directory(&year_path, "", account, mailbox, &attrs);
+                self.mailboxes.get_mut(&i).unwrap().bucket = Some(Bucket { mailbox, year, month: None, loaded: true });
+                i
+            },
+        };
+
+        let month_path = format!("{}/{:02}", year_path, month);
+        match self.inodes.get(&month_path) {
+            Some(i) => *i,
+            None => {
+                let i = self.add_directory(&month_path, "", account, year_ino, &attrs);
+                self.mailboxes.get_mut(&i).unwrap().bucket = Some(Bucket { mailbox, year, month: Some(month), loaded: false });
+                i
+            },
+        }
+    }
+
+    /// The directory an email goes in, its mailbox or the month it was
+    /// received or sent in.
+    fn email_dir(&mut self, mailbox: u64, summary: &Summary) -> u64 {
+        if !self.is_dated(mailbox) {
+            return mailbox;
+        }
+
+        let sent = summary.header.as_ref().and_then(|h| sent_time(h));
+        let secs = match self.layout {
+            Layout::Sent => sent.or(summary.received),
+            _ => summary.received.or(sent),
+        };
+
+        match secs {
+            Some(secs) => {
+                let tm = time::at(Timespec::new(secs, 0));
+                self.bucket_dir(mailbox, tm.tm_year + 1900, tm.tm_mon as u32 + 1)
+            },
+            None => mailbox,
+        }
+    }
+
+    /// Lists the emails of a month directory with SEARCH.
+    fn load_bucket(&mut self, dir: u64) -> error::Result<()> {
+        let bucket = match self.mailboxes.get(&dir).and_then(|m| m.bucket) {
+            Some(b) if b.month.is_some() => b,
+            _ => return Ok(()),
+        };
+        let (account, name) = match self.mailboxes.get(&bucket.mailbox) {
+            Some(m) => (m.account, m.name.clone()),
+            None => return Ok(()),
+        };
+
+        let criteria = bucket.criteria(self.layout == Layout::Sent);
+        let uids = self.accounts[account].backend.search(&name, &criteria)?;
+        let on_server: BTreeSet<&String> = uids.iter().collect();
+
+        let known = self.mailbox_uids(bucket.mailbox);
+        let contents = self.mailboxes.get(&dir).unwrap().contents.clone();
+        for (uid, ino) in known.iter() {
+            if contents.contains(ino) && !on_server.contains(uid) {
+                self.remove_copy(bucket.mailbox, *ino);
+            }
+        }
+
+        let unknown: Vec<String> = uids.iter()
+            .filter(|u| !known.contains_key(*u))
+            .cloned()
+            .collect();
+
+        if !unknown.is_empty() {
+            for summary in self.accounts[account].backend.summaries(&name, &unknown)? {
+                self.add_email_in(dir, bucket.mailbox, summary);
+            }
+        }
+
+        if let Some(b) = self.mailboxes.get_mut(&dir).and_then(|m| m.bucket.as_mut()) {
+            b.loaded = true;
+        }
+        self.update_views(bucket.mailbox);
+
+        Ok(())
+    }
+
+    /// Adds emails of a mailbox by uid, each in the directory it belongs in.
+    fn load_uids(&mut self, mailbox: u64, uids: &[String]) -> error::Result<()> {
+        let (account, name) = match self.mailboxes.get(&mailbox) {
+            Some(m) => (m.account, m.name.clone()),
+            None => return Ok(()),
+        };
+
+        for summary in self.accounts[account].backend.summaries(&name, uids)? {
+            self.add_email(mailbox, summary);
+        }
+
+        Ok(())
+    }
+
+    fn find_child(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        let name = name.to_str()?;
+        let path = self.child_path(parent, name)?;
+
+        if let Some(ino) = self.inodes.get(&path) {
+            return Some(*ino);
+        }
+
+        // emails linked into more than one directory are only known by the
+        // path in their own mailbox
+        self.mailboxes.get(&parent)?
+            .contents
+            .iter()
+            .find(|i| self.entry_name(**i) == Some(name))
+            .cloned()
+    }
+
+    /// The name of a file or directory, the last part of its path.
+    fn entry_name(&self, ino: u64) -> Option<&str> {
+        let path = match (self.emails.get(&ino), self.mailboxes.get(&ino)) {
+            (Some(e), _) => &e.abs_path,
+            (_, Some(m)) => &m.abs_path,
+            _ => return None,
+        };
+
+        path.rsplit('/').next()
+    }
+
+    /// Adds the flag views to a mailbox directory.
+    fn add_views(&mut self, mailbox: u64, dir_attrs: &FileAttr) {
+        let (path, account) = match self.mailboxes.get(&mailbox) {
+            Some(m) => (m.abs_path.clone(), m.account),
+            None => return,
+        };
+
+        for (name, view) in VIEWS.iter() {
+            let view_path = format!("{}/{}", path, name);
+            if self.inodes.contains_key(&view_path) {
+                continue;
+            }
+
+            let inode = self.add_directory(&view_path, "", account, mailbox, dir_attrs);
+            self.mailboxes.get_mut(&inode).unwrap().view = Some(*view);
+        }
+
+        self.update_views(mailbox);
+    }
+
+    /// Brings the flag views of a mailbox in line with the flags of its
+    /// emails.
+    fn update_views(&mut self, mailbox: u64) {
+        let contents = match self.mailboxes.get(&mailbox) {
+            Some(m) => m.contents.clone(),
+            None => return,
+        };
+
+        let views: Vec<(u64, View)> = contents.iter()
+            .filter_map(|i| Some((*i, self.mailboxes.get(i)?.view?)))
+            .collect();
+
+        // in a date layout the emails are in the month directories, and
+        // only some of them are known
+        let emails: Vec<(u64, Option<BTreeSet<String>>)> = self.mailbox_uids(mailbox)
+            .values()
+            .map(|i| (*i, self.emails.get(i).and_then(|e| e.flags.clone())))
+            .collect();
+
+        for (view_ino, view) in views {
+            let view_box = self.mailboxes.get_mut(&view_ino).unwrap();
+
+            for (ino, flags) in emails.iter() {
+                if flags.as_ref().map(|f| view.matches(f)).unwrap_or(false) {
+                    view_box.contents.insert(*ino);
+                } else {
+                    view_box.contents.remove(ino);
+                }
+            }
+        }
+
+        for (ino, _) in emails.iter() {
+            self.update_nlink(*ino);
+        }
+    }
+
+    /// Sets the link count of an email to the number of directories it is
+    /// listed in.
+    fn update_nlink(&mut self, ino: u64) {
+        let links = self.mailboxes.values()
+            .filter(|m| m.contents.contains(&ino))
+            .count();
+
+        if let Some(attrs) = self.attributes.get_mut(&ino) {
+            attrs.nlink = std::cmp::max(links, 1) as u32;
+        }
+    }
+
+    /// The attributes of an inode. A directory has a link for each of its
+    /// subdirectories' "..", and is as new as the newest email in it.
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let mut attrs = *self.attributes.get(&ino)?;
+        attrs.perm &= !self.umask;
+        if self.read_only {
+            attrs.perm &= !0o222;
+        }
 
-        for subpart in parsed.subparts.iter() {
-            if subpart.ctype.mimetype == "text/plain" {
-                add_key_val("Body", &subpart.get_body().unwrap());
-                //println!("BODY\n{}", subpart.get_body().unwrap());
+        if let Some(mailbox) = self.mailboxes.get(&ino) {
+            let subdirs = mailbox.contents.iter()
+                .filter(|i| self.mailboxes.contains_key(i))
+                .count();
+            attrs.nlink = 2 + subdirs as u32;
+
+            let newest = mailbox.contents.iter()
+                .filter(|i| self.emails.contains_key(i) && !self.statuses.contains_key(i))
+                .filter_map(|i| self.attributes.get(i))
+                .map(|a| a.mtime)
+                .max();
+            if let Some(mtime) = newest {
+                attrs.mtime = mtime;
+                attrs.ctime = mtime;
             }
-            //println!("{}", subpart.ctype.mimetype);
         }
-        //println!("{}", reply_text);
 
-        reply.data(reply_text.as_bytes());
+        Some(attrs)
+    }
+
+    fn child_path(&self, parent: u64, name: &str) -> Option<String> {
+        let mut abs_path = if parent != 1 {
+            self.mailboxes.get(&parent)?
+                .abs_path
+                .clone()
+        } else {
+            "".to_string()
+        };
+
+        if parent != 1 { abs_path.push('/') }; 
+
+        abs_path.push_str(name);
+        Some(abs_path)
+    }
+
+    /// The path of a mailbox directory below its account's root, as handed
+    /// to `Backend::create` and `Backend::rename`.
+    fn mailbox_components(&self, ino: u64) -> Option<Vec<String>> {
+        let mailbox = self.mailboxes.get(&ino)?;
+        let root = self.accounts.get(mailbox.account)?.root;
+
+        if ino == root {
+            return Some(Vec::new());
+        }
+
+        let path = if root == 1 {
+            mailbox.abs_path.as_str()
+        } else {
+            let root_path = &self.mailboxes.get(&root)?.abs_path;
+            mailbox.abs_path.get(root_path.len()+1..)?
+        };
+
+        Some(path.split('/').map(|c| c.to_string()).collect())
+    }
+
+    /// Renames a mailbox on the server and moves its directory, along with
+    /// everything below it, to the new place in the tree.
+    fn rename_mailbox(&mut self, ino: u64, newparent: u64, newname: &str) -> Result<(), c_int> {
+        let (account, name, old_path) = match self.mailboxes.get(&ino) {
+            Some(m) if !m.name.is_empty() => (m.account, m.name.clone(), m.abs_path.clone()),
+            _ => return Err(EACCES),
+        };
+
+        // mailboxes stay within their account
+        match self.mailboxes.get(&newparent) {
+            Some(p) if p.account == account && !self.is_outbox(newparent) => (),
+            _ => return Err(EXDEV),
+        }
+        if newparent == 1 && self.accounts.len() > 1 {
+            return Err(EXDEV);
+        }
+
+        if self.find_child(newparent, OsStr::new(newname)).is_some() {
+            return Err(EEXIST);
+        }
+
+        let new_path = self.child_path(newparent, newname).ok_or(ENOENT)?;
+        let old_prefix = format!("{}/", old_path);
+        if new_path.starts_with(&old_prefix) {
+            return Err(EINVAL);
+        }
+
+        let mut components = self.mailbox_components(newparent).ok_or(ENOENT)?;
+        components.push(newname.to_string());
+
+        let new_name = match self.accounts[account].backend.rename(&name, &components) {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("{}", e);
+                return Err(e.errno());
+            }
+        };
+
+        let relocate = |path: &str| -> Option<String> {
+            if path == old_path || path.starts_with(&old_prefix) {
+                Some(format!("{}{}", new_path, &path[old_path.len()..]))
+            } else {
+                None
+            }
+        };
+
+        let moved: Vec<(String, u64)> = self.inodes.iter()
+            .filter(|(p, _)| relocate(p).is_some())
+            .map(|(p, i)| (p.clone(), *i))
+            .collect();
+        for (path, i) in moved {
+            self.inodes.remove(&path);
+            self.inodes.insert(relocate(&path).unwrap(), i);
+        }
+
+        for mailbox in self.mailboxes.values_mut() {
+            if let Some(path) = relocate(&mailbox.abs_path) {
+                mailbox.abs_path = path;
+            }
+            mailbox.contents.remove(&ino);
+        }
+        for email in self.emails.values_mut() {
+            if let Some(path) = relocate(&email.abs_path) {
+                email.abs_path = path;
+            }
+        }
+
+        self.mailboxes.get_mut(&newparent).unwrap().add_content(ino);
+        self.mailboxes.get_mut(&ino).unwrap().name = new_name;
+
+        // the server renamed the mailboxes below this one too
+        if let Ok(listed) = self.accounts[account].backend.list() {
+            let root = self.accounts[account].root;
+            let prefix = if root == 1 {
+                "".to_string()
+            } else {
+                format!("{}/", self.mailboxes.get(&root).unwrap().abs_path)
+            };
+
+            for mb in listed.iter().filter(|mb| mb.selectable) {
+                let path = format!("{}{}", prefix, mb.path.join("/"));
+
+                if let Some(i) = self.inodes.get(&path) {
+                    if let Some(m) = self.mailboxes.get_mut(i) {
+                        m.name = mb.name.clone();
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Names an email after its subject, once. Emails without a subject
+    /// keep their uid, and one with the same subject as another gets its uid
+    /// added.
+    fn set_subject(&mut self, ino: u64, subject: &str) {
+        let (old_path, uid) = match self.emails.get(&ino) {
+            Some(e) if e.subject.is_none() => (e.abs_path.clone(), e.uid.clone()),
+            _ => return,
+        };
+
+        let dir = match old_path.rfind('/') {
+            Some(i) => &old_path[..i],
+            None => return,
+        };
+        let name = entry_name_for(subject);
+        let mut new_path = match &name {
+            Some(n) => format!("{}/{}", dir, n),
+            None => format!("{}/{}", dir, uid),
+        };
+        if self.inodes.get(&new_path).map(|i| *i != ino).unwrap_or(false) {
+            new_path = format!("{} ({})", new_path, uid);
+        }
+
+        if self.inodes.get(&old_path) == Some(&ino) {
+            self.inodes.remove(&old_path);
+        }
+        self.inodes.insert(new_path.clone(), ino);
+
+        if let Some(email) = self.emails.get_mut(&ino) {
+            email.abs_path = new_path;
+            email.set_subject(subject.to_string());
+        }
+    }
+
+    fn add_email(&mut self, mailbox: u64, summary: Summary) -> u64 {
+        let dir = self.email_dir(mailbox, &summary);
+        self.add_email_in(dir, mailbox, summary)
+    }
+
+    /// Adds an email of `mailbox` to the directory `dir`.
+    fn add_email_in(&mut self, dir: u64, mailbox: u64, summary: Summary) -> u64 {
+        // ids are only unique within an account
+        let account = self.mailboxes.get(&mailbox).map(|m| m.account).unwrap_or(0);
+
+        // another copy of a message we already have
+        if let Some(ino) = summary.id.as_ref().and_then(|id| self.message_ids.get(&(account, id.clone()))).cloned() {
+            if let Some(email) = self.emails.get_mut(&ino) {
+                if email.mailbox != mailbox && !email.copies.iter().any(|(m, _)| *m == mailbox) {
+                    email.copies.push((mailbox, summary.uid.clone()));
+                }
+            }
+
+            self.mailboxes.get_mut(&dir).unwrap().add_content(ino);
+            self.update_nlink(ino);
+            return ino;
+        }
+
+        let mut path = self.mailboxes.get(&dir)
+            .unwrap()
+            .abs_path
+            .clone();
+        let parent_attrs = *self.attributes.get(&dir).unwrap();
+        let u_inode = self.next_inode;
+
+        path.push('/');
+        path.push_str(summary.uid.as_str());
+        self.next_inode += 1;
+
+        let mut email = Email::new(&path, mailbox, &summary.uid);
+        email.flags = summary.flags.map(|f| f.into_iter().collect());
+
+        // when the server got the message, or failing that when it was sent
+        let sent = summary.header.as_ref().and_then(|h| sent_time(h));
+        let time = match summary.received.or(sent) {
+            Some(t) => {
+                email.set_date(time::at(Timespec::new(t, 0)).rfc822().to_string());
+                Timespec::new(t, 0)
+            },
+            None => parent_attrs.mtime,
+        };
+        let size = summary.size.unwrap_or(4096) as u64;
+        let readable = self.mailboxes.get(&mailbox)
+            .and_then(|m| m.rights.as_ref())
+            .map(|r| r.contains('r'))
+            .unwrap_or(true);
+
+        let email_attrs =  FileAttr {
+            ino: u_inode,
+            size,
+            blocks: blocks(size),
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind: FileType::RegularFile,
+            perm: if readable { 0o444 } else { 0 },
+            nlink: 1,
+            uid: parent_attrs.uid,
+            gid: parent_attrs.gid,
+            rdev: 0,
+            flags: 0,
+        };
+
+        if let Some(id) = summary.id {
+            self.message_ids.insert((account, id), u_inode);
+        }
+
+        self.inodes.insert(path.clone(), u_inode);
+        self.emails.insert(u_inode, email);
+        self.attributes.insert(u_inode, email_attrs);
+
+        self.mailboxes.get_mut(&dir)
+            .unwrap()
+            .add_content(u_inode);
+
+        // backends that hand out headers while listing let us name
+        // the email after its subject straight away
+        if let Some(header) = summary.header {
+            if let Ok((headers, _)) = mailparse::parse_headers(&header) {
+                if let Some(Ok(subject)) = headers.iter()
+                    .find(|h| h.get_key().ok() == Some("Subject".to_string()))
+                    .map(|h| h.get_value()) {
+                    self.set_subject(u_inode, &subject);
+                }
+            }
+        }
+
+        u_inode
+    }
+
+    /// Adds an empty message to an outbox, to be sent once it has been
+    /// written.
+    fn add_outgoing(&mut self, parent: u64, name: &OsStr, uid: u32, gid: u32) -> Result<u64, c_int> {
+        if !self.is_outbox(parent) {
+            return Err(EACCES);
+        }
+
+        if self.find_child(parent, name).is_some() {
+            return Err(EEXIST);
+        }
+
+        let path = match name.to_str().and_then(|n| self.child_path(parent, n)) {
+            Some(p) => p,
+            None => return Err(ENOENT),
+        };
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+
+        let now = time::now().to_timespec();
+        let attrs = FileAttr {
+            ino: inode,
+            size: 0,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+        };
+
+        self.inodes.insert(path.clone(), inode);
+        self.emails.insert(inode, Email::new(&path, parent, ""));
+        self.attributes.insert(inode, attrs);
+        self.outgoing.insert(inode, Vec::new());
+        if let Some(m) = self.mailboxes.get_mut(&parent) {
+            m.add_content(inode);
+        }
+
+        Ok(inode)
+    }
+
+    /// Sends an outgoing message and files a copy in the server's sent
+    /// mailbox. The message stays in the outbox if it can't be sent.
+    fn send_outgoing(&mut self, ino: u64) -> Result<(), c_int> {
+        let message = match self.outgoing.get(&ino) {
+            Some(m) if !m.is_empty() => m.clone(),
+            _ => return Ok(()),
+        };
+
+        let account = match self.emails.get(&ino).and_then(|e| self.mailboxes.get(&e.mailbox)) {
+            Some(m) => m.account,
+            None => return Err(ENOENT),
+        };
+        let smtp = match self.accounts[account].smtp.clone() {
+            Some(s) => s,
+            None => return Err(ENOTSUP),
+        };
+
+        let sent = smtp::envelope(&message)
+            .and_then(|(from, to, message)| {
+                smtp::send(&smtp, &from, &to, &message).map(|_| message)
+            });
+
+        let message = match sent {
+            Ok(m) => m,
+            Err(e) => {
+                error!("failed to send message: {}", e);
+                return Err(e.errno());
+            }
+        };
+
+        info!("sent message");
+        self.outgoing.remove(&ino);
+        self.remove_email(ino);
+
+        if let Some(sent_box) = self.accounts[account].backend.special_use("\\Sent") {
+            match self.accounts[account].backend.append(&sent_box, &message) {
+                Ok(_) => {
+                    if let Some(ino) = self.mailbox_inode(account, &sent_box) {
+                        let _ = self.sync_mailbox(ino);
+                    }
+                },
+                Err(e) => warn!("failed to save sent message: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove_email(&mut self, ino: u64) {
+        if self.emails.remove(&ino).is_some() {
+            for m in self.mailboxes.values_mut() {
+                m.contents.remove(&ino);
+            }
+        }
+
+        self.message_ids.retain(|_, i| *i != ino);
+        self.inodes.retain(|_, i| *i != ino);
+        self.attributes.remove(&ino);
+    }
+
+    /// Removes the copy of an email in one mailbox, and the email once it
+    /// is in no mailbox.
+    fn remove_copy(&mut self, mailbox: u64, ino: u64) {
+        let email = match self.emails.get_mut(&ino) {
+            Some(e) => e,
+            None => return,
+        };
+
+        if email.mailbox == mailbox {
+            if email.copies.is_empty() {
+                self.remove_email(ino);
+                return;
+            }
+
+            // another mailbox's copy becomes the one used to fetch it, the
+            // old path is found by scanning directories from now on
+            let (other, uid) = email.copies.remove(0);
+            email.mailbox = other;
+            email.uid = uid;
+            self.inodes.retain(|_, i| *i != ino);
+        } else {
+            email.copies.retain(|(m, _)| *m != mailbox);
+        }
+
+        let dirs: Vec<u64> = self.mailboxes.iter()
+            .filter(|(_, m)| m.contents.contains(&ino))
+            .map(|(d, _)| *d)
+            .filter(|d| self.dir_mailbox(*d) == Some(mailbox))
+            .collect();
+        for dir in dirs {
+            self.mailboxes.get_mut(&dir).unwrap().contents.remove(&ino);
+        }
+
+        self.update_nlink(ino);
+    }
+
+    /// uid -> email for the emails in a mailbox, copies included.
+    fn mailbox_uids(&self, mailbox: u64) -> BTreeMap<String, u64> {
+        let mut uids = BTreeMap::new();
+
+        for (ino, email) in self.emails.iter() {
+            if self.statuses.contains_key(ino) {
+                continue;
+            }
+            if email.mailbox == mailbox {
+                uids.insert(email.uid.clone(), *ino);
+            }
+            for (m, uid) in email.copies.iter() {
+                if *m == mailbox {
+                    uids.insert(uid.clone(), *ino);
+                }
+            }
+        }
+
+        uids
+    }
+
+    /// The (mailbox, uid) of an email as listed in a directory, its own copy
+    /// if the directory belongs to a mailbox that has one.
+    fn copy_in(&self, dir: u64, ino: u64) -> Option<(u64, String)> {
+        let email = self.emails.get(&ino)?;
+
+        if let Some(mailbox) = self.dir_mailbox(dir) {
+            if let Some((m, uid)) = email.copies.iter().find(|(m, _)| *m == mailbox) {
+                return Some((*m, uid.clone()));
+            }
+        }
+
+        Some((email.mailbox, email.uid.clone()))
+    }
+
+    /// The mailbox whose emails a directory lists: the mailbox itself, or
+    /// one of its months, views or conversations.
+    fn dir_mailbox(&self, dir: u64) -> Option<u64> {
+        let m = self.mailboxes.get(&dir)?;
+
+        if !m.name.is_empty() {
+            Some(dir)
+        } else if let Some(b) = m.bucket {
+            Some(b.mailbox)
+        } else if m.view.is_some() || m.threads {
+            Some(m.parent)
+        } else if self.is_thread(dir) {
+            self.mailboxes.get(&m.parent).map(|p| p.parent)
+        } else {
+            None
+        }
+    }
+
+    /// Brings the emails listed under a mailbox in line with the server.
+    fn sync_mailbox(&mut self, mailbox: u64) -> error::Result<()> {
+        let (account, name) = match self.mailboxes.get(&mailbox) {
+            Some(m) if !m.name.is_empty() => (m.account, m.name.clone()),
+            _ => return Ok(()),
+        };
+
+        // listing every email is what the date layout avoids, so only the
+        // months already looked at and the views are searched again
+        if self.is_dated(mailbox) {
+            let dirs: Vec<u64> = self.mailboxes.iter()
+                .filter(|(_, m)| match (m.bucket, m.view) {
+                    (Some(b), _) => b.mailbox == mailbox && b.loaded && b.month.is_some(),
+                    (None, Some(_)) => m.parent == mailbox,
+                    _ => false,
+                })
+                .map(|(i, _)| *i)
+                .collect();
+
+            for dir in dirs {
+                if self.mailboxes.get(&dir).map(|m| m.view.is_some()).unwrap_or(false) {
+                    self.run_search(dir)?;
+                } else {
+                    self.load_bucket(dir)?;
+                }
+            }
+            return Ok(());
+        }
+
+        let summaries = self.accounts[account].backend.messages(&name)?;
+        let known = self.mailbox_uids(mailbox);
+
+        let on_server: BTreeSet<String> = summaries.iter()
+            .map(|s| s.uid.clone())
+            .collect();
+
+        for (uid, ino) in known.iter() {
+            if !on_server.contains(uid) {
+                self.remove_copy(mailbox, *ino);
+            }
+        }
+
+        for summary in summaries {
+            match known.get(&summary.uid) {
+                Some(ino) => {
+                    if let Some(email) = self.emails.get_mut(ino) {
+                        email.flags = summary.flags.map(|f| f.into_iter().collect());
+                    }
+                },
+                None => {
+                    self.add_email(mailbox, summary);
+                },
+            }
+        }
+
+        self.update_views(mailbox);
+
+        Ok(())
+    }
+}
+
+impl Filesystem for REmailFS {
+    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+        debug!("Entered init!");
+
+        let (uid, gid) = (self.uid.unwrap_or(_req.uid()), self.gid.unwrap_or(_req.gid()));
+        self.build(uid, gid)?;
+        self.load_journal();
+
+        info!("REmailFS is ready to use!");
+        if let Some(mut ready) = self.ready.take() {
+            ready();
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self, _req: &Request) {
+        debug!("Entered destroy!");
+        self.logout();
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        debug!("statfs(ino = {})", _ino);
+
+        // accounts without a limit are shown as full
+        let (mut total_blocks, mut bfree, mut files, mut ffree) = (0, 0, 0, 0);
+        for account in 0..self.accounts.len() {
+            let quota = self.quota(account);
+            let used = blocks(quota.storage);
+            let total = std::cmp::max(quota.storage_limit.map(blocks).unwrap_or(used), used);
+            total_blocks += total;
+            bfree += total - used;
+
+            let limit = std::cmp::max(quota.message_limit.unwrap_or(quota.messages), quota.messages);
+            files += limit;
+            ffree += limit - quota.messages;
+        }
+
+        reply.statfs(total_blocks, bfree, bfree, files, ffree, 512, 255, 512);
+    }
+
+    fn getattr(&mut self, _req: &Request, _ino: u64, reply: ReplyAttr) {
+        debug!("getattr(ino={})", _ino);
+        self.check_reload();
+
+        // the size has to be right before the file is read
+        if self.statuses.contains_key(&_ino) {
+            if let Err(e) = self.refresh_status(_ino) {
+                warn!("{}", e);
+            }
+        }
+        let attrs = self.attr(_ino);
+        if let Some(a) = attrs {
+            let ttl = Timespec::new(1, 0);
+            reply.attr(&ttl, &a);
+        } else {
+            trace!("ENOENT in getattr");
+            reply.error(ENOENT);
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, mut reply: ReplyDirectory) {
+        debug!("readdir(ino = {}, fh = {})", _ino, _fh);
+        self.check_reload();
+
+        // flags change on the server, so views are refreshed when listed
+        let source = self.mailboxes.get(&_ino)
+            .filter(|m| m.view.is_some())
+            .map(|m| m.parent);
+        if let (Some(source), 0) = (source, _offset) {
+            let result = if self.is_dated(source) {
+                self.run_search(_ino)
+            } else {
+                self.sync_mailbox(source)
+            };
+            if let Err(e) = result {
+                warn!("{}", e);
+            }
+        }
+
+        // and so are saved searches and months
+        let search = self.mailboxes.get(&_ino).map(|m| m.search.is_some()).unwrap_or(false);
+        if search && _offset == 0 {
+            if let Err(e) = self.run_search(_ino) {
+                warn!("{}", e);
+            }
+        }
+
+        let threads = self.mailboxes.get(&_ino).map(|m| m.threads).unwrap_or(false);
+        if threads && _offset == 0 {
+            if let Err(e) = self.run_threads(_ino) {
+                warn!("{}", e);
+            }
+        }
+
+        let month = self.mailboxes.get(&_ino).and_then(|m| m.bucket).map(|b| b.month.is_some()).unwrap_or(false);
+        if month && _offset == 0 {
+            if let Err(e) = self.load_bucket(_ino) {
+                warn!("{}", e);
+            }
+        }
+
+        let mailbox = match self.mailboxes.get(&_ino) {
+            Some(m) => m,
+            None => {
+                trace!("ENOENT in readdir");
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if _offset == 0 {
+            let mut count = 2;
+            let mut contents: Vec<&u64> = mailbox.contents.iter().collect();
+
+            // conversations are listed oldest first
+            if mailbox.threads {
+                let attributes = &self.attributes;
+                contents.sort_by_key(|i| attributes.get(i).map(|a| a.mtime));
+            }
+
+            reply.add(_ino, 0, FileType::Directory, ".");
+            reply.add(mailbox.parent, 1, FileType::Directory, "..");
+
+            for inode in contents {
+                let rel_path = match self.entry_name(*inode) {
+                    Some(name) => name,
+                    None => continue,
+                };
+
+                let f_type = match self.attributes.get(inode) {
+                    Some(a) => a.kind,
+                    None => continue,
+                };
+
+                reply.add(*inode, 2+count as i64, f_type, rel_path); 
+                count += 1;
+            }
+
+            trace!("CONTENT COUNT = {}", count);
+
+            reply.ok();
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup(parent = {}, name = {:#?})", _parent, _name);
+        self.check_reload();
+
+        // a conversation may be looked up before .threads is listed
+        let threads = self.mailboxes.get(&_parent)
+            .map(|m| m.threads && m.contents.is_empty())
+            .unwrap_or(false);
+        if threads {
+            if let Err(e) = self.run_threads(_parent) {
+                warn!("{}", e);
+            }
+        }
+
+        let unloaded = self.mailboxes.get(&_parent)
+            .and_then(|m| m.bucket)
+            .map(|b| b.month.is_some() && !b.loaded)
+            .unwrap_or(false);
+        if unloaded {
+            if let Err(e) = self.load_bucket(_parent) {
+                warn!("{}", e);
+            }
+        }
+
+        let inode = match self.find_child(_parent, _name) {
+            Some(i) => i,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let attrs = self.attr(inode);
+
+        if let Some(a) = attrs {
+            let ttl = Timespec::new(1, 0);
+            reply.entry(&ttl, &a, 1);
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn read(&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: ReplyData) {
+        debug!("read(_ino = {})", _ino);
+
+        if let Some(data) = self.outgoing.get(&_ino) {
+            let start = std::cmp::min(_offset as usize, data.len());
+            let end = std::cmp::min(start + _size as usize, data.len());
+            reply.data(&data[start..end]);
+            return;
+        }
+
+        if self.statuses.contains_key(&_ino) || self.undo_file == Some(_ino) {
+            let data = self.emails.get(&_ino).and_then(|e| e.contents.clone()).unwrap_or_default();
+            let start = std::cmp::min(_offset as usize, data.len());
+            let end = std::cmp::min(start + _size as usize, data.len());
+            reply.data(&data[start..end]);
+            return;
+        }
+
+        let reply_text = match self.read_email(_ino) {
+            Ok(t) => t,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        let start = std::cmp::min(_offset as usize, reply_text.len());
+        let end = std::cmp::min(start + _size as usize, reply_text.len());
+        reply.data(&reply_text[start..end]);
+    }
+
+    fn unlink(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        debug!("unlink(parent = {}, name = {:#?})", _parent, _name);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let inode = self.find_child(_parent, _name);
+
+        if inode.is_some() && inode == self.undo_file {
+            reply.error(EACCES);
+            return;
+        }
+
+        if let Some(ino) = inode.filter(|_| self.is_outbox(_parent)) {
+            self.outgoing.remove(&ino);
+            self.remove_email(ino);
+            reply.ok();
+            return;
+        }
+
+        // the copy in the mailbox the directory belongs to, if the message is
+        // in more than one
+        let (inode, source, uid) = match inode.and_then(|i| self.copy_in(_parent, i).map(|(s, u)| (i, s, u))) {
+            Some(c) => c,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        // search results are whatever the server finds, and conversations
+        // are whatever the emails say
+        if self.mailboxes.get(&_parent).map(|m| m.search.is_some()).unwrap_or(false) || self.is_thread(_parent) || self.statuses.contains_key(&inode) {
+            reply.error(EACCES);
+            return;
+        }
+
+        // removing an email from a view clears the flag instead of deleting
+        // it, \Recent can't be cleared
+        if let Some(view) = self.mailboxes.get(&_parent).and_then(|m| m.view) {
+            let (add, remove): (&[&str], &[&str]) = match view {
+                View::Unread => (&["\\Seen"], &[]),
+                View::Flagged => (&[], &["\\Flagged"]),
+                View::Recent => {
+                    reply.error(EACCES);
+                    return;
+                },
+            };
+
+            let mailbox = match self.mailboxes.get(&source) {
+                Some(m) => m,
+                None => {
+                    reply.error(ENOENT);
+                    return;
+                }
+            };
+            if let Err(e) = self.accounts[mailbox.account].backend.set_flags(&mailbox.name, &uid, add, remove) {
+                error!("{}", e);
+                reply.error(e.errno());
+                return;
+            }
+
+            if let Err(e) = self.sync_mailbox(source) {
+                warn!("{}", e);
+            }
+
+            reply.ok();
+            return;
+        }
+        let (account, name) = match self.mailboxes.get(&source) {
+            Some(m) => (m.account, m.name.clone()),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        // emails go to the trash if there is one, otherwise the whole
+        // message is kept so it can be appended again, if the server can
+        let trash = self.accounts[account].backend.special_use("\\Trash").filter(|t| *t != name);
+        let (operation, id) = match trash {
+            Some(trash) => {
+                let id = self.email_message_id(inode, account, &name, &uid);
+                if let Err(e) = self.accounts[account].backend.move_to(&name, &uid, &trash) {
+                    error!("{}", e);
+                    reply.error(e.errno());
+                    return;
+                }
+                (Some(undo::Operation::Trashed(trash)), id)
+            },
+            None => {
+                // without append a deleted email could never be put back
+                let cached = self.emails.get(&inode)
+                    .and_then(|e| e.contents.clone())
+                    .filter(|_| self.template.raw);
+                let message = match cached {
+                    _ if !self.accounts[account].backend.has_append() => None,
+                    Some(c) => Some(c),
+                    None => match self.accounts[account].backend.fetch(&name, &uid) {
+                        Ok(m) => Some(m),
+                        Err(e) => {
+                            error!("{}", e);
+                            reply.error(e.errno());
+                            return;
+                        }
+                    },
+                };
+
+                if let Err(e) = self.accounts[account].backend.delete(&name, &uid) {
+                    error!("{}", e);
+                    reply.error(e.errno());
+                    return;
+                }
+                let id = message.as_ref().and_then(|m| message_id(m));
+                (message.map(undo::Operation::Deleted), id)
+            },
+        };
+
+        self.remove_copy(source, inode);
+
+        if let Some(undo::Operation::Trashed(trash)) = &operation {
+            if let Some(ino) = self.mailbox_inode(account, trash) {
+                if let Err(e) = self.sync_mailbox(ino) {
+                    warn!("{}", e);
+                }
+            }
+        }
+
+        if let Some(operation) = operation {
+            self.record(undo::Entry {
+                account,
+                mailbox: name,
+                uid,
+                message_id: id,
+                operation,
+            });
+        }
+
+        reply.ok();
+    }
+
+    fn rename(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, reply: ReplyEmpty) {
+        debug!("rename(parent = {}, name = {:#?}, newparent = {}, newname = {:#?})", _parent, _name, _newparent, _newname);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let inode = self.find_child(_parent, _name);
+
+        // renaming a directory renames the mailbox on the server
+        if let Some(ino) = inode.filter(|i| self.mailboxes.contains_key(i)) {
+            if self.is_virtual(ino) || self.is_virtual(_newparent) {
+                reply.error(EACCES);
+                return;
+            }
+
+            let result = match _newname.to_str() {
+                Some(n) => self.rename_mailbox(ino, _newparent, n),
+                None => Err(EINVAL),
+            };
+
+            match result {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+
+        // the email may be listed in a view, it moves from its own mailbox
+        let (inode, source, uid) = match inode.and_then(|i| self.copy_in(_parent, i).map(|(s, u)| (i, s, u))) {
+            Some(c) => c,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if self.statuses.contains_key(&inode) || self.undo_file == Some(inode) {
+            reply.error(EACCES);
+            return;
+        }
+
+        // only moving an email to a different mailbox means anything to the
+        // server, emails are named after their subject so they can't be
+        // renamed in place
+        if _parent == _newparent || source == _newparent || self.is_outbox(_parent) || self.is_outbox(_newparent) {
+            reply.error(EXDEV);
+            return;
+        }
+
+        let (account, from, to) = match (self.mailboxes.get(&source), self.mailboxes.get(&_newparent)) {
+            (Some(f), Some(t)) => {
+                // there is no moving emails between accounts
+                if f.account != t.account || t.name.is_empty() {
+                    reply.error(EXDEV);
+                    return;
+                }
+                (f.account, f.name.clone(), t.name.clone())
+            },
+            _ => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let id = self.email_message_id(inode, account, &from, &uid);
+
+        if let Err(e) = self.accounts[account].backend.move_to(&from, &uid, &to) {
+            error!("{}", e);
+            reply.error(e.errno());
+            return;
+        }
+
+        self.remove_copy(source, inode);
+
+        self.record(undo::Entry {
+            account,
+            mailbox: from,
+            uid,
+            message_id: id,
+            operation: undo::Operation::Moved(to),
+        });
+
+        if let Err(e) = self.sync_mailbox(_newparent) {
+            warn!("{}", e);
+        }
+
+        reply.ok();
+    }
+
+    fn mkdir(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        debug!("mkdir(parent = {}, name = {:#?})", _parent, _name);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        if self.find_child(_parent, _name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+
+        // a new directory in .search is a new saved search named by its query
+        if self.is_search(_parent) {
+            let (name, criteria) = match _name.to_str().and_then(|n| search::parse(n).map(|c| (n, c))) {
+                Some(c) => c,
+                None => {
+                    reply.error(EINVAL);
+                    return;
+                }
+            };
+
+            let inode = self.add_search_directory(_parent, name, criteria);
+
+            match self.attr(inode) {
+                Some(a) => reply.entry(&Timespec::new(1, 0), &a, 0),
+                None => reply.error(ENOENT),
+            }
+            return;
+        }
+
+        let account = match self.mailboxes.get(&_parent) {
+            Some(_) if self.is_virtual(_parent) || self.is_outbox(_parent) || (_parent == 1 && self.accounts.len() > 1) => {
+                reply.error(EACCES);
+                return;
+            },
+            Some(m) => m.account,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let (path, components) = match _name.to_str() {
+            Some(n) => match (self.child_path(_parent, n), self.mailbox_components(_parent)) {
+                (Some(p), Some(mut c)) => {
+                    c.push(n.to_string());
+                    (p, c)
+                },
+                _ => {
+                    reply.error(ENOENT);
+                    return;
+                }
+            },
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        let name = match self.accounts[account].backend.create(&components) {
+            Ok(n) => n,
+            Err(e) => {
+                error!("{}", e);
+                reply.error(e.errno());
+                return;
+            }
+        };
+
+        let attrs = match self.attributes.get(&_parent) {
+            Some(a) => *a,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let inode = self.add_directory(&path, &name, account, _parent, &attrs);
+
+        match self.attr(inode) {
+            Some(a) => reply.entry(&Timespec::new(1, 0), &a, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        debug!("rmdir(parent = {}, name = {:#?})", _parent, _name);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let inode = match self.find_child(_parent, _name) {
+            Some(i) => i,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        // only saved searches can be removed, that doesn't touch any mail
+        let (path, contents, account, criteria) = match self.mailboxes.get(&inode) {
+            Some(m) if m.search.is_some() => (m.abs_path.clone(), m.contents.clone(), m.account, m.search.clone().unwrap_or_default()),
+            _ => {
+                reply.error(ENOSYS);
+                return;
+            }
+        };
+
+        self.record(undo::Entry {
+            account,
+            mailbox: String::new(),
+            uid: String::new(),
+            message_id: None,
+            operation: undo::Operation::Search(_name.to_string_lossy().to_string(), criteria),
+        });
+
+        self.mailboxes.remove(&inode);
+        self.attributes.remove(&inode);
+        self.inodes.remove(&path);
+        if let Some(m) = self.mailboxes.get_mut(&_parent) {
+            m.contents.remove(&inode);
+        }
+
+        for email in contents {
+            self.update_nlink(email);
+        }
+
+        reply.ok();
+    }
+
+    fn create(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _flags: u32, reply: ReplyCreate) {
+        debug!("create(parent = {}, name = {:#?})", _parent, _name);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let inode = match self.add_outgoing(_parent, _name, self.uid.unwrap_or(_req.uid()), self.gid.unwrap_or(_req.gid())) {
+            Ok(i) => i,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        match self.attr(inode) {
+            Some(a) => reply.created(&Timespec::new(1, 0), &a, 0, 0, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn write(&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: i64, _data: &[u8], _flags: u32, reply: ReplyWrite) {
+        debug!("write(ino = {}, offset = {}, size = {})", _ino, _offset, _data.len());
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        // writing a number to the undo file undoes that many operations
+        if self.undo_file == Some(_ino) {
+            let count = match String::from_utf8_lossy(_data).trim() {
+                "" => Some(1),
+                n => n.parse::<usize>().ok(),
+            };
+
+            match count.map(|c| self.undo(c)) {
+                Some(Ok(())) => reply.written(_data.len() as u32),
+                Some(Err(e)) => {
+                    error!("{}", e);
+                    reply.error(e.errno());
+                },
+                None => reply.error(EINVAL),
+            }
+            return;
+        }
+
+        let data = match self.outgoing.get_mut(&_ino) {
+            Some(d) => d,
+            None => {
+                reply.error(EACCES);
+                return;
+            }
+        };
+
+        let end = _offset as usize + _data.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[_offset as usize..end].copy_from_slice(_data);
+
+        if let Some(a) = self.attributes.get_mut(&_ino) {
+            a.size = data.len() as u64;
+            a.blocks = blocks(a.size);
+        }
+
+        reply.written(_data.len() as u32);
+    }
+
+    fn setattr(&mut self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+        debug!("setattr(ino = {})", _ino);
+
+        let changes = _mode.is_some() || _uid.is_some() || _gid.is_some() || _size.is_some()
+            || _atime.is_some() || _mtime.is_some() || _crtime.is_some() || _chgtime.is_some()
+            || _bkuptime.is_some() || _flags.is_some();
+        if self.read_only && changes {
+            reply.error(EROFS);
+            return;
+        }
+
+        // only the size of messages waiting in the outbox can change
+        if let (Some(size), Some(data)) = (_size, self.outgoing.get_mut(&_ino)) {
+            data.resize(size as usize, 0);
+            if let Some(a) = self.attributes.get_mut(&_ino) {
+                a.size = size;
+                a.blocks = blocks(size);
+            }
+        }
+
+        match self.attr(_ino) {
+            Some(a) => {
+                let ttl = Timespec::new(1, 0);
+                reply.attr(&ttl, &a);
+            },
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+        debug!("getxattr(ino = {}, name = {:#?})", _ino, _name);
+
+        let (account, mailbox, uid) = match self.labelled(_ino) {
+            Some(l) if _name == LABELS => l,
+            _ => {
+                reply.error(ENODATA);
+                return;
+            }
+        };
+
+        let labels = match self.accounts[account].backend.labels(&mailbox, &uid) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("{}", e);
+                reply.error(e.errno());
+                return;
+            }
+        };
+
+        let value = labels.join("\n");
+        if _size == 0 {
+            reply.size(value.len() as u32);
+        } else if (_size as usize) < value.len() {
+            reply.error(ERANGE);
+        } else {
+            reply.data(value.as_bytes());
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, _ino: u64, _size: u32, reply: ReplyXattr) {
+        debug!("listxattr(ino = {})", _ino);
+
+        let names = match self.labelled(_ino) {
+            Some(_) => format!("{}\0", LABELS),
+            None => String::new(),
+        };
+
+        if _size == 0 {
+            reply.size(names.len() as u32);
+        } else if (_size as usize) < names.len() {
+            reply.error(ERANGE);
+        } else {
+            reply.data(names.as_bytes());
+        }
+    }
+
+    /// Sets the labels of an email to a list with one label per line, or
+    /// adds and removes the labels in a list like "+Work\n-Travel". Labels
+    /// can have commas in them but not line breaks.
+    fn setxattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
+        debug!("setxattr(ino = {}, name = {:#?})", _ino, _name);
+
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let (account, mailbox, uid) = match self.labelled(_ino) {
+            Some(l) if _name == LABELS => l,
+            _ => {
+                reply.error(ENOTSUP);
+                return;
+            }
+        };
+
+        let value = match std::str::from_utf8(_value) {
+            Ok(v) => v,
+            Err(_) => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        let wanted: Vec<&str> = value.lines()
+            .filter(|l| !l.is_empty())
+            .collect();
+
+        let (add, remove): (Vec<String>, Vec<String>) = if !wanted.is_empty() && wanted.iter().all(|l| l.starts_with('+') || l.starts_with('-')) {
+            (wanted.iter().filter(|l| l.starts_with('+')).map(|l| l[1..].to_string()).collect(),
+             wanted.iter().filter(|l| l.starts_with('-')).map(|l| l[1..].to_string()).collect())
+        } else {
+            let current = match self.accounts[account].backend.labels(&mailbox, &uid) {
+                Ok(l) => l,
+                Err(e) => {
+                    error!("{}", e);
+                    reply.error(e.errno());
+                    return;
+                }
+            };
+
+            (wanted.iter().filter(|l| !current.iter().any(|c| c == *l)).map(|l| l.to_string()).collect(),
+             current.iter().filter(|c| !wanted.contains(&c.as_str())).cloned().collect())
+        };
+
+        if let Err(e) = self.accounts[account].backend.set_labels(&mailbox, &uid, &add, &remove) {
+            error!("{}", e);
+            reply.error(e.errno());
+            return;
+        }
+
+        // labels are mailboxes on Gmail, so those have changed too
+        for label in add.iter().chain(remove.iter()) {
+            let name = if label.eq_ignore_ascii_case("\\Inbox") {
+                "INBOX".to_string()
+            } else {
+                utf7::encode(label)
+            };
+
+            if let Some(ino) = self.mailbox_inode(account, &name) {
+                if let Err(e) = self.sync_mailbox(ino) {
+                    warn!("{}", e);
+                }
+            }
+        }
+
+        reply.ok();
+    }
+
+    fn removexattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr(ino = {}, name = {:#?})", _ino, _name);
+        reply.error(if self.read_only { EROFS } else { ENOSYS });
+    }
+
+    fn mknod(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _rdev: u32, reply: ReplyEntry) {
+        debug!("mknod(parent = {}, name = {:#?})", _parent, _name);
+        reply.error(if self.read_only { EROFS } else { ENOSYS });
+    }
+
+    fn symlink(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _link: &std::path::Path, reply: ReplyEntry) {
+        debug!("symlink(parent = {}, name = {:#?})", _parent, _name);
+        reply.error(if self.read_only { EROFS } else { ENOSYS });
+    }
+
+    fn link(&mut self, _req: &Request, _ino: u64, _newparent: u64, _newname: &OsStr, reply: ReplyEntry) {
+        debug!("link(ino = {}, newparent = {}, newname = {:#?})", _ino, _newparent, _newname);
+        reply.error(if self.read_only { EROFS } else { ENOSYS });
+    }
+
+    /// Sends a message written into the outbox once the file is closed.
+    fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        debug!("flush(ino = {})", _ino);
+
+        match self.send_outgoing(_ino) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::ffi::OsStr;
+    use std::sync::{Arc, Mutex};
+
+    use super::{entry_name_for, thread, undo, IMAPMailbox, REmailFS};
+    use crate::backend::{Backend, MailboxName, Summary, Thread};
+    use crate::error::{Error, Result};
+    use crate::render::{self, Template};
+    use crate::smtp::SmtpConfig;
+    use crate::smtp::tests::{sink, Sink};
+
+    // mailbox -> (uid, message)
+    type Store = Arc<Mutex<BTreeMap<String, Vec<(String, Vec<u8>)>>>>;
+
+    /// A backend that keeps its mailboxes in memory.
+    struct Mock {
+        store: Store,
+        next_uid: u32,
+    }
+
+    impl Backend for Mock {
+        fn list(&mut self) -> Result<Vec<MailboxName>> {
+            Ok(self.store.lock().unwrap().keys()
+                .map(|n| MailboxName::new(n, n.split('/').map(|p| p.to_string()).collect()))
+                .collect())
+        }
+
+        fn examine(&mut self, mailbox: &str) -> Result<IMAPMailbox> {
+            match self.store.lock().unwrap().get(mailbox) {
+                Some(m) => Ok(IMAPMailbox { exists: m.len() as u32, ..IMAPMailbox::default() }),
+                None => Err(Error::NotFound(mailbox.to_string())),
+            }
+        }
+
+        fn messages(&mut self, mailbox: &str) -> Result<Vec<Summary>> {
+            let store = self.store.lock().unwrap();
+            let emails = store.get(mailbox).ok_or_else(|| Error::NotFound(mailbox.to_string()))?;
+            Ok(emails.iter()
+                .map(|(uid, m)| {
+                    let mut summary = Summary::new(uid.clone());
+                    summary.size = Some(m.len() as u32);
+                    summary.header = Some(m.clone());
+                    summary
+                })
+                .collect())
+        }
+
+        fn fetch(&mut self, mailbox: &str, uid: &str) -> Result<Vec<u8>> {
+            self.store.lock().unwrap().get(mailbox)
+                .and_then(|m| m.iter().find(|(u, _)| u == uid))
+                .map(|(_, m)| m.clone())
+                .ok_or_else(|| Error::NotFound(uid.to_string()))
+        }
+
+        fn delete(&mut self, mailbox: &str, uid: &str) -> Result<()> {
+            if let Some(m) = self.store.lock().unwrap().get_mut(mailbox) {
+                m.retain(|(u, _)| u != uid);
+            }
+            Ok(())
+        }
+
+        fn append(&mut self, mailbox: &str, content: &[u8]) -> Result<()> {
+            let mut store = self.store.lock().unwrap();
+            let emails = store.get_mut(mailbox).ok_or_else(|| Error::NotFound(mailbox.to_string()))?;
+            emails.push((self.next_uid.to_string(), content.to_vec()));
+            self.next_uid += 1;
+            Ok(())
+        }
+
+        fn has_threads(&self) -> bool {
+            true
+        }
+
+        fn threads(&mut self, mailbox: &str) -> Result<Vec<Thread>> {
+            let store = self.store.lock().unwrap();
+            let emails = store.get(mailbox).ok_or_else(|| Error::NotFound(mailbox.to_string()))?;
+            Ok(thread::thread(emails))
+        }
+
+        fn special_use(&self, attribute: &str) -> Option<String> {
+            match attribute {
+                "\\Sent" if self.store.lock().unwrap().contains_key("Sent") => Some("Sent".to_string()),
+                _ => None,
+            }
+        }
+
+        fn logout(&mut self) {}
+    }
+
+    /// A writable mount of one account with `mailboxes`, all empty.
+    fn mount(mailboxes: &[&str], smtp: Option<SmtpConfig>) -> (REmailFS, Store) {
+        let store: Store = Arc::new(Mutex::new(mailboxes.iter().map(|m| (m.to_string(), Vec::new())).collect()));
+
+        let mut fs = REmailFS::new();
+        fs.set_read_only(false);
+        fs.add_account("test", Box::new(Mock { store: store.clone(), next_uid: 1 }), smtp);
+        fs.build(1000, 1000).unwrap();
+        (fs, store)
+    }
+
+    const MESSAGE: &[u8] = b"From: alice@example.com\r\nTo: bob@example.com\r\nBcc: carol@example.com\r\nSubject: Hi\r\n\r\nHello\r\n";
+
+    #[test]
+    fn outbox() {
+        let (config, server) = sink(Sink { reject: Some("carol@"), ..Sink::default() });
+        let (mut fs, store) = mount(&["INBOX", "Sent"], Some(config));
+
+        let outbox = fs.accounts[0].outbox.unwrap();
+        let ino = fs.add_outgoing(outbox, OsStr::new("hello.eml"), 1000, 1000).unwrap();
+        fs.outgoing.insert(ino, MESSAGE.to_vec());
+
+        // a refused message stays in the outbox to be sent again
+        assert!(fs.send_outgoing(ino).is_err());
+        assert!(!server.join().unwrap().iter().any(|l| l == "DATA"));
+        assert_eq!(fs.find_child(outbox, OsStr::new("hello.eml")), Some(ino));
+        assert_eq!(fs.outgoing.get(&ino).map(|m| m.as_slice()), Some(MESSAGE));
+        assert!(store.lock().unwrap()["Sent"].is_empty());
+
+        let (config, server) = sink(Sink::default());
+        fs.accounts[0].smtp = Some(config);
+        fs.send_outgoing(ino).unwrap();
+
+        let lines = server.join().unwrap();
+        assert!(lines.contains(&"RCPT TO:<carol@example.com>".to_string()));
+        assert!(!lines.iter().any(|l| l.starts_with("Bcc")));
+        assert_eq!(fs.find_child(outbox, OsStr::new("hello.eml")), None);
+        assert!(!fs.outgoing.contains_key(&ino));
+
+        let store = store.lock().unwrap();
+        assert_eq!(store["Sent"].len(), 1);
+        assert_eq!(store["Sent"][0].1, &b"From: alice@example.com\r\nTo: bob@example.com\r\nSubject: Hi\r\n\r\nHello\r\n"[..]);
+    }
+
+    fn deleted(uid: usize, size: usize) -> undo::Entry {
+        undo::Entry {
+            account: 0,
+            mailbox: "INBOX".to_string(),
+            uid: uid.to_string(),
+            message_id: None,
+            operation: undo::Operation::Deleted(vec![b'x'; size]),
+        }
+    }
+
+    #[test]
+    fn journal_limits() {
+        let mut fs = REmailFS::new();
+
+        for uid in 0..undo::LIMIT + 10 {
+            fs.record(deleted(uid, 10));
+        }
+        assert_eq!(fs.journal.len(), undo::LIMIT);
+        assert_eq!(fs.journal[0].uid, "10");
+
+        // the bytes kept count too, the oldest go first
+        for uid in 0..4 {
+            fs.record(deleted(1000 + uid, undo::MAX_BYTES / 2));
+        }
+        assert_eq!(fs.journal.last().map(|e| e.uid.as_str()), Some("1003"));
+        assert_eq!(fs.journal.iter().map(|e| e.uid.as_str()).collect::<Vec<_>>(), vec!["1002", "1003"]);
+
+        // a message that could never fit isn't journalled at all
+        fs.record(deleted(2000, undo::MAX_BYTES + 1));
+        assert_eq!(fs.journal.last().map(|e| e.uid.as_str()), Some("1003"));
+    }
+
+    #[test]
+    fn journal_saved() {
+        let dir = std::env::temp_dir().join(format!("remailfs-journal-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let (mut fs, _) = mount(&["INBOX"], None);
+        fs.set_state_dir(&dir);
+        fs.record(undo::Entry { operation: undo::Operation::Deleted(MESSAGE.to_vec()), ..deleted(7, 0) });
+
+        // the next mount can still undo it
+        let (mut fs, store) = mount(&["INBOX"], None);
+        fs.set_state_dir(&dir);
+        fs.load_journal();
+        let undo_file = fs.undo_file.unwrap();
+        assert_eq!(fs.emails[&undo_file].contents.as_deref(), Some(&b"1 deleted INBOX/7\n"[..]));
+
+        fs.undo(1).unwrap();
+        assert_eq!(store.lock().unwrap()["INBOX"], vec![("1".to_string(), MESSAGE.to_vec())]);
+        assert_eq!(std::fs::read_to_string(dir.join("journal.json")).unwrap(), "[]");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn message_ids() {
+        let mut fs = REmailFS::new();
+        for name in &["work", "home"] {
+            let store: Store = Arc::new(Mutex::new(["INBOX", "All Mail"].iter().map(|m| (m.to_string(), Vec::new())).collect()));
+            fs.add_account(name, Box::new(Mock { store, next_uid: 1 }), None);
+        }
+        fs.build(1000, 1000).unwrap();
+
+        let summary = |uid: &str| Summary { id: Some("1234".to_string()), ..Summary::new(uid.to_string()) };
+        let work = fs.add_email(fs.mailbox_inode(0, "INBOX").unwrap(), summary("1"));
+        let all_mail = fs.add_email(fs.mailbox_inode(0, "All Mail").unwrap(), summary("7"));
+        let home = fs.add_email(fs.mailbox_inode(1, "INBOX").unwrap(), summary("1"));
+
+        // the same message in two mailboxes of an account, but two messages
+        // that happen to have the same id in different accounts
+        assert_eq!(work, all_mail);
+        assert_eq!(fs.emails[&work].copies, vec![(fs.mailbox_inode(0, "All Mail").unwrap(), "7".to_string())]);
+        assert_ne!(work, home);
+        assert!(fs.emails[&home].copies.is_empty());
+    }
+
+    #[test]
+    fn subject_names() {
+        assert_eq!(entry_name_for("  Re: a/b  "), Some("Re: a\u{2215}b".to_string()));
+        assert_eq!(entry_name_for("one\0two\r\n three"), Some("one two   three".to_string()));
+        assert_eq!(entry_name_for(" \t "), None);
+        assert_eq!(entry_name_for(".."), None);
+        assert_eq!(entry_name_for(&"é".repeat(200)).map(|n| n.len()), Some(200));
+    }
+
+    #[test]
+    fn email_names() {
+        let (mut fs, store) = mount(&["INBOX"], None);
+        {
+            let mut store = store.lock().unwrap();
+            let inbox = store.get_mut("INBOX").unwrap();
+            for (uid, header) in &[("1", "Subject: Hello\r\n"), ("2", "Subject: Hello\r\n"), ("3", "Subject: 1/2\r\n"),
+                                   ("4", "Subject:\r\n"), ("5", "From: bob@example.com\r\n")] {
+                inbox.push((uid.to_string(), format!("{}\r\nbody\r\n", header).into_bytes()));
+            }
+        }
+        let inbox = fs.mailbox_inode(0, "INBOX").unwrap();
+        fs.sync_mailbox(inbox).unwrap();
+
+        let mut names: Vec<&str> = fs.mailboxes[&inbox].contents.iter()
+            .filter(|i| fs.emails.contains_key(i) && !fs.statuses.contains_key(i))
+            .filter_map(|i| fs.entry_name(*i))
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["1\u{2215}2", "4", "5", "Hello", "Hello (2)"]);
+
+        // the uid paths they had before are gone
+        let hello = fs.find_child(inbox, OsStr::new("Hello")).unwrap();
+        assert_eq!(fs.find_child(inbox, OsStr::new("1")), None);
+        assert_eq!(fs.find_child(inbox, OsStr::new("2")), None);
+        assert!(fs.find_child(inbox, OsStr::new("Hello (2)")).is_some());
+
+        // named once, reading it doesn't rename it again
+        fs.set_subject(hello, "Something else");
+        assert_eq!(fs.entry_name(hello), Some("Hello"));
+    }
+
+    #[test]
+    fn thread_names() {
+        let (mut fs, store) = mount(&["INBOX"], None);
+        {
+            let mut store = store.lock().unwrap();
+            let inbox = store.get_mut("INBOX").unwrap();
+            let long = format!("Subject: {}\r\n", "x".repeat(300));
+            for (uid, header) in &[("1", "Subject: ..\r\n"), ("2", "Subject: a/b\x01c\r\n"), ("3", long.as_str()),
+                                   ("4", "Subject:  \r\n"), ("5", "Subject: a/b\x01c\r\n")] {
+                inbox.push((uid.to_string(), format!("Message-ID: <{}@example.com>\r\n{}\r\nbody\r\n", uid, header).into_bytes()));
+            }
+        }
+        let inbox = fs.mailbox_inode(0, "INBOX").unwrap();
+        fs.sync_mailbox(inbox).unwrap();
+        let threads = fs.find_child(inbox, OsStr::new(".threads")).unwrap();
+        fs.run_threads(threads).unwrap();
+
+        let mut names: Vec<String> = fs.mailboxes[&threads].contents.iter()
+            .filter_map(|i| fs.mailboxes.get(i))
+            .map(|m| m.abs_path.rsplit('/').next().unwrap().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec![
+            "(no subject)".to_string(),
+            "(no subject) (2)".to_string(),
+            "a\u{2215}b c".to_string(),
+            "a\u{2215}b c (2)".to_string(),
+            "x".repeat(200),
+        ]);
+    }
+
+    /// An email as it is read with the template, and the name of its file.
+    fn read_message(template: Template, message: &[u8]) -> (Vec<u8>, String) {
+        let (mut fs, store) = mount(&["INBOX"], None);
+        fs.set_template(template);
+        store.lock().unwrap().get_mut("INBOX").unwrap().push(("1".to_string(), message.to_vec()));
+        let inbox = fs.mailbox_inode(0, "INBOX").unwrap();
+        fs.sync_mailbox(inbox).unwrap();
+
+        let ino = *fs.mailboxes[&inbox].contents.iter()
+            .find(|i| fs.emails.get(i).map(|e| e.uid == "1").unwrap_or(false))
+            .unwrap();
+        let text = fs.read_email(ino).unwrap();
+        (text, fs.entry_name(ino).unwrap().to_string())
+    }
+
+    #[test]
+    fn decoded_headers() {
+        // encoded-words, and raw 8-bit headers in Latin-1 and UTF-8
+        let message = b"Subject: =?utf-8?q?Caf=C3=A9?= au =?ISO-8859-1?B?bGFpdA==?=\r\n\
+                        From: =?iso-8859-1?q?J=F6rg?= <jorg@example.com>\r\n\
+                        To: Ren\xe9 <rene@example.com>\r\n\
+                        Cc: Zo\xc3\xab <zoe@example.com>\r\n\
+                        Date: Mon, 15 Jan 2024 12:00:00 +0000\r\n\r\nHi\r\n";
+
+        let (text, name) = read_message(Template::default(), message);
+        assert_eq!(String::from_utf8(text).unwrap(), "Subject: Caf\u{e9} au lait\n\
+                                                      From: J\u{f6}rg <jorg@example.com>\n\
+                                                      To: Ren\u{e9} <rene@example.com>\n\
+                                                      Cc: Zo\u{eb} <zoe@example.com>\n\
+                                                      Date: Mon, 15 Jan 2024 12:00:00 +0000\n\nHi\n");
+        assert_eq!(name, "Caf\u{e9} au lait");
+    }
+
+    #[test]
+    fn body_charsets() {
+        let body_only = Template { headers: Some(Vec::new()), ..Template::default() };
+        let body = |message: &[u8]| String::from_utf8(read_message(body_only.clone(), message).0).unwrap();
+
+        assert_eq!(body(b"Content-Type: text/plain; charset=iso-8859-1\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\ncaf=E9\r\n"), "\ncaf\u{e9}\n");
+        assert_eq!(body(b"Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\nY2Fmw6k=\r\n"), "\ncaf\u{e9}\n");
+        assert_eq!(body(b"Content-Transfer-Encoding: Quoted-Printable\r\n\r\nsoft=\r\nbreak =3D=\nx=ZZ=\r\n"), "\nsoftbreak =x=ZZ\n");
+        assert_eq!(body(b"Content-Type: text/plain; charset=shift_jis\r\n\r\n\x93\xfa\x96\x7b\r\n"), "\n\u{65e5}\u{672c}\n");
+
+        // without a charset, or with us-ascii or one that isn't known, UTF-8
+        // is tried first and Windows-1252 used when it doesn't decode
+        assert_eq!(body(b"\r\ncaf\xc3\xa9\r\n"), "\ncaf\u{e9}\n");
+        assert_eq!(body(b"\r\n\x80 5 caf\xe9\r\n"), "\n\u{20ac} 5 caf\u{e9}\n");
+        assert_eq!(body(b"Content-Type: text/plain; charset=us-ascii\r\n\r\ncaf\xe9\r\n"), "\ncaf\u{e9}\n");
+        assert_eq!(body(b"Content-Type: text/plain; charset=x-unknown\r\n\r\ncaf\xc3\xa9\r\n"), "\ncaf\u{e9}\n");
+
+        // and the files are in the charset of the mount
+        let (mut fs, store) = mount(&["INBOX"], None);
+        fs.set_template(body_only);
+        assert!(fs.set_charset("latin1"));
+        store.lock().unwrap().get_mut("INBOX").unwrap().push(("1".to_string(), b"\r\ncaf\xc3\xa9 \xe2\x82\xac\r\n".to_vec()));
+        let inbox = fs.mailbox_inode(0, "INBOX").unwrap();
+        fs.sync_mailbox(inbox).unwrap();
+        let ino = *fs.mailboxes[&inbox].contents.iter().find(|i| fs.emails.get(i).map(|e| e.uid == "1").unwrap_or(false)).unwrap();
+        assert_eq!(fs.read_email(ino).unwrap(), b"\ncaf\xe9 \x80\n");
+    }
+
+    #[test]
+    fn presets() {
+        let message = b"Message-ID: <1@example.com>\r\n\
+                        Date: Mon, 15 Jan 2024 12:00:00 +0000\r\n\
+                        From: alice@example.com\r\n\
+                        To: bob@example.com\r\n\
+                        Subject: Hi\r\n\
+                        List-Id: <news.example.com>\r\n\r\nHello\r\n";
+        let read = |preset: &str| read_message(Template::preset(preset).unwrap(), message).0;
+
+        assert_eq!(String::from_utf8(read("default")).unwrap(), "Subject: Hi\nFrom: alice@example.com\nTo: bob@example.com\n\
+                                                                 Date: Mon, 15 Jan 2024 12:00:00 +0000\n\nHello\n");
+        assert_eq!(String::from_utf8(read("full-headers")).unwrap(), "Message-ID: <1@example.com>\nDate: Mon, 15 Jan 2024 12:00:00 +0000\n\
+                                                                      From: alice@example.com\nTo: bob@example.com\nSubject: Hi\n\
+                                                                      List-Id: <news.example.com>\n\nHello\n");
+        assert_eq!(read("raw"), message.to_vec());
+
+        // the date is in local time, which is the same day anywhere for noon UTC
+        let minimal = String::from_utf8(read("minimal")).unwrap();
+        let lines: Vec<&str> = minimal.lines().collect();
+        assert_eq!(lines[..2], ["Subject: Hi", "From: alice@example.com"]);
+        assert!(lines[2].starts_with("Date: 2024-01-15 ") && lines[2].len() == "Date: 2024-01-15 12:00".len(), "{}", lines[2]);
+        assert_eq!(lines[3..], ["", "Hello"]);
+
+        assert!(Template::preset("fancy").is_none());
+    }
+
+    #[test]
+    fn wrap() {
+        let long = "word ".repeat(20);
+        let plain = format!("Subject: Plain\r\n\r\n{}\r\n", long.trim_end());
+        let html = format!("Subject: HTML\r\nContent-Type: text/html\r\n\r\n<p>{}</p>\r\n", long.trim_end());
+        let body = |message: &str, template: &Template| {
+            let parsed = mailparse::parse_mail(message.as_bytes()).unwrap();
+            let text = render::render(&parsed, template);
+            text.split_once("\n\n").unwrap().1.lines().map(|l| l.len()).collect::<Vec<_>>()
+        };
+
+        // plain text is left alone and HTML is wrapped at 72 unless a width is set
+        let mut template = Template::default();
+        assert_eq!(body(&plain, &template), vec![99]);
+        assert_eq!(body(&html, &template), vec![69, 29]);
+
+        template.wrap = Some(40);
+        assert_eq!(body(&plain, &template), vec![39, 39, 19]);
+        assert_eq!(body(&html, &template), vec![39, 39, 19]);
+
+        template.wrap = Some(0);
+        assert_eq!(body(&html, &template), vec![99]);
     }
 }
@@ -0,0 +1,200 @@
+// Where log lines go and which are kept. Filters are written like RUST_LOG,
+// "warn,remailfs::backend=debug", the most specific target wins.
+
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::{LevelFilter, Log, Metadata, Record};
+
+static REDACT: AtomicBool = AtomicBool::new(true);
+
+// commands whose arguments are credentials
+const SECRET_COMMANDS: &[&str] = &["LOGIN", "AUTHENTICATE", "AUTH", "PASS", "APOP"];
+
+// commands followed by SASL responses, which are lines of their own
+const SASL_COMMANDS: &[&str] = &["AUTHENTICATE", "AUTH"];
+
+thread_local! {
+    // whether the last command started a SASL exchange on this thread
+    static SASL: Cell<bool> = const { Cell::new(false) };
+}
+
+struct Logger {
+    level: LevelFilter,
+    // (target, level) with the longest targets first
+    targets: Vec<(String, LevelFilter)>,
+    file: Option<Mutex<File>>,
+}
+
+impl Logger {
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets.iter()
+            .find(|(t, _)| target == t || target.starts_with(&format!("{}::", t)))
+            .map(|(_, l)| *l)
+            .unwrap_or(self.level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let now = time::now();
+        let line = format!("{} {:<5} {}: {}\n",
+                           now.strftime("%Y-%m-%d %H:%M:%S").map(|t| t.to_string()).unwrap_or_default(),
+                           record.level(), record.target(), record.args());
+
+        match &self.file {
+            Some(file) => if let Ok(mut f) = file.lock() {
+                let _ = f.write_all(line.as_bytes());
+            },
+            None => {
+                let _ = io::stderr().write_all(line.as_bytes());
+            },
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(Ok(mut f)) = self.file.as_ref().map(|f| f.lock()) {
+            let _ = f.flush();
+        }
+    }
+}
+
+/// Parses a filter, "level", "target=level" or "target" for every level of
+/// the target, separated by commas.
+pub fn parse_filter(filter: &str) -> Option<(LevelFilter, Vec<(String, LevelFilter)>)> {
+    let mut level = LevelFilter::Warn;
+    let mut targets = Vec::new();
+
+    for part in filter.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let mut split = part.splitn(2, '=');
+        match (split.next().unwrap_or(""), split.next()) {
+            (target, Some(l)) => targets.push((target.to_string(), LevelFilter::from_str(l).ok()?)),
+            (l, None) => match LevelFilter::from_str(l) {
+                Ok(l) => level = l,
+                Err(_) => targets.push((l.to_string(), LevelFilter::Trace)),
+            },
+        }
+    }
+
+    targets.sort_by_key(|t| std::cmp::Reverse(t.0.len()));
+    Some((level, targets))
+}
+
+/// Sends log lines passing `filter` to stderr, or appended to `path`.
+pub fn init(filter: &str, path: Option<&str>, redact: bool) -> io::Result<()> {
+    let (level, targets) = parse_filter(filter)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid log filter {}", filter)))?;
+
+    let file = match path {
+        Some(p) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(p)?)),
+        None => None,
+    };
+
+    let max = targets.iter().map(|(_, l)| *l).fold(level, std::cmp::max);
+    let logger = Box::new(Logger { level, targets, file });
+
+    REDACT.store(redact, Ordering::Relaxed);
+    log::set_logger(Box::leak(logger))
+        .map_err(|_| io::Error::other("logging is already set up"))?;
+    log::set_max_level(max);
+    Ok(())
+}
+
+/// A command as it can be logged, the arguments of logins hidden. The
+/// responses after an AUTH or AUTHENTICATE are one word each, so every
+/// line without a space after one is hidden too, until the next command.
+pub fn command(line: &str) -> String {
+    if !REDACT.load(Ordering::Relaxed) {
+        return line.to_string();
+    }
+
+    let line = line.trim_end();
+    if !line.contains(' ') && SASL.with(|s| s.get()) {
+        return "***".to_string();
+    }
+    SASL.with(|s| s.set(false));
+
+    // IMAP commands start with a tag
+    let words: Vec<&str> = line.splitn(3, ' ').collect();
+    for (i, word) in words.iter().enumerate().take(2) {
+        if SECRET_COMMANDS.iter().any(|c| word.eq_ignore_ascii_case(c)) {
+            let sasl = SASL_COMMANDS.iter().any(|c| word.eq_ignore_ascii_case(c));
+            SASL.with(|s| s.set(sasl));
+            return format!("{} ***", words[..i+1].join(" "));
+        }
+    }
+
+    line.to_string()
+}
+
+/// Message data as it can be logged, only its length unless asked for.
+pub fn body(data: &[u8]) -> String {
+    if REDACT.load(Ordering::Relaxed) {
+        format!("[{} bytes]", data.len())
+    } else {
+        String::from_utf8_lossy(data).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::LevelFilter;
+
+    use super::{command, parse_filter};
+
+    #[test]
+    fn filters() {
+        assert_eq!(parse_filter(""), Some((LevelFilter::Warn, vec![])));
+        assert_eq!(parse_filter("debug"), Some((LevelFilter::Debug, vec![])));
+        assert_eq!(parse_filter("info, remailfs::backend=trace ,remailfs=error"), Some((LevelFilter::Info, vec![
+            ("remailfs::backend".to_string(), LevelFilter::Trace),
+            ("remailfs".to_string(), LevelFilter::Error),
+        ])));
+        assert_eq!(parse_filter("remailfs::smtp"), Some((LevelFilter::Warn, vec![
+            ("remailfs::smtp".to_string(), LevelFilter::Trace),
+        ])));
+        assert_eq!(parse_filter("remailfs=loud"), None);
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(command("a1 SELECT INBOX"), "a1 SELECT INBOX");
+        assert_eq!(command("a2 LOGIN alice secret"), "a2 LOGIN ***");
+        assert_eq!(command("USER alice"), "USER alice");
+        assert_eq!(command("PASS secret"), "PASS ***");
+        assert_eq!(command("APOP alice 1b2c"), "APOP ***");
+        assert_eq!(command("STAT"), "STAT");
+        assert_eq!(command("AUTH PLAIN AGFsaWNlAHNlY3JldA=="), "AUTH ***");
+        assert_eq!(command("MAIL FROM:<alice@example.com>"), "MAIL FROM:<alice@example.com>");
+    }
+
+    #[test]
+    fn sasl_responses() {
+        // the response follows a "+" or "334" continuation on a line of its own
+        assert_eq!(command("AUTH PLAIN"), "AUTH ***");
+        assert_eq!(command("AGFsaWNlAHNlY3JldA=="), "***");
+        assert_eq!(command("MAIL FROM:<alice@example.com>"), "MAIL FROM:<alice@example.com>");
+
+        assert_eq!(command("AUTH LOGIN"), "AUTH ***");
+        assert_eq!(command("YWxpY2U=\r\n"), "***");
+        assert_eq!(command("c2VjcmV0"), "***");
+        // a command of one word can't be told apart from a response
+        assert_eq!(command("QUIT"), "***");
+
+        assert_eq!(command("a1 AUTHENTICATE PLAIN"), "a1 AUTHENTICATE ***");
+        assert_eq!(command("AGFsaWNlAHNlY3JldA=="), "***");
+        assert_eq!(command("a2 SELECT INBOX"), "a2 SELECT INBOX");
+        assert_eq!(command("NOOP"), "NOOP");
+    }
+}
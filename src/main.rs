@@ -1,36 +1,126 @@
+#[macro_use]
+extern crate log;
+
+mod daemon;
 mod setup;
 
 use std::env;
+use std::fs;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::thread;
+use libc::SIGHUP;
 use setup::Config;
 
 //use fuse;
 
 fn main() {
-    // parse the command line arguments 
+    // parse the command line arguments
     // the config object should contain the filesystem
-    // the mountpoint and 
-    
-    let config = Config::new(env::args());
+    // the mountpoint and
+
+    let args: Vec<String> = env::args().collect();
+    let config = Config::new(&args);
 
-    let config = match config {
+    let mut config = match config {
         Some(c) => c,
         None => {
             println!("failed to configure...");
             return
         },
     };
-    
-    // parse command line arguments 
+
+    // the daemon runs from /, so every path has to be absolute first
+    let mountpoint = match fs::canonicalize(&config.mountpoint) {
+        Ok(p) => p.to_string_lossy().to_string(),
+        Err(e) => {
+            eprintln!("unable to mount {}: {}", config.mountpoint, e);
+            process::exit(1);
+        },
+    };
+    let pidfile = config.pidfile.as_ref().map(|p| match env::current_dir() {
+        Ok(dir) => dir.join(p).to_string_lossy().to_string(),
+        Err(_) => p.clone(),
+    });
+
+    let mut ready = if config.foreground {
+        None
+    } else {
+        match daemon::daemonize() {
+            Ok(r) => Some(r),
+            Err(e) => {
+                eprintln!("unable to run in the background: {}", e);
+                process::exit(1);
+            },
+        }
+    };
+    config.filesystem.on_ready(move || {
+        daemon::notify(&format!("READY=1\nMAINPID={}", process::id()));
+        if let Some(mut r) = ready.take() {
+            let _ = r.write_all(b"1");
+        }
+    });
+
+    if let Some(p) = &pidfile {
+        if let Err(e) = fs::write(p, format!("{}\n", process::id())) {
+            error!("unable to write {}: {}", p, e);
+        }
+    }
+
+    // SIGINT and SIGTERM unmount, which ends the session below, and SIGHUP
+    // reads the configuration again and logs in again here, the mount takes
+    // the result up the next time it is looked at
+    let signals = daemon::block_signals();
+    let reload = config.filesystem.reload_slot();
+    let config_path = config.config.clone();
+    let watched = mountpoint.clone();
+    thread::spawn(move || loop {
+        match daemon::wait(&signals) {
+            SIGHUP => {
+                daemon::notify("RELOADING=1");
+                match Config::reload(&args, config_path.as_deref()) {
+                    Some(fs) => if let Ok(mut r) = reload.lock() {
+                        *r = Some(fs);
+                    },
+                    None => warn!("keeping the old configuration"),
+                }
+                daemon::notify("READY=1");
+            },
+            signal => {
+                info!("unmounting {} on signal {}", watched, signal);
+                daemon::notify("STOPPING=1");
+                daemon::unmount(&watched);
+                break;
+            },
+        }
+    });
+
+    // parse command line arguments
     // setup the filesystem
     // mount the file system
 
-    let result = fuse::mount(
-        config.filesystem,
-        &config.mountpoint,
-        &[]
-    );
+    let options: Vec<&OsStr> = config.options.iter()
+        .flat_map(|o| vec![OsStr::new("-o"), OsStr::new(o)])
+        .collect();
+
+    let result = fuse::Session::new(config.filesystem, Path::new(&mountpoint), &options)
+        .and_then(|mut session| {
+            let result = session.run();
+            // the kernel only sends destroy for block device mounts
+            if !session.destroyed {
+                session.filesystem.logout();
+            }
+            result
+        });
+
+    if let Some(p) = &pidfile {
+        let _ = fs::remove_file(p);
+    }
 
-    if result.is_err() {
-        eprintln!("{:?}", result);
+    if let Err(e) = result {
+        error!("unable to mount {}: {}", mountpoint, e);
+        process::exit(1);
     }
 }
@@ -0,0 +1,130 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use native_tls::{TlsConnector, TlsStream};
+
+use crate::error::{Error, Result};
+
+// seconds to wait for a connection or an answer, 0 waits forever
+static TIMEOUT: AtomicU64 = AtomicU64::new(60);
+
+/// Sets how long connecting to a server, and every read and write after,
+/// may take before failing with `Error::Timeout`. 0 waits forever.
+pub fn set_timeout(seconds: u64) {
+    TIMEOUT.store(seconds, Ordering::Relaxed);
+}
+
+/// Connects to a server with the timeout set, trying each of its addresses
+/// in turn.
+pub fn tcp(domain: &str, port: u16) -> Result<TcpStream> {
+    let timeout = match TIMEOUT.load(Ordering::Relaxed) {
+        0 => None,
+        s => Some(Duration::from_secs(s)),
+    };
+
+    let mut error = Error::Connection(format!("{} has no address", domain));
+    for addr in (domain, port).to_socket_addrs()? {
+        let connected = match timeout {
+            Some(t) => TcpStream::connect_timeout(&addr, t),
+            None => TcpStream::connect(addr),
+        };
+
+        match connected {
+            Ok(tcp) => {
+                tcp.set_read_timeout(timeout)?;
+                tcp.set_write_timeout(timeout)?;
+                return Ok(tcp);
+            },
+            Err(e) => error = Error::from(e),
+        }
+    }
+
+    Err(error)
+}
+
+/// A connection to a mail server that may or may not be wrapped in TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Stream {
+    pub fn connect(domain: &str, port: u16, tls: bool) -> Result<Stream> {
+        let tcp = tcp(domain, port)?;
+
+        if tls {
+            let connector = TlsConnector::builder().build()?;
+            Ok(Stream::Tls(connector.connect(domain, tcp)?))
+        } else {
+            Ok(Stream::Plain(tcp))
+        }
+    }
+
+    /// Upgrades a plain connection to TLS, as done after STARTTLS.
+    pub fn starttls(self, domain: &str) -> Result<Stream> {
+        match self {
+            Stream::Plain(tcp) => {
+                let connector = TlsConnector::builder().build()?;
+                Ok(Stream::Tls(connector.connect(domain, tcp)?))
+            },
+            tls => Ok(tls),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    use super::{set_timeout, Stream};
+    use crate::error::Error;
+
+    #[test]
+    fn read_timeout() {
+        // a server that accepts and never says anything
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        set_timeout(1);
+        let stream = Stream::connect("127.0.0.1", port, false);
+        set_timeout(60);
+
+        let start = Instant::now();
+        let mut buf = [0; 16];
+        match stream.unwrap().read(&mut buf).map_err(Error::from) {
+            Err(Error::Timeout) => (),
+            r => panic!("expected a timeout, got {:?}", r),
+        }
+        assert!(start.elapsed().as_secs() < 5);
+        drop(listener);
+    }
+}
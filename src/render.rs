@@ -0,0 +1,283 @@
+// Turns a raw message into the text shown when an email file is read.
+
+use encoding::{DecoderTrap, EncoderTrap, EncodingRef};
+use encoding::all::{UTF_8, WINDOWS_1252};
+use encoding::label::encoding_from_whatwg_label;
+use mailparse::{DispositionType, MailHeader, ParsedMail};
+
+use crate::html;
+
+/// The column HTML converted to text is wrapped at when the template doesn't
+/// set one. HTML paragraphs are single lines, unlike plain text bodies.
+pub const HTML_WRAP: usize = 72;
+
+/// Which alternative of a multipart/alternative is shown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Body {
+    Plain,
+    Html,
+}
+
+/// How an email is laid out when it is read.
+#[derive(Clone, Debug)]
+pub struct Template {
+    // headers shown above the body in this order, None for every header in
+    // the order the message has them
+    pub headers: Option<Vec<String>>,
+    // strftime format for the Date header, None to show it as sent
+    pub date_format: Option<String>,
+    pub body: Body,
+    // column to wrap the body at, 0 to leave lines alone, None to wrap only
+    // text converted from HTML, at HTML_WRAP
+    pub wrap: Option<usize>,
+    // show the message exactly as the server has it
+    pub raw: bool,
+}
+
+impl Template {
+    /// The built-in templates: `default`, `minimal`, `full-headers` and
+    /// `raw`.
+    pub fn preset(name: &str) -> Option<Template> {
+        let mut template = Template::default();
+
+        match name {
+            "default" => (),
+            "minimal" => {
+                template.headers = Some(vec!["Subject".to_string(), "From".to_string(), "Date".to_string()]);
+                template.date_format = Some("%Y-%m-%d %H:%M".to_string());
+            },
+            "full-headers" => template.headers = None,
+            "raw" => template.raw = true,
+            _ => return None,
+        }
+
+        Some(template)
+    }
+}
+
+impl Default for Template {
+    fn default() -> Template {
+        Template {
+            headers: Some(["Subject", "From", "To", "Cc", "Date"].iter().map(|h| h.to_string()).collect()),
+            date_format: None,
+            body: Body::Plain,
+            wrap: None,
+            raw: false,
+        }
+    }
+}
+
+/// The value of a header with encoded-words decoded. mailparse reads raw
+/// header bytes as Latin-1, but unencoded 8-bit headers are nearly always
+/// UTF-8 (RFC 6532), so those are read again as UTF-8 when they decode.
+pub fn header_value(header: &MailHeader) -> String {
+    // mailparse leaves the \r of a CRLF line ending on the value
+    let value = header.get_value().unwrap_or_default().trim_end().to_string();
+
+    if value.is_ascii() || value.chars().any(|c| c as u32 > 0xff) {
+        return value;
+    }
+
+    let bytes: Vec<u8> = value.chars().map(|c| c as u8).collect();
+    match String::from_utf8(bytes) {
+        Ok(v) => v,
+        Err(_) => value,
+    }
+}
+
+/// Undoes quoted-printable (RFC 2045 section 6.7), anything that isn't a
+/// valid escape is kept as it is.
+fn quoted_printable(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut i = 0;
+
+    while i < body.len() {
+        let rest = &body[i+1..];
+        let digit = |j: usize| rest.get(j).and_then(|c| (*c as char).to_digit(16));
+
+        if body[i] != b'=' {
+            out.push(body[i]);
+            i += 1;
+        } else if rest.starts_with(b"\r\n") {
+            // a soft line break
+            i += 3;
+        } else if rest.starts_with(b"\n") {
+            i += 2;
+        } else if let (Some(high), Some(low)) = (digit(0), digit(1)) {
+            out.push((high * 16 + low) as u8);
+            i += 3;
+        } else {
+            out.push(b'=');
+            i += 1;
+        }
+    }
+
+    out
+}
+
+/// The body of a part with its transfer encoding undone. mailparse only
+/// knows the encoding when nothing follows its name, and the \r of a CRLF
+/// line ending does, so it is undone here when mailparse didn't.
+fn body_bytes(part: &ParsedMail) -> Vec<u8> {
+    let raw = part.get_body_raw().unwrap_or_default();
+
+    let encoding = match part.headers.iter()
+        .find(|h| h.get_key().map(|k| k.eq_ignore_ascii_case("Content-Transfer-Encoding")).unwrap_or(false)) {
+        Some(h) => h.get_value().unwrap_or_default(),
+        None => return raw,
+    };
+    if encoding.trim_end() == encoding {
+        return raw;
+    }
+
+    match encoding.trim().to_lowercase().as_str() {
+        "base64" => {
+            let cleaned: Vec<u8> = raw.iter().filter(|c| !c.is_ascii_whitespace()).cloned().collect();
+            base64::decode(&cleaned).unwrap_or(raw)
+        },
+        "quoted-printable" => quoted_printable(&raw),
+        _ => raw,
+    }
+}
+
+/// The decoded text of a single part. Parts without a charset, or with one
+/// that isn't recognised, are read as UTF-8 if they can be and Windows-1252
+/// otherwise.
+pub fn body_text(part: &ParsedMail) -> String {
+    let raw = body_bytes(part);
+
+    // us-ascii is also what mailparse assumes when there is no charset, and
+    // is often wrong about 8-bit bodies
+    let declared = match part.ctype.charset.to_lowercase().as_str() {
+        "us-ascii" | "ascii" | "" => None,
+        c => encoding_from_whatwg_label(c),
+    };
+
+    let charset = match declared {
+        Some(c) => c,
+        None if String::from_utf8(raw.clone()).is_ok() => UTF_8 as EncodingRef,
+        None => WINDOWS_1252 as EncodingRef,
+    };
+
+    charset.decode(&raw, DecoderTrap::Replace)
+        .unwrap_or_else(|_| String::from_utf8_lossy(&raw).to_string())
+}
+
+fn is_attachment(part: &ParsedMail) -> bool {
+    part.get_content_disposition()
+        .map(|d| d.disposition == DispositionType::Attachment)
+        .unwrap_or(false)
+}
+
+/// The parts whose text makes up the body, found by walking the multipart
+/// tree. Of the alternatives in a multipart/alternative the one of the
+/// preferred type is used, otherwise the last one.
+fn text_parts<'a>(part: &'a ParsedMail<'a>, prefer: Body) -> Vec<&'a ParsedMail<'a>> {
+    let mimetype = part.ctype.mimetype.to_lowercase();
+
+    if mimetype == "multipart/alternative" {
+        let alternatives: Vec<Vec<&ParsedMail>> = part.subparts.iter()
+            .map(|p| text_parts(p, prefer))
+            .filter(|p| !p.is_empty())
+            .collect();
+
+        let preferred = match prefer {
+            Body::Plain => "text/plain",
+            Body::Html => "text/html",
+        };
+        let found = alternatives.iter()
+            .find(|a| a.iter().any(|p| p.ctype.mimetype.eq_ignore_ascii_case(preferred)));
+
+        return match found.or(alternatives.last()) {
+            Some(a) => a.clone(),
+            None => Vec::new(),
+        };
+    }
+
+    if mimetype.starts_with("multipart/") {
+        // multipart/related holds the body first and the images it uses
+        // after, those have no text so walking them all is harmless
+        return part.subparts.iter()
+            .flat_map(|p| text_parts(p, prefer))
+            .collect();
+    }
+
+    if (mimetype == "text/plain" || mimetype == "text/html") && !is_attachment(part) {
+        return vec![part];
+    }
+
+    Vec::new()
+}
+
+/// Formats a Date header with the template's format, falling back to the
+/// header as sent if it can't be parsed.
+fn format_date(value: &str, format: &str) -> String {
+    match mailparse::dateparse(value) {
+        Ok(secs) => {
+            let tm = time::at(time::Timespec::new(secs, 0));
+            match time::strftime(format, &tm) {
+                Ok(d) => d,
+                Err(_) => value.to_string(),
+            }
+        },
+        Err(_) => value.to_string(),
+    }
+}
+
+/// Renders the headers and the text parts of a message as laid out by the
+/// template, with HTML converted to plain text.
+pub fn render(parsed: &ParsedMail, template: &Template) -> String {
+    let mut text = String::new();
+    let mut add_key_val = |k: &str, v: &str| {
+        text.push_str(k);
+        text.push_str(": ");
+        text.push_str(v);
+        text.push('\n');
+    };
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    match &template.headers {
+        Some(keys) => {
+            for key in keys.iter() {
+                for header in parsed.headers.iter() {
+                    if header.get_key().map(|k| k.eq_ignore_ascii_case(key)).unwrap_or(false) {
+                        headers.push((key.clone(), header_value(header)));
+                    }
+                }
+            }
+        },
+        None => {
+            for header in parsed.headers.iter() {
+                headers.push((header.get_key().unwrap_or_default(), header_value(header)));
+            }
+        },
+    }
+
+    for (key, val) in headers.iter() {
+        match &template.date_format {
+            Some(format) if key.eq_ignore_ascii_case("Date") => add_key_val(key, &format_date(val, format)),
+            _ => add_key_val(key, val),
+        }
+    }
+
+    for part in text_parts(parsed, template.body) {
+        text.push('\n');
+
+        if part.ctype.mimetype.eq_ignore_ascii_case("text/html") {
+            text.push_str(&html::to_text(&body_text(part), template.wrap.unwrap_or(HTML_WRAP)));
+        } else {
+            for line in body_text(part).lines() {
+                text.push_str(&html::wrap(line, template.wrap.unwrap_or(0)));
+                text.push('\n');
+            }
+        }
+    }
+
+    text
+}
+
+/// Converts the rendered text to the charset files are presented in.
+pub fn encode(text: &str, charset: EncodingRef) -> Vec<u8> {
+    charset.encode(text, EncoderTrap::Replace)
+        .unwrap_or_else(|_| text.as_bytes().to_vec())
+}
@@ -0,0 +1,241 @@
+// Saved searches, written as "from:alice since:2024-01-01 is:unread" and
+// turned into IMAP SEARCH criteria.
+
+use crate::backend::imap_raw::quote;
+
+pub const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Criterion {
+    From(String),
+    To(String),
+    Cc(String),
+    Subject(String),
+    Body(String),
+    Text(String),
+    // dates are (year, month, day)
+    Since(u32, u32, u32),
+    Before(u32, u32, u32),
+    On(u32, u32, u32),
+    // the same by the Date header rather than when the server got it
+    SentSince(u32, u32, u32),
+    SentBefore(u32, u32, u32),
+    Larger(u32),
+    Smaller(u32),
+    // a header containing a value, (name, value)
+    Header(String, String),
+    // a flag the message must have (true) or not have (false)
+    Flag(String, bool),
+    Not(Box<Criterion>),
+}
+
+/// Splits a query into words, keeping "quoted phrases" together.
+fn words(query: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(word.clone());
+                    word.clear();
+                }
+            },
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+/// Parses YYYY-MM-DD.
+fn date(value: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = value.splitn(3, '-').map(|p| p.parse::<u32>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some((year, month, day))
+}
+
+/// Parses a size such as 500, 20k or 2M, None if it doesn't fit in 32 bits.
+fn size(value: &str) -> Option<u32> {
+    let lower = value.to_lowercase();
+    let (number, multiplier) = if lower.ends_with('k') {
+        (&lower[..lower.len()-1], 1024)
+    } else if lower.ends_with('m') {
+        (&lower[..lower.len()-1], 1024 * 1024)
+    } else {
+        (&lower[..], 1)
+    };
+
+    number.parse::<u32>().ok()?.checked_mul(multiplier)
+}
+
+fn flag(value: &str) -> Option<Criterion> {
+    let (flag, set) = match value.to_lowercase().as_str() {
+        "unread" | "unseen" => ("\\Seen", false),
+        "read" | "seen" => ("\\Seen", true),
+        "flagged" | "starred" => ("\\Flagged", true),
+        "unflagged" => ("\\Flagged", false),
+        "answered" | "replied" => ("\\Answered", true),
+        "unanswered" => ("\\Answered", false),
+        "draft" => ("\\Draft", true),
+        "recent" => ("\\Recent", true),
+        _ => return None,
+    };
+
+    Some(Criterion::Flag(flag.to_string(), set))
+}
+
+/// Parses a query. Words without a key search the whole message, a leading
+/// "-" negates a word. Returns None if a key or value isn't understood.
+pub fn parse(query: &str) -> Option<Vec<Criterion>> {
+    let mut criteria = Vec::new();
+
+    for word in words(query) {
+        let (negate, word) = if word.starts_with('-') && word.len() > 1 {
+            (true, &word[1..])
+        } else {
+            (false, &word[..])
+        };
+
+        let criterion = match word.find(':') {
+            Some(i) => {
+                let value = word[i+1..].to_string();
+                match word[..i].to_lowercase().as_str() {
+                    "from" => Criterion::From(value),
+                    "to" => Criterion::To(value),
+                    "cc" => Criterion::Cc(value),
+                    "subject" => Criterion::Subject(value),
+                    "body" => Criterion::Body(value),
+                    "text" => Criterion::Text(value),
+                    "since" | "after" => {
+                        let (y, m, d) = date(&value)?;
+                        Criterion::Since(y, m, d)
+                    },
+                    "before" => {
+                        let (y, m, d) = date(&value)?;
+                        Criterion::Before(y, m, d)
+                    },
+                    "on" => {
+                        let (y, m, d) = date(&value)?;
+                        Criterion::On(y, m, d)
+                    },
+                    "larger" => Criterion::Larger(size(&value)?),
+                    "smaller" => Criterion::Smaller(size(&value)?),
+                    "is" => flag(&value)?,
+                    _ => return None,
+                }
+            },
+            None => Criterion::Text(word.to_string()),
+        };
+
+        if negate {
+            criteria.push(Criterion::Not(Box::new(criterion)));
+        } else {
+            criteria.push(criterion);
+        }
+    }
+
+    if criteria.is_empty() {
+        return None;
+    }
+    Some(criteria)
+}
+
+fn imap_date(year: u32, month: u32, day: u32) -> String {
+    format!("{}-{}-{}", day, MONTHS[(month - 1) as usize], year)
+}
+
+fn imap_criterion(criterion: &Criterion) -> String {
+    match criterion {
+        Criterion::From(v) => format!("FROM {}", quote(v)),
+        Criterion::To(v) => format!("TO {}", quote(v)),
+        Criterion::Cc(v) => format!("CC {}", quote(v)),
+        Criterion::Subject(v) => format!("SUBJECT {}", quote(v)),
+        Criterion::Body(v) => format!("BODY {}", quote(v)),
+        Criterion::Text(v) => format!("TEXT {}", quote(v)),
+        Criterion::Since(y, m, d) => format!("SINCE {}", imap_date(*y, *m, *d)),
+        Criterion::Before(y, m, d) => format!("BEFORE {}", imap_date(*y, *m, *d)),
+        Criterion::On(y, m, d) => format!("ON {}", imap_date(*y, *m, *d)),
+        Criterion::SentSince(y, m, d) => format!("SENTSINCE {}", imap_date(*y, *m, *d)),
+        Criterion::SentBefore(y, m, d) => format!("SENTBEFORE {}", imap_date(*y, *m, *d)),
+        Criterion::Larger(n) => format!("LARGER {}", n),
+        Criterion::Smaller(n) => format!("SMALLER {}", n),
+        Criterion::Header(h, v) => format!("HEADER {} {}", quote(h), quote(v)),
+        // SEEN, UNSEEN, FLAGGED, ...
+        Criterion::Flag(f, set) => {
+            let name = f.trim_start_matches('\\').to_uppercase();
+            if *set { name } else { format!("UN{}", name) }
+        },
+        Criterion::Not(c) => format!("NOT {}", imap_criterion(c)),
+    }
+}
+
+/// The criteria as the arguments of an IMAP SEARCH command.
+pub fn to_imap(criteria: &[Criterion]) -> String {
+    let query: Vec<String> = criteria.iter().map(imap_criterion).collect();
+    let query = query.join(" ");
+
+    if query.is_ascii() {
+        query
+    } else {
+        format!("CHARSET UTF-8 {}", query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, size, to_imap, Criterion};
+
+    #[test]
+    fn sizes() {
+        assert_eq!(size("500"), Some(500));
+        assert_eq!(size("20k"), Some(20 * 1024));
+        assert_eq!(size("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(size("4095M"), Some(4095 * 1024 * 1024));
+        assert_eq!(size("4096M"), None);
+        assert_eq!(size("4194304k"), None);
+        assert_eq!(size("4294967296"), None);
+        assert_eq!(size("k"), None);
+        assert_eq!(size("-1"), None);
+    }
+
+    #[test]
+    fn query() {
+        assert_eq!(parse("from:alice \"big report\" -is:read larger:1m since:2024-01-31"), Some(vec![
+            Criterion::From("alice".to_string()),
+            Criterion::Text("big report".to_string()),
+            Criterion::Not(Box::new(Criterion::Flag("\\Seen".to_string(), true))),
+            Criterion::Larger(1024 * 1024),
+            Criterion::Since(2024, 1, 31),
+        ]));
+        assert_eq!(parse("subject:\"a b\" -"), Some(vec![
+            Criterion::Subject("a b".to_string()),
+            Criterion::Text("-".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn invalid() {
+        for query in &["", "   ", "larger:8192M", "smaller:x", "since:2024-13-01", "on:2024-02",
+                       "is:purple", "colour:red"] {
+            assert_eq!(parse(query), None, "{}", query);
+        }
+    }
+
+    #[test]
+    fn imap() {
+        let criteria = parse("to:bob -is:unread before:2023-12-05 smaller:10k").unwrap();
+        assert_eq!(to_imap(&criteria), "TO \"bob\" NOT UNSEEN BEFORE 5-Dec-2023 SMALLER 10240");
+        assert_eq!(to_imap(&parse("subject:café").unwrap()), "CHARSET UTF-8 SUBJECT \"café\"");
+    }
+}
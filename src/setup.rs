@@ -1,21 +1,62 @@
 extern crate getopts;
 
 use std::env;
-use getopts::Options;
-use remailfs::REmailFS;
-use rand::Rng;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::BTreeMap;
+use getopts::{Options, Matches};
+use remailfs::{Layout, REmailFS};
+use remailfs::logger;
+use remailfs::backend::{Backend, Protocol, ImapBackend, JmapBackend, Pop3Backend};
+use remailfs::render::{Body, Template};
+use remailfs::smtp::{Security, SmtpConfig};
+
+// whether mounting read-write with expunging was confirmed on the terminal
+static CONFIRMED: AtomicBool = AtomicBool::new(false);
 
 const DEFAULT_DOMAIN: &str = "imap.gmail.com";
-const DEFAULT_PORT: u16 = 993;
+const DEFAULT_POP3_DOMAIN: &str = "pop.gmail.com";
+const DEFAULT_JMAP_DOMAIN: &str = "api.fastmail.com";
 
 const USAGE: &str = "Usage: remailfs [OPTION]... MOUNT POINT
+  or:  mount.remailfs SOURCE MOUNT POINT [-o OPTIONS]
 Mount an email account at the specified MOUNT POINT.
 
+Called as mount.remailfs, or with a SOURCE as well as a MOUNT POINT like
+mount.fuse does, the arguments are the ones mount(8) gives a mount helper.
+SOURCE is the configuration file, or anything else such as \"remailfs\" to
+use REMAILFS_CONFIG, and any of the long options below can be given in
+OPTIONS, as in account=work,log-file=/tmp/remailfs.log,ro,allow_other.
+
+REmailFS runs in the background once the mount is ready unless -f is given.
+SIGINT and SIGTERM unmount it and log out, SIGHUP reads the configuration
+again and lists every mailbox again.
+
 The following environment variables can be used to configure REmailFS:
-REMAILFS_DOMAIN:    the IMAP server to connect to (default=imap.google.com) 
-REMAILFS_PORT:      the port to connect to on the IMAP server (default=993)
+REMAILFS_DOMAIN:    the server to connect to (default=imap.google.com), for JMAP
+                    this may also be the URL of the session resource
+REMAILFS_PORT:      the port to connect to on the server (default=993 for IMAP,
+                    995 for POP3, 443 for JMAP)
+REMAILFS_PROTOCOL:  the protocol to speak to the server, imap, pop3 or jmap
+                    (default=imap)
 REMAILFS_USERNAME:  username for the account 
 REMAILFS_PASSWORD:  password for the account
+REMAILFS_SMTP:      the SMTP server used to send messages written into Outbox/,
+                    as DOMAIN or DOMAIN:PORT (default=no Outbox directory)
+REMAILFS_SMTP_SECURITY: tls, starttls or plain (default=tls)
+REMAILFS_CONFIG:    a configuration file
+REMAILFS_CHARSET:   the charset emails are shown in (default=utf-8)
+REMAILFS_TEMPLATE:  how emails are laid out, default, minimal, full-headers or
+                    raw (default=default)
+REMAILFS_OPTIONS:   mount options, see -o
+REMAILFS_READ_WRITE: allow changes to the server (default=false)
+REMAILFS_NO_EXPUNGE: only flag deleted emails \\Deleted (default=false)
+REMAILFS_LOG_LEVEL: what is logged, see --log-level (default=RUST_LOG, or warn)
+REMAILFS_LOG_FILE:  the file log lines are appended to (default=stderr)
+REMAILFS_NO_REDACT: log passwords and message contents too (default=false)
+REMAILFS_PIDFILE:   the file the process id is written to (default=none)
 
 *** IMPORTANT ***
 Configuration value location priority:
@@ -23,11 +64,78 @@ Configuration value location priority:
 2) Environment variables
 3) Configuration file
 
+The configuration file holds \"key = value\" lines using the long option names
+above (domain, port, uname, pword, ...). Keys before the first [section] apply
+to every account. Each [NAME] section describes an account, when there is more
+than one account each one is mounted as a directory called NAME and the command
+line and environment variables are not used for them.
+
+    protocol = imap
+
+    [work]
+    domain = imap.example.com
+    uname = me@example.com
+    pword = secret
+
+    [personal]
+    uname = me@gmail.com
+    pword = secret
+    search.from alice = from:alice since:2024-01-01
+
+Keys called search.NAME add a saved search called NAME to the account's .search
+directory, see the README for the query syntax.
+
 Mandatory arguments to long options are mandatory for short options too.
 -u, --uname=USERNAME    
 -p, --pword=PASSWORD
 -d, --domain=DOMAIN
 -t, --port=PORT
+-P, --protocol=PROTOCOL imap, pop3 or jmap
+    --apop              authenticate to a POP3 server with APOP
+-s, --smtp=DOMAIN[:PORT] send messages written into Outbox/ with this server
+    --smtp-security=SECURITY tls, starttls or plain
+-c, --config=FILE       read accounts from FILE
+    --account=NAME      only mount the account in the [NAME] section of the
+                        configuration file
+    --charset=CHARSET   show emails in CHARSET, e.g. utf-8, latin1, shift_jis
+    --template=PRESET   default, minimal, full-headers or raw
+    --headers=LIST      the headers shown above the body, e.g.
+                        Subject,From,To,Cc,Reply-To,Message-ID,List-Id
+    --date-format=FORMAT show the Date header with this strftime format
+    --prefer=TYPE       plain or html, which version of the body to show
+    --wrap=COLUMNS      wrap the body at COLUMNS, 0 to not wrap (default=72
+                        for HTML turned into text, 0 for plain text)
+    --mark-read         mark emails as read on the server when they are read
+    --layout=LAYOUT     flat, date or sent-date, date puts the emails of each
+                        mailbox in YYYY/MM/ directories by when they arrived,
+                        sent-date by their Date header (default=flat)
+    --read-only         never change anything on the server, the default
+    --read-write        allow deleting, moving and sending emails and making
+                        mailboxes
+    --no-expunge        like --read-write, but deleted and moved emails are
+                        only flagged \\Deleted and never expunged
+-y, --yes               mount with --read-write without asking first, which
+                        is needed when there is no terminal to ask on
+    --timeout=SECONDS   give up on a server that doesn't connect or answer
+                        within SECONDS, 0 to wait forever (default=60)
+    --state-dir=DIR     keep what can be undone in DIR, so that it can still
+                        be undone after mounting again
+                        (default=$XDG_STATE_HOME/remailfs)
+-o, --options=OPTIONS   comma separated mount options: uid=N and gid=N own
+                        every file, umask=NNN takes permission bits away, ro
+                        and rw are --read-only and --read-write, anything else such as allow_other or
+                        default_permissions is passed on to FUSE
+-v, --verbose           log more, -v for info, -vv for debug and -vvv for
+                        every command sent to the server
+    --log-level=FILTER  error, warn, info, debug or trace, optionally
+                        followed by TARGET=LEVEL for parts of REmailFS, e.g.
+                        warn,remailfs::backend::imap=trace
+    --log-file=FILE     append log lines to FILE instead of stderr
+    --no-redact         log passwords and message contents, which are left
+                        out by default
+-f, --foreground        stay in the foreground instead of running in the
+                        background once mounted
+    --pidfile=FILE      write the process id to FILE while mounted
 -h, --help              show usage text
 ";
 
@@ -41,17 +149,483 @@ fn setup_opts(opt: &mut Options) {
     opt.optopt("p", "pword", "the password", "PASSWORD");
     opt.optopt("d", "domain", "the domain of the server", "DOMAIN");
     opt.optopt("t", "port", "the port to connect to", "PORT");
+    opt.optopt("P", "protocol", "the protocol to use", "PROTOCOL");
+    opt.optflag("", "apop", "use APOP authentication");
+    opt.optopt("s", "smtp", "the SMTP server", "DOMAIN[:PORT]");
+    opt.optopt("", "smtp-security", "how to secure the SMTP connection", "SECURITY");
+    opt.optopt("c", "config", "the configuration file", "FILE");
+    opt.optopt("", "account", "the only account to mount", "NAME");
+    opt.optopt("", "charset", "the charset emails are shown in", "CHARSET");
+    opt.optopt("", "template", "how emails are laid out", "PRESET");
+    opt.optopt("", "headers", "the headers shown above the body", "LIST");
+    opt.optopt("", "date-format", "the format of the Date header", "FORMAT");
+    opt.optopt("", "prefer", "which version of the body to show", "TYPE");
+    opt.optopt("", "wrap", "the column to wrap the body at", "COLUMNS");
+    opt.optflag("", "mark-read", "mark emails as read when they are read");
+    opt.optopt("", "layout", "how the emails of a mailbox are laid out", "LAYOUT");
+    opt.optflag("", "read-only", "never change the server");
+    opt.optflag("", "read-write", "allow changes to the server");
+    opt.optflag("", "no-expunge", "only flag deleted emails");
+    opt.optflag("y", "yes", "mount read-write without asking");
+    opt.optopt("", "timeout", "seconds to wait for a server", "SECONDS");
+    opt.optopt("", "state-dir", "where the undo journal is kept", "DIR");
+    opt.optopt("o", "options", "mount options", "OPTIONS");
+    opt.optflagmulti("v", "verbose", "log more");
+    opt.optopt("", "log-level", "what is logged", "FILTER");
+    opt.optopt("", "log-file", "the file log lines are appended to", "FILE");
+    opt.optflag("", "no-redact", "log passwords and message contents");
+    opt.optflag("f", "foreground", "stay in the foreground");
+    opt.optopt("", "pidfile", "the file the process id is written to", "FILE");
+}
+
+/// Mount options that mean something to mount(8) or in fstab, not to
+/// REmailFS. rw is there too as mount(8) passes it when nothing else is
+/// said, read-write has to be asked for by name.
+const MOUNT_ONLY: &[&str] = &["defaults", "auto", "noauto", "user", "users", "nouser", "owner", "group",
+                              "_netdev", "nofail", "rw"];
+
+/// Turns the arguments mount(8) gives a mount helper, "SOURCE DIR [-sfnv]
+/// [-o OPTIONS]", into the usual ones. Options named like a long option,
+/// account=work or foreground, become that option, the rest are still
+/// mount options.
+fn helper_args(args: &[String], opts: &Options) -> Vec<String> {
+    let known = match opts.parse(Vec::<String>::new()) {
+        Ok(m) => m,
+        Err(_) => return args.to_vec(),
+    };
+
+    let mut paths = Vec::new();
+    let mut options = Vec::new();
+    let mut converted = vec![args[0].clone()];
+
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-o" => options.extend(rest.next().cloned()),
+            o if o.starts_with("-o") => options.push(o[2..].to_string()),
+            // the type and namespace
+            "-t" | "-N" => {
+                rest.next();
+            },
+            "-v" => converted.push(arg.clone()),
+            // sloppy, fake and no mtab mean nothing here
+            "-s" | "-f" | "-n" => (),
+            a if a.starts_with('-') => converted.push(a.to_string()),
+            a => paths.push(a.to_string()),
+        }
+    }
+
+    let mut mount_options = Vec::new();
+    for option in options.iter().flat_map(|o| o.split(',')).map(|o| o.trim()).filter(|o| !o.is_empty()) {
+        let mut parts = option.splitn(2, '=');
+        let (key, value) = (parts.next().unwrap_or(""), parts.next());
+
+        if MOUNT_ONLY.contains(&key) || key.starts_with("x-") || key == "comment" {
+            continue;
+        }
+        if key.len() > 1 && key != "options" && known.opt_defined(key) {
+            converted.push(format!("--{}", key));
+            converted.extend(value.map(|v| v.to_string()));
+        } else {
+            mount_options.push(option.to_string());
+        }
+    }
+    if !mount_options.is_empty() {
+        converted.push("-o".to_string());
+        converted.push(mount_options.join(","));
+    }
+
+    // the source is only a name unless it is a configuration file
+    if paths.len() == 2 {
+        let source = paths.remove(0);
+        if Path::new(&source).is_file() {
+            converted.push("--config".to_string());
+            converted.push(source);
+        }
+    }
+    converted.extend(paths);
+
+    converted
+}
+
+/// The contents of a configuration file, see USAGE for the format.
+struct ConfigFile {
+    globals: BTreeMap<String, String>,
+    sections: Vec<(String, BTreeMap<String, String>)>,
+}
+
+impl ConfigFile {
+    fn empty() -> ConfigFile {
+        ConfigFile { globals: BTreeMap::new(), sections: Vec::new() }
+    }
+
+    fn read(path: &str) -> Option<ConfigFile> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("unable to read {}: {}", path, e);
+                return None;
+            },
+        };
+
+        let mut config = ConfigFile::empty();
+
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let name = line[1..line.len()-1].trim().to_string();
+                config.sections.push((name, BTreeMap::new()));
+                continue;
+            }
+
+            let (key, val) = match line.find('=') {
+                Some(i) => (line[..i].trim().to_string(), line[i+1..].trim().to_string()),
+                None => {
+                    eprintln!("{}:{}: expected key = value", path, num + 1);
+                    return None;
+                },
+            };
+
+            match config.sections.last_mut() {
+                Some((_, section)) => section.insert(key, val),
+                None => config.globals.insert(key, val),
+            };
+        }
+
+        Some(config)
+    }
+}
+
+/// Everything needed to connect to one account.
+struct AccountConfig {
+    name: String,
+    protocol: Protocol,
+    domain: String,
+    port: u16,
+    username: String,
+    password: String,
+    apop: bool,
+    smtp: Option<SmtpConfig>,
+    // saved searches as (name, query)
+    searches: Vec<(String, String)>,
+}
+
+impl AccountConfig {
+    /// Looks up each setting with `find_var`, which is given the long option
+    /// name, the environment variable and the default value.
+    fn new<F>(name: &str, find_var: F) -> Option<AccountConfig>
+        where F: Fn(&str, &str, Option<String>) -> Result<String, &'static str> {
+        let username = match find_var("uname", "REMAILFS_USERNAME", None) {
+            Ok(u) => u,
+            Err(_) => {
+                eprintln!("no username for {}", name);
+                return None;
+            },
+        };
+
+        let password = match find_var("pword", "REMAILFS_PASSWORD", None) {
+            Ok(p) => p,
+            Err(_) => {
+                eprintln!("no password for {}", name);
+                return None;
+            },
+        };
+
+        let protocol = find_var("protocol", "REMAILFS_PROTOCOL", Some("imap".to_string()))
+                        .unwrap();
+
+        let protocol = match protocol.parse::<Protocol>() {
+            Ok(p) => p,
+            Err(_) => {
+                eprintln!("unknown protocol {}", protocol);
+                return None;
+            },
+        };
+
+        let default_domain = match protocol {
+            Protocol::IMAP => DEFAULT_DOMAIN,
+            Protocol::POP3 => DEFAULT_POP3_DOMAIN,
+            Protocol::JMAP => DEFAULT_JMAP_DOMAIN,
+        };
+
+        let domain = find_var("domain", "REMAILFS_DOMAIN", Some(default_domain.to_string()))
+                        .unwrap();
+
+        let port = match find_var("port", "REMAILFS_PORT", Some(protocol.default_port().to_string()))
+                        .unwrap()
+                        .parse::<u16>() {
+            Ok(p) => p,
+            Err(_) => {
+                eprintln!("invalid port for {}", name);
+                return None;
+            },
+        };
+
+        let apop = find_var("apop", "REMAILFS_APOP", Some("false".to_string()))
+                        .map(|a| a == "true" || a == "yes" || a == "1")
+                        .unwrap();
+
+        let smtp = match find_var("smtp", "REMAILFS_SMTP", None) {
+            Ok(s) => {
+                let security = find_var("smtp-security", "REMAILFS_SMTP_SECURITY", Some("tls".to_string()))
+                    .unwrap();
+
+                let security = match security.parse::<Security>() {
+                    Ok(s) => s,
+                    Err(_) => {
+                        eprintln!("unknown SMTP security {}", security);
+                        return None;
+                    },
+                };
+
+                let (smtp_domain, smtp_port) = match s.rfind(':') {
+                    Some(i) => (s[..i].to_string(), s[i+1..].parse::<u16>().ok()?),
+                    None => (s.clone(), security.default_port()),
+                };
+
+                Some(SmtpConfig {
+                    domain: smtp_domain,
+                    port: smtp_port,
+                    security,
+                    username: username.clone(),
+                    password: password.clone(),
+                })
+            },
+            Err(_) => None,
+        };
+
+        Some(AccountConfig {
+            name: name.to_string(),
+            protocol,
+            domain,
+            port,
+            username,
+            password,
+            apop,
+            smtp,
+            searches: Vec::new(),
+        })
+    }
+
+    fn print(&self) {
+        debug!("account    = {}", self.name);
+        debug!("username   = {}", self.username);
+        debug!("password   = ********");
+        debug!("protocol   = {:?}", self.protocol);
+        debug!("domain     = {}", self.domain);
+        debug!("port       = {}", self.port);
+        if let Some(s) = &self.smtp {
+            debug!("smtp       = {}:{} ({:?})", s.domain, s.port, s.security);
+        }
+    }
+
+    fn connect(&self) -> remailfs::error::Result<Box<dyn Backend>> {
+        match self.protocol {
+            Protocol::IMAP => ImapBackend::connect(&self.domain, self.port, &self.username, &self.password)
+                .map(|b| Box::new(b) as Box<dyn Backend>),
+            Protocol::POP3 => Pop3Backend::connect(&self.domain, self.port, &self.username, &self.password, self.apop)
+                .map(|b| Box::new(b) as Box<dyn Backend>),
+            Protocol::JMAP => JmapBackend::connect(&self.domain, self.port, &self.username, &self.password)
+                .map(|b| Box::new(b) as Box<dyn Backend>),
+        }
+    }
+}
+
+/// The saved searches in the configuration file, `search.NAME = QUERY` keys
+/// from the globals and then the account's section.
+fn searches(section: Option<&BTreeMap<String, String>>, globals: &BTreeMap<String, String>) -> Vec<(String, String)> {
+    globals.iter()
+        .chain(section.into_iter().flat_map(|s| s.iter()))
+        .filter(|(k, _)| k.starts_with("search."))
+        .map(|(k, v)| (k["search.".len()..].to_string(), v.clone()))
+        .filter(|(k, _)| !k.is_empty() && !k.contains('/'))
+        .collect()
+}
+
+/// Finds a setting on the command line, in the environment or in the
+/// configuration file (first the account's section, then the globals).
+fn find_setting(matches: &Matches, section: Option<&BTreeMap<String, String>>, globals: &BTreeMap<String, String>, use_args: bool,
+                cmd_ln: &str, env_var: &str, default: Option<String>) -> Result<String, &'static str> {
+    if use_args {
+        if let Ok(s) = env::var(env_var) {
+            return Ok(s);
+        }
+
+        if matches.opt_defined(cmd_ln) {
+            if let Some(s) = matches.opt_str(cmd_ln) {
+                return Ok(s);
+            }
+            if matches.opt_present(cmd_ln) {
+                return Ok("true".to_string());
+            }
+        }
+    }
+
+    if let Some(s) = section.and_then(|s| s.get(cmd_ln)) {
+        return Ok(s.clone());
+    }
+
+    if let Some(s) = globals.get(cmd_ln) {
+        return Ok(s.clone());
+    }
+
+    match default {
+        Some(v) => Ok(v),
+        None => Err("unable to necessary environment variable"), 
+    }
+}
+
+/// Builds the rendering template from a preset and the settings that
+/// override parts of it.
+fn template<F>(find_var: F) -> Option<Template>
+    where F: Fn(&str, &str, Option<String>) -> Result<String, &'static str> {
+    let preset = find_var("template", "REMAILFS_TEMPLATE", Some("default".to_string()))
+                    .unwrap();
+
+    let mut template = match Template::preset(&preset) {
+        Some(t) => t,
+        None => {
+            eprintln!("unknown template {}", preset);
+            return None;
+        },
+    };
+
+    if let Ok(headers) = find_var("headers", "REMAILFS_HEADERS", None) {
+        template.headers = Some(headers.split(',')
+            .map(|h| h.trim().to_string())
+            .filter(|h| !h.is_empty())
+            .collect());
+    }
+
+    if let Ok(format) = find_var("date-format", "REMAILFS_DATE_FORMAT", None) {
+        template.date_format = Some(format);
+    }
+
+    if let Ok(prefer) = find_var("prefer", "REMAILFS_PREFER", None) {
+        template.body = match prefer.to_lowercase().as_str() {
+            "plain" | "text" => Body::Plain,
+            "html" => Body::Html,
+            _ => {
+                eprintln!("unknown body type {}", prefer);
+                return None;
+            },
+        };
+    }
+
+    if let Ok(wrap) = find_var("wrap", "REMAILFS_WRAP", None) {
+        template.wrap = match wrap.parse::<usize>() {
+            Ok(w) => Some(w),
+            Err(_) => {
+                eprintln!("invalid wrap width {}", wrap);
+                return None;
+            },
+        };
+    }
+
+    Some(template)
+}
+
+/// Applies the uid=, gid= and umask= mount options to the filesystem and
+/// returns the others, which are for FUSE, along with whether ro (true) or
+/// rw (false) was given.
+fn mount_options(options: &str, fs: &mut REmailFS) -> Option<(Vec<String>, Option<bool>)> {
+    let (mut uid, mut gid) = (None, None);
+    let mut read_only = None;
+    let mut fuse_options = Vec::new();
+
+    for option in options.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+        let mut parts = option.splitn(2, '=');
+        let valid = match (parts.next().unwrap(), parts.next()) {
+            ("uid", Some(v)) => v.parse::<u32>().ok().map(|v| uid = Some(v)),
+            ("gid", Some(v)) => v.parse::<u32>().ok().map(|v| gid = Some(v)),
+            ("umask", Some(v)) => u16::from_str_radix(v, 8).ok().map(|m| fs.set_umask(m)),
+            ("ro", None) | ("rw", None) => {
+                read_only = Some(option == "ro");
+                Some(())
+            },
+            _ => {
+                fuse_options.push(option.to_string());
+                Some(())
+            },
+        };
+
+        if valid.is_none() {
+            eprintln!("invalid mount option {}", option);
+            return None;
+        }
+    }
+
+    fs.set_owner(uid, gid);
+    Some((fuse_options, read_only))
+}
+
+/// Where mounts keep their undo journals when --state-dir isn't given,
+/// $XDG_STATE_HOME/remailfs or ~/.local/state/remailfs.
+fn default_state_dir() -> Option<String> {
+    let state = match env::var("XDG_STATE_HOME") {
+        Ok(s) if !s.is_empty() => s,
+        _ => format!("{}/.local/state", env::var("HOME").ok().filter(|h| !h.is_empty())?),
+    };
+
+    Some(format!("{}/remailfs", state))
+}
+
+/// The directory of a mount's state in the state directory, named by its
+/// mountpoint with '/' and '%' escaped, "home%2Falex%2Fmail".
+fn mount_state_dir(state_dir: &str, mountpoint: &str) -> String {
+    let mountpoint = fs::canonicalize(mountpoint)
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_else(|_| mountpoint.to_string());
+
+    format!("{}/{}", state_dir, mountpoint.trim_matches('/').replace('%', "%25").replace('/', "%2F"))
+}
+
+/// Asks on the terminal whether to mount read-write with deleted and moved
+/// emails expunged, false if there is no terminal to ask on.
+fn confirm_expunge() -> bool {
+    if unsafe { libc::isatty(0) } == 0 {
+        eprintln!("--read-write expunges deleted and moved emails, give --yes to mount without asking or --no-expunge to only flag them");
+        return false;
+    }
+
+    eprint!("Deleted and moved emails will be expunged from the server. Mount read-write? [y/N] ");
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    answer.trim().eq_ignore_ascii_case("y") || answer.trim().eq_ignore_ascii_case("yes")
 }
 
 pub struct Config {
     pub filesystem: REmailFS,
     pub mountpoint: String,
+    // passed to FUSE as -o OPTION
+    pub options: Vec<String>,
+    pub foreground: bool,
+    pub pidfile: Option<String>,
+    // the absolute path of the configuration file, if there is one
+    pub config: Option<String>,
 }
     
 impl Config {
-    pub fn new(args: env::Args) -> Option<Config> {
-        let args: Vec<String> = args.collect();
-       
+    pub fn new(args: &[String]) -> Option<Config> {
+        Config::build(args, None, true)
+    }
+
+    /// Reads the configuration again for a mount that is running, from
+    /// `config` rather than the file named in `args`, whose path may have
+    /// been relative. The logging stays as it was set up.
+    pub fn reload(args: &[String], config: Option<&str>) -> Option<REmailFS> {
+        Config::build(args, config, false).map(|c| c.filesystem)
+    }
+
+    /// `mounting` is false for a reload, which leaves the logging as it is
+    /// and can't ask anything.
+    fn build(args: &[String], config: Option<&str>, mounting: bool) -> Option<Config> {
         if args.len() < 2 {
             eprintln!("too few arguments");
             return None;
@@ -60,7 +634,17 @@ impl Config {
         let mut opts = Options::new();
         setup_opts(&mut opts);
 
-        let matches = match opts.parse(&args[1..]) {
+        // called by mount(8) as mount.remailfs, or by mount.fuse with the
+        // source first
+        let helper = Path::new(&args[0]).file_name()
+            .map(|n| n.to_string_lossy().starts_with("mount."))
+            .unwrap_or(false);
+        let mut matches = if helper { opts.parse(&helper_args(args, &opts)[1..]) } else { opts.parse(&args[1..]) };
+        if !helper && matches.as_ref().map(|m| m.free.len() == 2).unwrap_or(false) {
+            matches = opts.parse(&helper_args(args, &opts)[1..]);
+        }
+
+        let matches = match matches {
             Ok(p) => p,
             Err(e) => {
                 eprintln!("{:?}", e);
@@ -74,65 +658,192 @@ impl Config {
         }
 
         let mountpoint = matches.free[0].clone();
-        
-        let find_var = |cmd_ln, env_var, default| {
-            match env::var(env_var) {
-                Ok(s) => Ok(s),
-                Err(_) => {
-                    match matches.opt_str(cmd_ln) {
-                        Some(s) => Ok(s),
-                        None => {
-                            match default {
-                                Some(v) => Ok(v),
-                                None => Err("unable to necessary environment variable"), 
-                            }
-                        },
-                    }
+
+        let config = match config.map(|c| c.to_string()).or(matches.opt_str("c")).or(env::var("REMAILFS_CONFIG").ok()) {
+            Some(path) => match fs::canonicalize(&path) {
+                Ok(p) => Some(p.to_string_lossy().to_string()),
+                Err(e) => {
+                    eprintln!("unable to read {}: {}", path, e);
+                    return None;
                 },
+            },
+            None => None,
+        };
+
+        let mut config_file = match &config {
+            Some(path) => ConfigFile::read(path)?,
+            None => ConfigFile::empty(),
+        };
+
+        // one account of several is mounted like it was the only one
+        if let Some(account) = matches.opt_str("account") {
+            config_file.sections.retain(|(name, _)| *name == account);
+            if config_file.sections.is_empty() {
+                eprintln!("no account called {} in the configuration file", account);
+                return None;
             }
+        }
+
+        // -v picks the level unless a whole filter is given
+        let filter = match (matches.opt_str("log-level"), matches.opt_count("v")) {
+            (None, 1) => "info".to_string(),
+            (None, 2) => "debug".to_string(),
+            (None, n) if n > 2 => "trace".to_string(),
+            _ => find_setting(&matches, None, &config_file.globals, true, "log-level", "REMAILFS_LOG_LEVEL",
+                              Some(env::var("RUST_LOG").unwrap_or_else(|_| "warn".to_string()))).unwrap(),
         };
+        let log_file = find_setting(&matches, None, &config_file.globals, true, "log-file", "REMAILFS_LOG_FILE", None).ok();
+        let redact = find_setting(&matches, None, &config_file.globals, true, "no-redact", "REMAILFS_NO_REDACT", Some("false".to_string()))
+                        .map(|r| !(r == "true" || r == "yes" || r == "1"))
+                        .unwrap();
+        if mounting {
+            if let Err(e) = logger::init(&filter, log_file.as_deref(), redact) {
+                eprintln!("unable to set up logging: {}", e);
+                return None;
+            }
+        }
 
-        let username = find_var("u", "REMAILFS_USERNAME", None)
-                        .expect("no username");
-        
-        let password = find_var("p", "REMAILFS_PASSWORD", None)
-                        .expect("no password");
+        let mut accounts = Vec::new();
 
-        let domain = find_var("d", "REMAILFS_DOMAIN", Some(DEFAULT_DOMAIN.to_string()))
+        if config_file.sections.len() > 1 {
+            for (name, section) in config_file.sections.iter() {
+                let mut account = AccountConfig::new(name, |cmd_ln, env_var, default| {
+                    find_setting(&matches, Some(section), &config_file.globals, false, cmd_ln, env_var, default)
+                })?;
+                account.searches = searches(Some(section), &config_file.globals);
+                accounts.push(account);
+            }
+        } else {
+            let (name, section) = match config_file.sections.first() {
+                Some((name, section)) => (name.clone(), Some(section)),
+                None => ("default".to_string(), None),
+            };
+
+            let mut account = AccountConfig::new(&name, |cmd_ln, env_var, default| {
+                find_setting(&matches, section, &config_file.globals, true, cmd_ln, env_var, default)
+            })?;
+            account.searches = searches(section, &config_file.globals);
+            accounts.push(account);
+        }
+
+        let mut fs = REmailFS::new();
+
+        let charset = find_setting(&matches, None, &config_file.globals, true, "charset", "REMAILFS_CHARSET", Some("utf-8".to_string()))
                         .unwrap();
+        if !fs.set_charset(&charset) {
+            eprintln!("unknown charset {}", charset);
+            return None;
+        }
 
-        let port = find_var("t", "REMAILFS_PORT", Some(DEFAULT_PORT.to_string()))
-                        .unwrap()
-                        .parse::<u16>()
+        let template = template(|cmd_ln, env_var, default| {
+            find_setting(&matches, None, &config_file.globals, true, cmd_ln, env_var, default)
+        })?;
+        fs.set_template(template);
+
+        let mark_read = find_setting(&matches, None, &config_file.globals, true, "mark-read", "REMAILFS_MARK_READ", Some("false".to_string()))
+                        .map(|m| m == "true" || m == "yes" || m == "1")
                         .unwrap();
-       
-        let convert_to_stars = || {
-            let plus_minus: usize = rand::thread_rng().gen_range(0, 8) - 4;
-            std::iter::repeat("*")
-                .take(plus_minus + password.len())
-                .collect::<String>()
-        };
+        fs.set_mark_read(mark_read);
 
-        println!("username   = {}", username);
-        println!("password   = {}", convert_to_stars());
-        println!("domain     = {}", domain);
-        println!("port       = {}", port);
-        println!("mountpoint = {}", mountpoint);
+        let layout = find_setting(&matches, None, &config_file.globals, true, "layout", "REMAILFS_LAYOUT", Some("flat".to_string()))
+                        .unwrap();
+        match layout.parse::<Layout>() {
+            Ok(l) => fs.set_layout(l),
+            Err(_) => {
+                eprintln!("unknown layout {}", layout);
+                return None;
+            },
+        }
 
-        let fs = match REmailFS::new(
-            username,
-            password,
-            domain,
-            port
-        ) {
-            Ok(fs) => fs,
-            Err(_) => return None,
-        };
+        // read-only unless asked otherwise, --no-expunge implies read-write
+        let read_write = find_setting(&matches, None, &config_file.globals, true, "read-write", "REMAILFS_READ_WRITE", Some("false".to_string()))
+                        .map(|r| r == "true" || r == "yes" || r == "1")
+                        .unwrap();
+        let no_expunge = find_setting(&matches, None, &config_file.globals, true, "no-expunge", "REMAILFS_NO_EXPUNGE", Some("false".to_string()))
+                        .map(|n| n == "true" || n == "yes" || n == "1")
+                        .unwrap();
+        let options = find_setting(&matches, None, &config_file.globals, true, "options", "REMAILFS_OPTIONS", Some(String::new()))
+                        .unwrap();
+        let (options, ro_option) = mount_options(&options, &mut fs)?;
+
+        // ro and rw in the mount options are --read-only and --read-write
+        let asked_read_only = matches.opt_present("read-only") || ro_option == Some(true);
+        let asked_read_write = matches.opt_present("read-write") || matches.opt_present("no-expunge") || ro_option == Some(false);
+        if asked_read_only && asked_read_write {
+            eprintln!("--read-only or ro can't be used with --read-write, --no-expunge or rw");
+            return None;
+        }
+        let read_only = asked_read_only || !(read_write || no_expunge || ro_option == Some(false));
+
+        // expunging can't be undone, so it is only done when confirmed, once
+        // for the mount
+        let yes = find_setting(&matches, None, &config_file.globals, true, "yes", "REMAILFS_YES", Some("false".to_string()))
+                        .map(|y| y == "true" || y == "yes" || y == "1")
+                        .unwrap();
+        if !read_only && !no_expunge && !yes && !CONFIRMED.load(Ordering::Relaxed) {
+            if !mounting {
+                eprintln!("not reloading read-write without --yes");
+                return None;
+            }
+            if !confirm_expunge() {
+                return None;
+            }
+            CONFIRMED.store(true, Ordering::Relaxed);
+        }
+        fs.set_read_only(read_only);
+        fs.set_expunge(!no_expunge);
+
+        let timeout = find_setting(&matches, None, &config_file.globals, true, "timeout", "REMAILFS_TIMEOUT", Some("60".to_string()))
+                        .unwrap();
+        match timeout.parse::<u64>() {
+            Ok(t) => remailfs::set_timeout(t),
+            Err(_) => {
+                eprintln!("invalid timeout {}", timeout);
+                return None;
+            },
+        }
 
-        println!("created filesystem");
+        if let Ok(state_dir) = find_setting(&matches, None, &config_file.globals, true, "state-dir", "REMAILFS_STATE_DIR", default_state_dir()) {
+            fs.set_state_dir(Path::new(&mount_state_dir(&state_dir, &mountpoint)));
+        }
+
+        for account in accounts.iter() {
+            account.print();
+
+            let backend = match account.connect() {
+                Ok(b) => b,
+                Err(e) => {
+                    error!("{}: {}", account.name, e);
+                    return None
+                },
+            };
+
+            fs.add_account(&account.name, backend, account.smtp.clone());
+
+            for (name, query) in account.searches.iter() {
+                if !fs.add_search(name, query) {
+                    eprintln!("invalid search {} = {}", name, query);
+                    return None;
+                }
+            }
+        }
+
+        debug!("mountpoint = {}", mountpoint);
+        debug!("created filesystem");
 
-        Some(Config { filesystem: fs, mountpoint: mountpoint })
+        let foreground = find_setting(&matches, None, &config_file.globals, true, "foreground", "REMAILFS_FOREGROUND", Some("false".to_string()))
+                        .map(|f| f == "true" || f == "yes" || f == "1")
+                        .unwrap();
+        let pidfile = find_setting(&matches, None, &config_file.globals, true, "pidfile", "REMAILFS_PIDFILE", None).ok();
+
+        Some(Config {
+            filesystem: fs,
+            mountpoint,
+            options,
+            foreground,
+            pidfile,
+            config,
+        })
 
     }
 }
-
@@ -0,0 +1,505 @@
+use std::io::{BufRead, BufReader, Write};
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::logger;
+use crate::net::Stream;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Security {
+    Tls,
+    StartTls,
+    Plain,
+}
+
+impl FromStr for Security {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Security> {
+        match s.to_lowercase().as_str() {
+            "tls" | "ssl" => Ok(Security::Tls),
+            "starttls" => Ok(Security::StartTls),
+            "plain" | "none" => Ok(Security::Plain),
+            _ => Err(Error::Parse(format!("unknown SMTP security {}", s))),
+        }
+    }
+}
+
+impl Security {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Security::Tls => 465,
+            Security::StartTls => 587,
+            Security::Plain => 25,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SmtpConfig {
+    pub domain: String,
+    pub port: u16,
+    pub security: Security,
+    pub username: String,
+    pub password: String,
+}
+
+struct Smtp {
+    stream: BufReader<Stream>,
+}
+
+impl Smtp {
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(line.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Reads a (possibly multi-line) reply and checks its code is one of
+    /// `expect`. Returns the text of each line.
+    fn reply(&mut self, expect: &[u16]) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(Error::Connection("SMTP connection closed".to_string()));
+            }
+            let line = line.trim_end().to_string();
+            trace!("S: {}", line);
+
+            if line.len() < 3 {
+                return Err(Error::Parse(format!("bad SMTP reply {}", line)));
+            }
+
+            let code = line[..3].parse::<u16>()
+                .map_err(|_| Error::Parse(format!("bad SMTP reply {}", line)))?;
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            lines.push(line.get(4..).unwrap_or("").to_string());
+
+            if last {
+                if expect.contains(&code) {
+                    return Ok(lines);
+                }
+                let text = format!("SMTP error {} {}", code, lines.join(" "));
+                return Err(match code {
+                    452 | 552 => Error::Quota(text),
+                    530 | 534 | 535 => Error::Auth(text),
+                    _ => Error::Protocol(text),
+                });
+            }
+        }
+    }
+
+    fn command(&mut self, cmd: &str, expect: &[u16]) -> Result<Vec<String>> {
+        trace!("C: {}", logger::command(cmd));
+        self.write_line(cmd)?;
+        self.reply(expect)
+    }
+
+    fn authenticate(&mut self, capabilities: &[String], uname: &str, pword: &str) -> Result<()> {
+        let mechanisms: Vec<String> = capabilities.iter()
+            .filter(|c| c.to_uppercase().starts_with("AUTH "))
+            .flat_map(|c| c[5..].split_whitespace().map(|m| m.to_uppercase()).collect::<Vec<_>>())
+            .collect();
+
+        if mechanisms.iter().any(|m| m == "PLAIN") || mechanisms.is_empty() {
+            let token = base64::encode(&format!("\0{}\0{}", uname, pword));
+            self.command(&format!("AUTH PLAIN {}", token), &[235])?;
+        } else {
+            self.command("AUTH LOGIN", &[334])?;
+            self.command(&base64::encode(uname), &[334])?;
+            self.command(&base64::encode(pword), &[235])?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits an address header into the bare addresses it contains.
+pub fn addresses(value: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut angle = false;
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '<' if !quoted => angle = true,
+            '>' if !quoted => angle = false,
+            ',' if !quoted && !angle => {
+                parts.push(current.clone());
+                current.clear();
+                continue;
+            },
+            _ => (),
+        }
+        current.push(c);
+    }
+    parts.push(current);
+
+    parts.iter()
+        .filter_map(|p| {
+            let addr = match (p.rfind('<'), p.rfind('>')) {
+                (Some(s), Some(e)) if s < e => &p[s+1..e],
+                _ => p.rsplit(':').next().unwrap_or(p),
+            };
+            let addr = addr.trim().trim_end_matches(';');
+            if addr.contains('@') {
+                Some(addr.to_string())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Works out the envelope of a message from its From/To/Cc/Bcc headers and
+/// returns it along with the message stripped of its Bcc header.
+pub fn envelope(message: &[u8]) -> Result<(String, Vec<String>, Vec<u8>)> {
+    let (headers, _) = mailparse::parse_headers(message)
+        .map_err(|e| Error::Parse(format!("{:?}", e)))?;
+
+    let mut from = None;
+    let mut to = Vec::new();
+
+    for header in headers.iter() {
+        let key = header.get_key().unwrap_or_default().to_lowercase();
+        let val = header.get_value().unwrap_or_default();
+
+        match key.as_str() {
+            "from" => from = addresses(&val).into_iter().next(),
+            "to" | "cc" | "bcc" => to.extend(addresses(&val)),
+            _ => (),
+        }
+    }
+
+    let from = from.ok_or_else(|| Error::Parse("message has no From address".to_string()))?;
+    if to.is_empty() {
+        return Err(Error::Parse("message has no recipients".to_string()));
+    }
+
+    // drop the Bcc header (and its continuation lines) from the header block
+    let mut stripped = Vec::new();
+    let mut in_headers = true;
+    let mut skipping = false;
+    for line in message.split(|b| *b == b'\n') {
+        if in_headers {
+            let trimmed: &[u8] = if line.ends_with(b"\r") { &line[..line.len()-1] } else { line };
+            if trimmed.is_empty() {
+                in_headers = false;
+            } else if line.starts_with(b" ") || line.starts_with(b"\t") {
+                if skipping { continue; }
+            } else {
+                skipping = trimmed.len() >= 4 && trimmed[..4].eq_ignore_ascii_case(b"bcc:");
+                if skipping { continue; }
+            }
+        }
+        stripped.extend_from_slice(line);
+        stripped.push(b'\n');
+    }
+    stripped.pop();
+
+    Ok((from, to, stripped))
+}
+
+/// Submits a message to the SMTP server.
+pub fn send(config: &SmtpConfig, from: &str, to: &[String], message: &[u8]) -> Result<()> {
+    let stream = Stream::connect(&config.domain, config.port, config.security == Security::Tls)?;
+    let mut smtp = Smtp { stream: BufReader::new(stream) };
+
+    smtp.reply(&[220])?;
+    let mut capabilities = smtp.command("EHLO localhost", &[250])?;
+
+    if config.security == Security::StartTls {
+        smtp.command("STARTTLS", &[220])?;
+        let stream = smtp.stream.into_inner().starttls(&config.domain)?;
+        smtp = Smtp { stream: BufReader::new(stream) };
+        capabilities = smtp.command("EHLO localhost", &[250])?;
+    }
+
+    if !config.username.is_empty() {
+        smtp.authenticate(&capabilities, &config.username, &config.password)?;
+    }
+
+    smtp.command(&format!("MAIL FROM:<{}>", from), &[250])?;
+    for rcpt in to {
+        smtp.command(&format!("RCPT TO:<{}>", rcpt), &[250, 251])?;
+    }
+
+    smtp.command("DATA", &[354])?;
+
+    let message = if message.ends_with(b"\n") { &message[..message.len()-1] } else { message };
+    let mut data = Vec::new();
+    for line in message.split(|b| *b == b'\n') {
+        let line = if line.ends_with(b"\r") { &line[..line.len()-1] } else { line };
+        if line.starts_with(b".") {
+            data.push(b'.');
+        }
+        data.extend_from_slice(line);
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(b".\r\n");
+
+    trace!("C: {}", logger::body(&data));
+    {
+        let stream = smtp.stream.get_mut();
+        stream.write_all(&data)?;
+        stream.flush()?;
+    }
+    smtp.reply(&[250])?;
+
+    let _ = smtp.command("QUIT", &[221]);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::{addresses, envelope, send, Security, SmtpConfig};
+    use crate::error::Error;
+
+    /// What the sink offers and refuses.
+    #[derive(Clone, Copy, Default)]
+    pub struct Sink {
+        pub starttls: bool,
+        // the AUTH mechanisms offered, none if empty
+        pub auth: &'static str,
+        // a recipient that gets 550
+        pub reject: Option<&'static str>,
+    }
+
+    /// A loopback SMTP server for one session, which returns every line it
+    /// was sent.
+    pub fn sink(options: Sink) -> (SmtpConfig, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = SmtpConfig {
+            domain: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            security: Security::Plain,
+            username: String::new(),
+            password: String::new(),
+        };
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut lines = Vec::new();
+
+            let reply = |reader: &mut BufReader<_>, text: &str| {
+                let stream: &mut std::net::TcpStream = reader.get_mut();
+                stream.write_all(text.as_bytes()).unwrap();
+            };
+            reply(&mut reader, "220 sink ESMTP\r\n");
+
+            let mut data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end_matches("\r\n").to_string();
+                lines.push(line.clone());
+                let upper = line.to_uppercase();
+
+                if data {
+                    if line == "." {
+                        data = false;
+                        reply(&mut reader, "250 queued\r\n");
+                    }
+                } else if upper.starts_with("EHLO") {
+                    let mut text = "250-sink\r\n".to_string();
+                    if options.starttls {
+                        text.push_str("250-STARTTLS\r\n");
+                    }
+                    if !options.auth.is_empty() {
+                        text.push_str(&format!("250-AUTH {}\r\n", options.auth));
+                    }
+                    text.push_str("250 8BITMIME\r\n");
+                    reply(&mut reader, &text);
+                } else if upper == "STARTTLS" {
+                    if options.starttls {
+                        // there is no TLS here, the handshake fails
+                        reply(&mut reader, "220 go ahead\r\n");
+                        break;
+                    }
+                    reply(&mut reader, "502 no\r\n");
+                } else if upper.starts_with("AUTH PLAIN ") {
+                    if line[11..] == base64::encode("\0alice\0secret") {
+                        reply(&mut reader, "235 ok\r\n");
+                    } else {
+                        reply(&mut reader, "535 bad credentials\r\n");
+                    }
+                } else if upper == "AUTH LOGIN" {
+                    reply(&mut reader, "334 VXNlcm5hbWU6\r\n");
+                    let mut user = String::new();
+                    reader.read_line(&mut user).unwrap();
+                    reply(&mut reader, "334 UGFzc3dvcmQ6\r\n");
+                    let mut pass = String::new();
+                    reader.read_line(&mut pass).unwrap();
+                    lines.push(user.trim_end().to_string());
+                    lines.push(pass.trim_end().to_string());
+                    reply(&mut reader, "235 ok\r\n");
+                } else if upper.starts_with("MAIL FROM:") {
+                    reply(&mut reader, "250 ok\r\n");
+                } else if upper.starts_with("RCPT TO:") {
+                    if options.reject.map(|r| line.contains(r)).unwrap_or(false) {
+                        reply(&mut reader, "550 no such user\r\n");
+                    } else {
+                        reply(&mut reader, "250 ok\r\n");
+                    }
+                } else if upper == "DATA" {
+                    data = true;
+                    reply(&mut reader, "354 go ahead\r\n");
+                } else if upper == "QUIT" {
+                    reply(&mut reader, "221 bye\r\n");
+                    break;
+                } else {
+                    reply(&mut reader, "500 what\r\n");
+                }
+            }
+            lines
+        });
+
+        (config, handle)
+    }
+
+    fn with_login(mut config: SmtpConfig) -> SmtpConfig {
+        config.username = "alice".to_string();
+        config.password = "secret".to_string();
+        config
+    }
+
+    const MESSAGE: &[u8] = b"From: \"Alice, A.\" <alice@example.com>\r\n\
+        To: bob@example.com, \"Smith, Carol\" <carol@example.com>\r\n\
+        Cc: Team: dave@example.com, erin@example.com;\r\n\
+        Bcc: frank@example.com,\r\n \
+        grace@example.com\r\n\
+        Subject: Hi\r\n\
+        \r\n\
+        Hello\r\n";
+
+    #[test]
+    fn address_lists() {
+        assert_eq!(addresses("\"Doe, John\" <john@example.com>, jane@example.com"),
+                   vec!["john@example.com", "jane@example.com"]);
+        assert_eq!(addresses("undisclosed-recipients:;"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn envelope_recipients() {
+        let (from, to, message) = envelope(MESSAGE).unwrap();
+        assert_eq!(from, "alice@example.com");
+        assert_eq!(to, vec!["bob@example.com", "carol@example.com", "dave@example.com",
+                            "erin@example.com", "frank@example.com", "grace@example.com"]);
+
+        let text = String::from_utf8(message).unwrap();
+        assert!(!text.contains("Bcc") && !text.contains("frank") && !text.contains("grace"));
+        assert!(text.contains("Cc: Team: dave@example.com, erin@example.com;\r\nSubject: Hi\r\n\r\nHello\r\n"));
+    }
+
+    #[test]
+    fn envelope_errors() {
+        assert!(envelope(b"To: bob@example.com\r\n\r\nHello\r\n").is_err());
+        assert!(envelope(b"From: alice@example.com\r\n\r\nHello\r\n").is_err());
+    }
+
+    #[test]
+    fn plain() {
+        let (config, server) = sink(Sink::default());
+        let to = vec!["bob@example.com".to_string(), "carol@example.com".to_string()];
+        send(&config, "alice@example.com", &to, b"Subject: Hi\r\n\r\nHello\r\n").unwrap();
+
+        let lines = server.join().unwrap();
+        assert_eq!(lines, vec![
+            "EHLO localhost",
+            "MAIL FROM:<alice@example.com>",
+            "RCPT TO:<bob@example.com>",
+            "RCPT TO:<carol@example.com>",
+            "DATA",
+            "Subject: Hi",
+            "",
+            "Hello",
+            ".",
+            "QUIT",
+        ]);
+    }
+
+    #[test]
+    fn dot_stuffing() {
+        let (config, server) = sink(Sink::default());
+        let message = b"Subject: dots\n\n.\n..two\n.leading\nlast line without an ending";
+        send(&config, "alice@example.com", &["bob@example.com".to_string()], message).unwrap();
+
+        let lines = server.join().unwrap();
+        let data = lines.iter().position(|l| l == "DATA").unwrap();
+        assert_eq!(lines[data+1..], [
+            "Subject: dots", "", "..", "...two", "..leading", "last line without an ending", ".", "QUIT",
+        ]);
+    }
+
+    #[test]
+    fn auth_plain() {
+        let (config, server) = sink(Sink { auth: "LOGIN PLAIN", ..Sink::default() });
+        send(&with_login(config), "alice@example.com", &["bob@example.com".to_string()], b"\r\nHi\r\n").unwrap();
+
+        let lines = server.join().unwrap();
+        assert_eq!(lines[1], format!("AUTH PLAIN {}", base64::encode("\0alice\0secret")));
+        assert_eq!(lines[2], "MAIL FROM:<alice@example.com>");
+    }
+
+    #[test]
+    fn auth_login() {
+        let (config, server) = sink(Sink { auth: "LOGIN", ..Sink::default() });
+        send(&with_login(config), "alice@example.com", &["bob@example.com".to_string()], b"\r\nHi\r\n").unwrap();
+
+        let lines = server.join().unwrap();
+        assert_eq!(lines[1..4], ["AUTH LOGIN".to_string(), base64::encode("alice"), base64::encode("secret")]);
+    }
+
+    #[test]
+    fn auth_refused() {
+        let (mut config, server) = sink(Sink { auth: "PLAIN", ..Sink::default() });
+        config = with_login(config);
+        config.password = "wrong".to_string();
+
+        match send(&config, "alice@example.com", &["bob@example.com".to_string()], b"\r\nHi\r\n") {
+            Err(Error::Auth(_)) => (),
+            r => panic!("expected an auth error, got {:?}", r),
+        }
+        assert!(!server.join().unwrap().iter().any(|l| l.starts_with("MAIL")));
+    }
+
+    #[test]
+    fn recipient_refused() {
+        let (config, server) = sink(Sink { reject: Some("nobody@"), ..Sink::default() });
+        let to = vec!["bob@example.com".to_string(), "nobody@example.com".to_string()];
+
+        assert!(send(&config, "alice@example.com", &to, b"\r\nHi\r\n").is_err());
+        assert!(!server.join().unwrap().iter().any(|l| l == "DATA"));
+    }
+
+    #[test]
+    fn starttls() {
+        let (mut config, server) = sink(Sink { starttls: true, auth: "PLAIN", ..Sink::default() });
+        config.security = Security::StartTls;
+
+        // the sink can't do TLS, but nothing may be sent in the clear
+        // before the handshake
+        assert!(send(&with_login(config), "alice@example.com", &["bob@example.com".to_string()], b"\r\nHi\r\n").is_err());
+        assert_eq!(server.join().unwrap(), vec!["EHLO localhost", "STARTTLS"]);
+    }
+
+    #[test]
+    fn starttls_refused() {
+        let (mut config, server) = sink(Sink { auth: "PLAIN", ..Sink::default() });
+        config.security = Security::StartTls;
+
+        assert!(send(&with_login(config), "alice@example.com", &["bob@example.com".to_string()], b"\r\nHi\r\n").is_err());
+        assert!(!server.join().unwrap().iter().any(|l| l.starts_with("AUTH")));
+    }
+}
@@ -0,0 +1,181 @@
+// Threading by References and In-Reply-To, for servers without the THREAD
+// extension. Two messages are in the same conversation when one refers to
+// the other, or both refer to the same message, whether or not that message
+// is in the mailbox.
+
+use std::collections::BTreeMap;
+
+use mailparse::MailHeader;
+
+use crate::backend::Thread;
+use crate::render::header_value;
+
+/// The value of a header in a header block.
+pub fn header(headers: &[MailHeader], key: &str) -> Option<String> {
+    headers.iter()
+        .find(|h| h.get_key().map(|k| k.eq_ignore_ascii_case(key)).unwrap_or(false))
+        .map(|h| header_value(h))
+}
+
+/// The message ids in a header value, "<a@b> <c@d>".
+fn message_ids(value: &str) -> Vec<String> {
+    value.split('<')
+        .skip(1)
+        .filter_map(|s| s.split('>').next())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn find(parents: &mut [usize], node: usize) -> usize {
+    let mut root = node;
+    while parents[root] != root {
+        root = parents[root];
+    }
+
+    let mut node = node;
+    while parents[node] != root {
+        let next = parents[node];
+        parents[node] = root;
+        node = next;
+    }
+
+    root
+}
+
+fn join(parents: &mut [usize], a: usize, b: usize) {
+    let (a, b) = (find(parents, a), find(parents, b));
+    if a != b {
+        parents[b] = a;
+    }
+}
+
+/// Groups messages, given as (uid, header) pairs, into threads. Each thread
+/// is in date order, so its root is the oldest message, and the threads are
+/// in the order of their roots. A thread has the subject of its root, or of
+/// the first message with one.
+pub fn thread(messages: &[(String, Vec<u8>)]) -> Vec<Thread> {
+    // a node for every message and for every message id mentioned
+    let mut parents: Vec<usize> = (0..messages.len()).collect();
+    let mut ids: BTreeMap<String, usize> = BTreeMap::new();
+    let mut found = Vec::new();
+
+    for (i, (uid, raw)) in messages.iter().enumerate() {
+        let headers = match mailparse::parse_headers(raw) {
+            Ok((h, _)) => h,
+            Err(_) => Vec::new(),
+        };
+
+        let mut refs: Vec<String> = header(&headers, "Message-ID")
+            .map(|v| message_ids(&v))
+            .unwrap_or_default();
+        refs.extend(header(&headers, "References").map(|v| message_ids(&v)).unwrap_or_default());
+        refs.extend(header(&headers, "In-Reply-To").map(|v| message_ids(&v)).unwrap_or_default());
+
+        for id in refs {
+            let node = match ids.get(&id) {
+                Some(n) => *n,
+                None => {
+                    parents.push(parents.len());
+                    ids.insert(id, parents.len() - 1);
+                    parents.len() - 1
+                },
+            };
+            join(&mut parents, i, node);
+        }
+
+        let date = header(&headers, "Date").and_then(|d| mailparse::dateparse(&d).ok());
+        found.push((uid.clone(), header(&headers, "Subject"), date));
+    }
+
+    let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for i in 0..messages.len() {
+        let root = find(&mut parents, i);
+        groups.entry(root).or_default().push(i);
+    }
+
+    let mut threads: Vec<Thread> = groups.values_mut()
+        .map(|members| {
+            // messages without a date go last
+            members.sort_by_key(|m| found[*m].2.unwrap_or(i64::MAX));
+            let root = &found[members[0]];
+
+            Thread {
+                subject: root.1.clone().or_else(|| members.iter().find_map(|m| found[*m].1.clone())),
+                date: root.2,
+                uids: members.iter().map(|m| found[*m].0.clone()).collect(),
+            }
+        })
+        .collect();
+
+    threads.sort_by_key(|t| t.date.unwrap_or(i64::MAX));
+    threads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::thread;
+
+    fn message(uid: &str, header: &str) -> (String, Vec<u8>) {
+        (uid.to_string(), format!("{}\r\n\r\n", header).into_bytes())
+    }
+
+    fn uids(messages: &[(String, Vec<u8>)]) -> Vec<Vec<String>> {
+        thread(messages).into_iter().map(|t| t.uids).collect()
+    }
+
+    #[test]
+    fn reply_chain() {
+        let messages = vec![
+            message("3", "Message-ID: <c@x>\r\nReferences: <a@x> <b@x>\r\nIn-Reply-To: <b@x>\r\nSubject: Re: Lunch\r\nDate: Tue, 2 Jan 2024 12:00:00 +0000"),
+            message("1", "Message-ID: <a@x>\r\nSubject: Lunch\r\nDate: Mon, 1 Jan 2024 09:00:00 +0000"),
+            message("9", "Message-ID: <z@x>\r\nSubject: Other\r\nDate: Mon, 1 Jan 2024 10:00:00 +0000"),
+            message("2", "Message-ID: <b@x>\r\nIn-Reply-To: <a@x>\r\nSubject: Re: Lunch\r\nDate: Mon, 1 Jan 2024 11:00:00 +0000"),
+        ];
+
+        let threads = thread(&messages);
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].uids, vec!["1", "2", "3"]);
+        assert_eq!(threads[0].subject.as_deref(), Some("Lunch"));
+        assert_eq!(threads[0].date, Some(1704099600));
+        assert_eq!(threads[1].uids, vec!["9"]);
+    }
+
+    #[test]
+    fn missing_parent() {
+        // both answer a message that isn't in the mailbox
+        let messages = vec![
+            message("1", "Message-ID: <b@x>\r\nIn-Reply-To: <a@x>\r\nDate: Mon, 1 Jan 2024 09:00:00 +0000"),
+            message("2", "Message-ID: <c@x>\r\nReferences: <a@x>\r\nDate: Mon, 1 Jan 2024 10:00:00 +0000"),
+            message("3", "Message-ID: <d@x>\r\nIn-Reply-To: <e@x>\r\nDate: Mon, 1 Jan 2024 11:00:00 +0000"),
+        ];
+
+        assert_eq!(uids(&messages), vec![vec!["1", "2"], vec!["3"]]);
+    }
+
+    #[test]
+    fn undated() {
+        // messages without a date or any ids go last, each on its own
+        let messages = vec![
+            message("1", "Subject: No date"),
+            message("2", "Message-ID: <a@x>\r\nDate: Mon, 1 Jan 2024 09:00:00 +0000"),
+            message("3", "Message-ID: <b@x>\r\nIn-Reply-To: <a@x>"),
+        ];
+
+        assert_eq!(uids(&messages), vec![vec!["2", "3"], vec!["1"]]);
+    }
+
+    #[test]
+    fn subject_fallback() {
+        let messages = vec![
+            message("1", "Message-ID: <a@x>\r\nDate: Mon, 1 Jan 2024 09:00:00 +0000"),
+            message("2", "Message-ID: <b@x>\r\nIn-Reply-To: <a@x>\r\nSubject: =?utf-8?q?Re:_Caf=C3=A9?=\r\nDate: Mon, 1 Jan 2024 10:00:00 +0000"),
+            message("3", "Message-ID: <c@x>\r\nDate: Mon, 1 Jan 2024 11:00:00 +0000"),
+        ];
+
+        let threads = thread(&messages);
+        assert_eq!(threads[0].uids, vec!["1", "2"]);
+        assert_eq!(threads[0].subject.as_deref(), Some("Re: Caf\u{e9}"));
+        assert_eq!(threads[1].subject, None);
+    }
+}
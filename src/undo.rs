@@ -0,0 +1,332 @@
+// The journal of destructive operations, kept so they can be undone by
+// writing to .remailfs/undo. It is saved in a state directory of the mount
+// so that it lasts across mounts: the entries in journal.json and the
+// messages of deleted emails each in a file named by their MD5 digest.
+
+use std::collections::BTreeSet;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
+
+use crate::json::Json;
+use crate::md5;
+use crate::search::Criterion;
+
+const JOURNAL: &str = "journal.json";
+
+/// How many operations are remembered, the oldest are forgotten first.
+pub const LIMIT: usize = 100;
+
+/// How many bytes of deleted messages are kept, the oldest are forgotten
+/// first here too.
+pub const MAX_BYTES: usize = 32 * 1024 * 1024;
+
+pub enum Operation {
+    // an email unlinked and moved to the trash mailbox named
+    Trashed(String),
+    // an email deleted outright, with the whole message kept to append again
+    Deleted(Vec<u8>),
+    // an email renamed into the mailbox named
+    Moved(String),
+    // a saved search removed with rmdir, its name and criteria
+    Search(String, Vec<Criterion>),
+}
+
+pub struct Entry {
+    pub account: usize,
+    // the mailbox the email was in and its uid there
+    pub mailbox: String,
+    pub uid: String,
+    pub message_id: Option<String>,
+    pub operation: Operation,
+}
+
+impl Entry {
+    /// The bytes kept to undo the operation.
+    pub fn size(&self) -> usize {
+        match &self.operation {
+            Operation::Deleted(message) => message.len(),
+            _ => 0,
+        }
+    }
+
+    /// A line of the undo file, "deleted INBOX/4211 <id@host> to Trash".
+    pub fn describe(&self) -> String {
+        let id = self.message_id.as_ref().map(|i| format!(" {}", i)).unwrap_or_default();
+
+        match &self.operation {
+            Operation::Trashed(trash) => format!("deleted {}/{}{} to {}", self.mailbox, self.uid, id, trash),
+            Operation::Deleted(_) => format!("deleted {}/{}{}", self.mailbox, self.uid, id),
+            Operation::Moved(dest) => format!("moved {}/{}{} to {}", self.mailbox, self.uid, id, dest),
+            Operation::Search(name, _) => format!("removed search {}", name),
+        }
+    }
+}
+
+fn criterion_json(criterion: &Criterion) -> Json {
+    let date = |key: &str, y: u32, m: u32, d: u32| {
+        Json::Array(vec![Json::str(key), Json::Number(y as f64), Json::Number(m as f64), Json::Number(d as f64)])
+    };
+
+    match criterion {
+        Criterion::From(v) => Json::Array(vec![Json::str("from"), Json::str(v)]),
+        Criterion::To(v) => Json::Array(vec![Json::str("to"), Json::str(v)]),
+        Criterion::Cc(v) => Json::Array(vec![Json::str("cc"), Json::str(v)]),
+        Criterion::Subject(v) => Json::Array(vec![Json::str("subject"), Json::str(v)]),
+        Criterion::Body(v) => Json::Array(vec![Json::str("body"), Json::str(v)]),
+        Criterion::Text(v) => Json::Array(vec![Json::str("text"), Json::str(v)]),
+        Criterion::Since(y, m, d) => date("since", *y, *m, *d),
+        Criterion::Before(y, m, d) => date("before", *y, *m, *d),
+        Criterion::On(y, m, d) => date("on", *y, *m, *d),
+        Criterion::SentSince(y, m, d) => date("sentsince", *y, *m, *d),
+        Criterion::SentBefore(y, m, d) => date("sentbefore", *y, *m, *d),
+        Criterion::Larger(n) => Json::Array(vec![Json::str("larger"), Json::Number(*n as f64)]),
+        Criterion::Smaller(n) => Json::Array(vec![Json::str("smaller"), Json::Number(*n as f64)]),
+        Criterion::Header(h, v) => Json::Array(vec![Json::str("header"), Json::str(h), Json::str(v)]),
+        Criterion::Flag(f, set) => Json::Array(vec![Json::str("flag"), Json::str(f), Json::Bool(*set)]),
+        Criterion::Not(c) => Json::Array(vec![Json::str("not"), criterion_json(c)]),
+    }
+}
+
+fn criterion_from_json(json: &Json) -> Option<Criterion> {
+    let array = json.as_array()?;
+    let string = |i: usize| array.get(i).and_then(|v| v.as_str()).map(|v| v.to_string());
+    let number = |i: usize| array.get(i).and_then(|v| v.as_u64()).map(|v| v as u32);
+    let date = || Some((number(1)?, number(2)?, number(3)?));
+
+    let criterion = match array.first()?.as_str()? {
+        "from" => Criterion::From(string(1)?),
+        "to" => Criterion::To(string(1)?),
+        "cc" => Criterion::Cc(string(1)?),
+        "subject" => Criterion::Subject(string(1)?),
+        "body" => Criterion::Body(string(1)?),
+        "text" => Criterion::Text(string(1)?),
+        "since" => { let (y, m, d) = date()?; Criterion::Since(y, m, d) },
+        "before" => { let (y, m, d) = date()?; Criterion::Before(y, m, d) },
+        "on" => { let (y, m, d) = date()?; Criterion::On(y, m, d) },
+        "sentsince" => { let (y, m, d) = date()?; Criterion::SentSince(y, m, d) },
+        "sentbefore" => { let (y, m, d) = date()?; Criterion::SentBefore(y, m, d) },
+        "larger" => Criterion::Larger(number(1)?),
+        "smaller" => Criterion::Smaller(number(1)?),
+        "header" => Criterion::Header(string(1)?, string(2)?),
+        "flag" => match array.get(2)? {
+            Json::Bool(set) => Criterion::Flag(string(1)?, *set),
+            _ => return None,
+        },
+        "not" => Criterion::Not(Box::new(criterion_from_json(array.get(1)?)?)),
+        _ => return None,
+    };
+
+    Some(criterion)
+}
+
+/// The file a deleted message is kept in.
+fn message_file(message: &[u8]) -> String {
+    format!("{}.eml", md5::hex_digest(message))
+}
+
+fn entry_json(entry: &Entry, account: &str) -> Json {
+    let mut pairs = vec![
+        ("account", Json::str(account)),
+        ("mailbox", Json::str(&entry.mailbox)),
+        ("uid", Json::str(&entry.uid)),
+        ("message_id", entry.message_id.as_ref().map(|i| Json::str(i)).unwrap_or(Json::Null)),
+    ];
+
+    match &entry.operation {
+        Operation::Trashed(trash) => {
+            pairs.push(("operation", Json::str("trashed")));
+            pairs.push(("to", Json::str(trash)));
+        },
+        Operation::Deleted(message) => {
+            pairs.push(("operation", Json::str("deleted")));
+            pairs.push(("message", Json::str(&message_file(message))));
+        },
+        Operation::Moved(dest) => {
+            pairs.push(("operation", Json::str("moved")));
+            pairs.push(("to", Json::str(dest)));
+        },
+        Operation::Search(name, criteria) => {
+            pairs.push(("operation", Json::str("search")));
+            pairs.push(("name", Json::str(name)));
+            pairs.push(("criteria", Json::Array(criteria.iter().map(criterion_json).collect())));
+        },
+    }
+
+    Json::object(pairs)
+}
+
+fn entry_from_json(json: &Json, accounts: &[String], dir: &Path) -> Option<Entry> {
+    let string = |key: &str| json.get(key).and_then(|v| v.as_str()).map(|v| v.to_string());
+    let account = string("account")?;
+
+    let operation = match string("operation")?.as_str() {
+        "trashed" => Operation::Trashed(string("to")?),
+        "deleted" => {
+            // only ever a file name, never a path out of the directory
+            let file = string("message").filter(|f| !f.contains('/'))?;
+            Operation::Deleted(fs::read(dir.join(file)).ok()?)
+        },
+        "moved" => Operation::Moved(string("to")?),
+        "search" => {
+            let criteria = json.get("criteria")?.as_array()?.iter()
+                .map(criterion_from_json)
+                .collect::<Option<Vec<_>>>()?;
+            Operation::Search(string("name")?, criteria)
+        },
+        _ => return None,
+    };
+
+    Some(Entry {
+        account: accounts.iter().position(|a| *a == account)?,
+        mailbox: string("mailbox")?,
+        uid: string("uid")?,
+        message_id: string("message_id"),
+        operation,
+    })
+}
+
+/// Writes a file whole or not at all. Only its owner can read it, as it
+/// may hold mail.
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+/// Saves the journal in `dir`, which is made if it doesn't exist. The
+/// accounts are the names of the accounts of the mount by index, which
+/// may be different the next time.
+pub fn save(journal: &[Entry], accounts: &[String], dir: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+    let mut messages = BTreeSet::new();
+    for entry in journal {
+        if let Operation::Deleted(message) = &entry.operation {
+            let file = message_file(message);
+            if !dir.join(&file).exists() {
+                write_file(&dir.join(&file), message)?;
+            }
+            messages.insert(file);
+        }
+    }
+
+    let json = Json::Array(journal.iter().map(|e| entry_json(e, &accounts[e.account])).collect());
+    write_file(&dir.join(JOURNAL), json.to_string().as_bytes())?;
+
+    // the messages of operations undone or forgotten
+    for file in fs::read_dir(dir)? {
+        let name = file?.file_name().to_string_lossy().to_string();
+        if name.ends_with(".eml") && !messages.contains(&name) {
+            fs::remove_file(dir.join(name))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the journal saved in `dir`, which is empty if nothing was saved.
+/// Operations of accounts that aren't mounted any more are left out.
+pub fn load(accounts: &[String], dir: &Path) -> io::Result<Vec<Entry>> {
+    let text = match fs::read_to_string(dir.join(JOURNAL)) {
+        Ok(t) => t,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let json = Json::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let entries = json.as_array()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the journal isn't a list"))?;
+
+    Ok(entries.iter()
+        .filter_map(|e| {
+            let entry = entry_from_json(e, accounts, dir);
+            if entry.is_none() {
+                warn!("can't undo {} any more", e);
+            }
+            entry
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::{load, save, Entry, Operation};
+    use crate::search;
+
+    fn directory(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("remailfs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(account: usize, uid: &str, operation: Operation) -> Entry {
+        Entry {
+            account,
+            mailbox: "INBOX".to_string(),
+            uid: uid.to_string(),
+            message_id: Some(format!("<{}@example.com>", uid)),
+            operation,
+        }
+    }
+
+    #[test]
+    fn saved() {
+        let dir = directory("saved");
+        let accounts = vec!["work".to_string(), "home".to_string()];
+        let criteria = search::parse("from:alice -is:read since:2024-01-31 larger:1k").unwrap();
+        let journal = vec![
+            entry(0, "1", Operation::Trashed("Trash".to_string())),
+            entry(1, "2", Operation::Deleted(b"Subject: Hi\r\n\r\nHello\r\n".to_vec())),
+            entry(1, "3", Operation::Moved("Archive".to_string())),
+            Entry { message_id: None, ..entry(0, "", Operation::Search("Alice".to_string(), criteria.clone())) },
+        ];
+        save(&journal, &accounts, &dir).unwrap();
+
+        let loaded = load(&accounts, &dir).unwrap();
+        let lines: Vec<String> = loaded.iter().map(|e| format!("{} {}", e.account, e.describe())).collect();
+        assert_eq!(lines, vec![
+            "0 deleted INBOX/1 <1@example.com> to Trash",
+            "1 deleted INBOX/2 <2@example.com>",
+            "1 moved INBOX/3 <3@example.com> to Archive",
+            "0 removed search Alice",
+        ]);
+        match (&loaded[1].operation, &loaded[3].operation) {
+            (Operation::Deleted(message), Operation::Search(_, c)) => {
+                assert_eq!(message, b"Subject: Hi\r\n\r\nHello\r\n");
+                assert_eq!(*c, criteria);
+            },
+            _ => panic!("wrong operations"),
+        }
+
+        // an account that isn't mounted any more loses its operations
+        let loaded = load(&["home".to_string()], &dir).unwrap();
+        assert_eq!(loaded.iter().map(|e| (e.account, e.uid.as_str())).collect::<Vec<_>>(), vec![(0, "2"), (0, "3")]);
+
+        // messages no longer in the journal are removed
+        save(&journal[..1], &accounts, &dir).unwrap();
+        let files = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files, 1);
+        assert_eq!(load(&accounts, &dir).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nothing_saved() {
+        let dir = directory("nothing");
+        assert!(load(&["work".to_string()], &dir).unwrap().is_empty());
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("journal.json"), "{").unwrap();
+        assert!(load(&["work".to_string()], &dir).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+// Modified UTF-7 (RFC 3501 section 5.1.3), the encoding IMAP uses for
+// mailbox names.
+
+/// Decodes a mailbox name. Anything that isn't valid modified UTF-7 is kept
+/// as it is.
+pub fn decode(name: &str) -> String {
+    let mut out = String::new();
+    let mut rest = name;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start+1..];
+
+        let end = match rest.find('-') {
+            Some(e) => e,
+            None => {
+                out.push('&');
+                continue;
+            }
+        };
+
+        if end == 0 {
+            out.push('&');
+        } else {
+            match decode_utf16(&rest[..end]) {
+                Some(s) => out.push_str(&s),
+                None => {
+                    out.push('&');
+                    out.push_str(&rest[..end+1]);
+                }
+            }
+        }
+
+        rest = &rest[end+1..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn decode_utf16(encoded: &str) -> Option<String> {
+    let bytes = base64::decode_config(&encoded.replace(',', "/"), base64::STANDARD_NO_PAD).ok()?;
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+
+    let units: Vec<u16> = bytes.chunks(2)
+        .map(|c| ((c[0] as u16) << 8) | c[1] as u16)
+        .collect();
+
+    String::from_utf16(&units).ok()
+}
+
+/// Encodes a name for use in IMAP commands.
+pub fn encode(name: &str) -> String {
+    let mut out = String::new();
+    let mut pending: Vec<u16> = Vec::new();
+
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush(&mut out, &mut pending);
+            if c == '&' {
+                out.push_str("&-");
+            } else {
+                out.push(c);
+            }
+        } else {
+            let mut buf = [0; 2];
+            pending.extend_from_slice(c.encode_utf16(&mut buf));
+        }
+    }
+    flush(&mut out, &mut pending);
+
+    out
+}
+
+fn flush(out: &mut String, pending: &mut Vec<u16>) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let bytes: Vec<u8> = pending.iter()
+        .flat_map(|u| vec![(u >> 8) as u8, (u & 0xff) as u8])
+        .collect();
+
+    out.push('&');
+    out.push_str(&base64::encode_config(&bytes, base64::STANDARD_NO_PAD).replace('/', ","));
+    out.push('-');
+    pending.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+
+    #[test]
+    fn round_trip() {
+        for (name, encoded) in &[
+            ("INBOX", "INBOX"),
+            ("&", "&-"),
+            ("Tom & Jerry", "Tom &- Jerry"),
+            ("\u{e4}", "&AOQ-"),
+            ("Entw\u{fc}rfe", "Entw&APw-rfe"),
+            // the example of RFC 3501, with ',' for '/' in the base64
+            ("~peter/mail/\u{53f0}\u{5317}/\u{65e5}\u{672c}\u{8a9e}", "~peter/mail/&U,BTFw-/&ZeVnLIqe-"),
+            ("\u{e4}&\u{e4}", "&AOQ-&-&AOQ-"),
+        ] {
+            assert_eq!(encode(name), *encoded, "{}", name);
+            assert_eq!(decode(encoded), *name, "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn non_bmp() {
+        // surrogate pairs, one or several in a row
+        assert_eq!(encode("\u{1f600}"), "&2D3eAA-");
+        assert_eq!(decode("&2D3eAA-"), "\u{1f600}");
+        assert_eq!(decode(&encode("Fotos \u{1f4f7}\u{1f600} 2024")), "Fotos \u{1f4f7}\u{1f600} 2024");
+        assert_eq!(decode(&encode("\u{e4}\u{1d11e}")), "\u{e4}\u{1d11e}");
+    }
+
+    #[test]
+    fn invalid() {
+        // kept as they are
+        for name in &["&AOQ", "a&b", "&Jjo!-", "&AO-", "&2D0-", "&&-"] {
+            assert_eq!(decode(name), *name, "{}", name);
+        }
+        assert_eq!(decode("&AOQ-&AOQ"), "\u{e4}&AOQ");
+    }
+}
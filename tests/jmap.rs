@@ -0,0 +1,202 @@
+// Drives the JMAP backend against a mock server on the loopback interface.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use remailfs::backend::{Backend, JmapBackend};
+use remailfs::error::Error;
+
+// "Basic " + base64 of "alice:secret"
+const AUTH: &str = "Basic YWxpY2U6c2VjcmV0";
+
+const MESSAGE: &str = "From: bob@example.com\r\nSubject: Hello\r\n\r\nHi Alice\r\n";
+
+const SESSION: &str = r#"{
+    "apiUrl": "/api/",
+    "downloadUrl": "/download/{accountId}/{blobId}/{name}?type={type}",
+    "uploadUrl": "../upload/{accountId}/",
+    "primaryAccounts": {"urn:ietf:params:jmap:mail": "A1"}
+}"#;
+
+const MAILBOXES: &str = r#"{"methodResponses": [["Mailbox/get", {"accountId": "A1", "state": "1", "notFound": [], "list": [
+    {"id": "M1", "name": "Inbox", "parentId": null, "role": "inbox", "totalEmails": 2, "unreadEmails": 1},
+    {"id": "M2", "name": "Archive", "parentId": null, "role": "archive", "totalEmails": 0, "unreadEmails": 0},
+    {"id": "M3", "name": "2019", "parentId": "M2", "role": null, "totalEmails": 0, "unreadEmails": 0}
+]}, "0"]]}"#;
+
+const EMAILS: &str = r#"{"methodResponses": [
+    ["Email/query", {"accountId": "A1", "ids": ["E1", "E2"], "position": 0, "total": 2}, "0"],
+    ["Email/get", {"accountId": "A1", "state": "1", "notFound": [], "list": [
+        {"id": "E1", "blobId": "B1", "size": 48, "receivedAt": "2019-05-01T10:00:00Z",
+         "header:Subject": " Hello", "keywords": {"$seen": true}},
+        {"id": "E2", "blobId": "B2", "size": 1024, "receivedAt": "2019-05-02T10:00:00Z",
+         "header:Subject": " Re: Hello", "keywords": {}}
+    ]}, "1"]
+]}"#;
+
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: String,
+}
+
+/// Serves `SESSION` and the rest of the mock account until the test ends,
+/// keeping every request it was sent.
+fn serve() -> (u16, Arc<Mutex<Vec<Request>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let log = requests.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut words = line.split_whitespace();
+            let method = words.next().unwrap_or("").to_string();
+            let path = words.next().unwrap_or("").to_string();
+
+            let mut authorization = None;
+            let mut length = 0;
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                let l = line.trim_end();
+                if l.is_empty() {
+                    break;
+                }
+                if let Some(v) = l.strip_prefix("Authorization: ") {
+                    authorization = Some(v.to_string());
+                }
+                if let Some(v) = l.strip_prefix("Content-Length: ") {
+                    length = v.parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let body = String::from_utf8(body).unwrap();
+
+            let (status, reply) = if authorization.as_deref() != Some(AUTH) {
+                (401, String::new())
+            } else if method == "GET" && path == "/.well-known/jmap" {
+                (200, SESSION.to_string())
+            } else if method == "POST" && path == "/api/" {
+                if body.contains("Mailbox/get") {
+                    (200, MAILBOXES.to_string())
+                } else if body.contains("Email/query") {
+                    (200, EMAILS.to_string())
+                } else if body.contains("\"destroy\":[\"E404\"]") {
+                    (200, r#"{"methodResponses": [["Email/set", {"notDestroyed": {"E404": {"type": "notFound"}}}, "0"]]}"#.to_string())
+                } else if body.contains("Email/set") {
+                    (200, r#"{"methodResponses": [["Email/set", {"updated": {"E1": null}, "destroyed": ["E2"]}, "0"]]}"#.to_string())
+                } else if body.contains("Email/import") {
+                    (200, r#"{"methodResponses": [["Email/import", {"created": {"0": {"id": "E3"}}}, "0"]]}"#.to_string())
+                } else {
+                    (200, r#"{"methodResponses": [["error", {"type": "unknownMethod"}, "0"]]}"#.to_string())
+                }
+            } else if method == "GET" && path == "/download/A1/B1/email.eml?type=message%2Frfc822" {
+                (200, MESSAGE.to_string())
+            } else if method == "POST" && path == "/upload/A1/" {
+                (201, r#"{"accountId": "A1", "blobId": "B3", "type": "message/rfc822", "size": 47}"#.to_string())
+            } else {
+                (404, String::new())
+            };
+
+            log.lock().unwrap().push(Request { method, path, authorization, body });
+
+            let mut stream = reader.into_inner();
+            let _ = write!(stream, "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                           status, reply.len(), reply);
+        }
+    });
+
+    (port, requests)
+}
+
+fn connect(port: u16) -> JmapBackend {
+    let url = format!("http://127.0.0.1:{}/.well-known/jmap", port);
+    JmapBackend::connect(&url, port, "alice", "secret").unwrap()
+}
+
+#[test]
+fn wrong_password() {
+    let (port, _) = serve();
+    let url = format!("http://127.0.0.1:{}/.well-known/jmap", port);
+
+    match JmapBackend::connect(&url, port, "alice", "wrong") {
+        Err(Error::Auth(_)) => (),
+        Err(e) => panic!("expected an auth error, got {:?}", e),
+        Ok(_) => panic!("connected with the wrong password"),
+    }
+}
+
+#[test]
+fn list_and_read() {
+    let (port, requests) = serve();
+    let mut backend = connect(port);
+
+    let mut names: Vec<(String, Vec<String>)> = backend.list().unwrap().into_iter()
+        .map(|m| (m.name, m.path))
+        .collect();
+    names.sort();
+    assert_eq!(names, vec![
+        ("Archive".to_string(), vec!["Archive".to_string()]),
+        ("Archive/2019".to_string(), vec!["Archive".to_string(), "2019".to_string()]),
+        ("Inbox".to_string(), vec!["Inbox".to_string()]),
+    ]);
+    assert_eq!(backend.special_use("\\Archive"), Some("Archive".to_string()));
+    assert_eq!(backend.examine("Inbox").unwrap().exists, 2);
+    assert!(backend.examine("Drafts").is_err());
+
+    let summaries = backend.messages("Inbox").unwrap();
+    assert_eq!(summaries.len(), 2);
+    assert_eq!(summaries[0].uid, "E1");
+    assert_eq!(summaries[0].size, Some(48));
+    assert_eq!(summaries[0].received, Some(1556704800));
+    assert_eq!(summaries[0].header.as_deref(), Some(&b"Subject: Hello\r\n\r\n"[..]));
+    assert_eq!(summaries[0].flags, Some(vec!["\\Seen".to_string()]));
+    assert_eq!(summaries[1].flags, Some(vec![]));
+
+    assert_eq!(backend.fetch("Inbox", "E1").unwrap(), MESSAGE.as_bytes());
+    assert!(backend.fetch("Inbox", "E9").is_err());
+
+    let requests = requests.lock().unwrap();
+    assert!(requests.iter().all(|r| r.authorization.as_deref() == Some(AUTH)));
+    let query = requests.iter().find(|r| r.body.contains("Email/query")).unwrap();
+    assert!(query.body.contains(r#""filter":{"inMailbox":"M1"}"#));
+    assert!(query.body.contains(r##""#ids":{"name":"Email/query","path":"/ids","resultOf":"0"}"##));
+}
+
+#[test]
+fn change() {
+    let (port, requests) = serve();
+    let mut backend = connect(port);
+    backend.list().unwrap();
+
+    backend.set_flags("Inbox", "E1", &["\\Flagged"], &["\\Seen"]).unwrap();
+    backend.move_to("Inbox", "E1", "Archive/2019").unwrap();
+    backend.delete("Inbox", "E2").unwrap();
+    match backend.delete("Inbox", "E404") {
+        Err(Error::NotFound(_)) => (),
+        Err(e) => panic!("expected not found, got {:?}", e),
+        Ok(_) => panic!("deleted a missing email"),
+    }
+    backend.append("Archive", MESSAGE.as_bytes()).unwrap();
+
+    let requests = requests.lock().unwrap();
+    let sets: Vec<&Request> = requests.iter().filter(|r| r.body.contains("Email/set")).collect();
+    assert!(sets[0].body.contains(r#""update":{"E1":{"keywords/$flagged":true,"keywords/$seen":null}}"#));
+    assert!(sets[1].body.contains(r#""update":{"E1":{"mailboxIds/M1":null,"mailboxIds/M3":true}}"#));
+    assert!(sets[2].body.contains(r#""destroy":["E2"]"#));
+
+    let upload = requests.iter().find(|r| r.method == "POST" && r.path.starts_with("/upload/")).unwrap();
+    assert_eq!(upload.body, MESSAGE);
+    let import = requests.iter().find(|r| r.body.contains("Email/import")).unwrap();
+    assert!(import.body.contains(r#""blobId":"B3""#));
+    assert!(import.body.contains(r#""mailboxIds":{"M2":true}"#));
+}